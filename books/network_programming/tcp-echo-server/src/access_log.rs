@@ -0,0 +1,363 @@
+//! One structured record per connection, written when the connection closes.
+//!
+//! JSON lines:
+//!
+//! ```text
+//! {"time":"2026-10-18T09:12:03.532Z","peer":"127.0.0.1:51234","protocol":"line","duration_ms":1204,"bytes_in":18,"bytes_out":23,"messages":3,"close":"bye"}
+//! ```
+//!
+//! or logfmt:
+//!
+//! ```text
+//! time=2026-10-18T09:12:03.532Z peer=127.0.0.1:51234 protocol=line duration_ms=1204 bytes_in=18 bytes_out=23 messages=3 close=bye
+//! ```
+//!
+//! `close` is one of `eof`, `bye`, `timeout` or `error`; the last two come with an
+//! `error` field saying which timeout or what went wrong. Connections that came through
+//! a proxy have the client from its PROXY header as `peer`, followed by the address the
+//! client connected to as `destination` and the proxy itself as `proxy`.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Protocol;
+use crate::timeouts::TimeoutError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Json,
+    Logfmt,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(LogFormat::Json),
+            "logfmt" => Ok(LogFormat::Logfmt),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessLogOptions {
+    /// `None` disables the access log, `-` writes it to stdout, anything else is a file path.
+    pub target: Option<String>,
+    pub format: LogFormat,
+    /// The file is rotated once it would grow past this size; 0 never rotates.
+    pub max_bytes: u64,
+    /// How many rotated files (`<path>.1` being the newest) are kept.
+    pub keep: usize,
+}
+
+impl Default for AccessLogOptions {
+    fn default() -> Self {
+        AccessLogOptions {
+            target: None,
+            format: LogFormat::Json,
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+        }
+    }
+}
+
+/// How a connection ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed its side.
+    Eof,
+    /// The client said `bye` (or `QUIT`, or `Connection: close`).
+    Bye,
+    Timeout(TimeoutError),
+    Error(String),
+}
+
+impl CloseReason {
+    pub fn from_error(err: &std::io::Error) -> CloseReason {
+        match TimeoutError::from_io(err) {
+            Some(timeout) => CloseReason::Timeout(timeout),
+            None => CloseReason::Error(err.to_string()),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Self::Eof => "eof",
+            Self::Bye => "bye",
+            Self::Timeout(_) => "timeout",
+            Self::Error(_) => "error",
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        match self {
+            Self::Timeout(timeout) => Some(timeout.to_string()),
+            Self::Error(err) => Some(err.clone()),
+            Self::Eof | Self::Bye => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionRecord {
+    pub peer: String,
+    /// Where a proxied client connected to, and the proxy's own address.
+    pub proxy: Option<(SocketAddr, String)>,
+    pub protocol: Protocol,
+    pub connected_at: SystemTime,
+    pub duration: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// Messages answered: chunks for the raw echo, commands for the line protocol, frames for
+    /// the framed one, requests for HTTP, messages and pings for WebSocket.
+    pub messages: u64,
+    pub close: CloseReason,
+}
+
+impl ConnectionRecord {
+    fn fields(&self) -> Vec<(&'static str, Value)> {
+        let mut fields: Vec<(&'static str, Value)> = vec![
+            ("time", Value::Text(format_time(self.connected_at))),
+            ("peer", Value::Text(self.peer.clone())),
+        ];
+        if let Some((destination, proxy)) = &self.proxy {
+            fields.push(("destination", Value::Text(destination.to_string())));
+            fields.push(("proxy", Value::Text(proxy.clone())));
+        }
+        fields.extend([
+            ("protocol", Value::Text(self.protocol.to_string())),
+            ("duration_ms", Value::Number(self.duration.as_millis() as u64)),
+            ("bytes_in", Value::Number(self.bytes_in)),
+            ("bytes_out", Value::Number(self.bytes_out)),
+            ("messages", Value::Number(self.messages)),
+            ("close", Value::Text(self.close.label().to_owned())),
+        ]);
+        if let Some(detail) = self.close.detail() {
+            fields.push(("error", Value::Text(detail)));
+        }
+        fields
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        let fields: Vec<(&'static str, Value)> = self.fields();
+        match format {
+            LogFormat::Json => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Value::Number(n) => format!("\"{}\":{}", key, n),
+                        Value::Text(text) => format!("\"{}\":\"{}\"", key, json_escape(text)),
+                    })
+                    .collect();
+                format!("{{{}}}", fields.join(","))
+            }
+            LogFormat::Logfmt => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Value::Number(n) => format!("{}={}", key, n),
+                        Value::Text(text) => format!("{}={}", key, logfmt_value(text)),
+                    })
+                    .collect();
+                fields.join(" ")
+            }
+        }
+    }
+}
+
+enum Value {
+    Number(u64),
+    Text(String),
+}
+
+pub fn json_escape(text: &str) -> String {
+    let mut escaped: String = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Bare if it can be, quoted (with JSON style escapes) if it has spaces, quotes or `=`.
+fn logfmt_value(text: &str) -> String {
+    if !text.is_empty() && !text.chars().any(|c| c == ' ' || c == '"' || c == '=' || c == '\\' || c.is_control()) {
+        return text.to_owned();
+    }
+    format!("\"{}\"", json_escape(text))
+}
+
+/// RFC 3339 in UTC with milliseconds, e.g. `2026-10-18T09:12:03.532Z`.
+pub fn format_time(time: SystemTime) -> String {
+    let since_epoch: Duration = time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let secs: u64 = since_epoch.as_secs();
+    let (days, secs_of_day): (i64, u64) = ((secs / 86400) as i64, secs % 86400);
+
+    // Days since 1970-01-01 to a civil date, after Howard Hinnant's `civil_from_days`.
+    let z: i64 = days + 719468;
+    let era: i64 = z.div_euclid(146097);
+    let day_of_era: i64 = z.rem_euclid(146097);
+    let year_of_era: i64 = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year: i64 = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index: i64 = (5 * day_of_year + 2) / 153;
+    let day: i64 = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month: i64 = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year: i64 = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Log file that is renamed to `<path>.1` (shifting older ones to `.2`, `.3`, ...) once it gets too big.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: usize) -> std::io::Result<RotatingFile> {
+        let file: File = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written: u64 = file.metadata()?.len();
+        Ok(RotatingFile { path, file, written, max_bytes, keep })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let len: u64 = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.written > 0 && self.written + len > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(format!("{}\n", line).as_bytes())?;
+        self.written += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        let rotated = |n: usize| -> PathBuf {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+
+        if self.keep == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.keep).rev() {
+                match std::fs::rename(rotated(n), rotated(n + 1)) {
+                    Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            std::fs::rename(&self.path, rotated(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+enum Sink {
+    Disabled,
+    Stdout,
+    File(RotatingFile),
+}
+
+/// Where connection records go; shared by every connection.
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Mutex<Sink>,
+}
+
+impl AccessLog {
+    pub fn open(options: &AccessLogOptions) -> std::io::Result<AccessLog> {
+        let sink: Sink = match options.target.as_deref() {
+            None => Sink::Disabled,
+            Some("-") => Sink::Stdout,
+            Some(path) => Sink::File(RotatingFile::open(PathBuf::from(path), options.max_bytes, options.keep)?),
+        };
+        Ok(AccessLog { format: options.format, sink: Mutex::new(sink) })
+    }
+
+    pub fn record(&self, record: &ConnectionRecord) {
+        let mut sink = self.sink.lock().unwrap();
+        let result: std::io::Result<()> = match &mut *sink {
+            Sink::Disabled => Ok(()),
+            Sink::Stdout => writeln!(std::io::stdout(), "{}", record.format(self.format)),
+            Sink::File(file) => file.write_line(&record.format(self.format)),
+        };
+        if let Err(err) = result {
+            eprintln!("couldn't write access log: {}", err);
+        }
+    }
+}
+
+#[test]
+fn connection_record_format_test() {
+    let record: ConnectionRecord = ConnectionRecord {
+        peer: "127.0.0.1:51234".to_owned(),
+        proxy: None,
+        protocol: Protocol::Line,
+        connected_at: SystemTime::UNIX_EPOCH + Duration::from_millis(1_792_314_723_532),
+        duration: Duration::from_millis(1204),
+        bytes_in: 18,
+        bytes_out: 23,
+        messages: 3,
+        close: CloseReason::Error("connection reset \"hard\"".to_owned()),
+    };
+
+    assert_eq!(
+        record.format(LogFormat::Json),
+        r#"{"time":"2026-10-18T09:12:03.532Z","peer":"127.0.0.1:51234","protocol":"line","duration_ms":1204,"bytes_in":18,"bytes_out":23,"messages":3,"close":"error","error":"connection reset \"hard\""}"#
+    );
+    assert_eq!(
+        record.format(LogFormat::Logfmt),
+        r#"time=2026-10-18T09:12:03.532Z peer=127.0.0.1:51234 protocol=line duration_ms=1204 bytes_in=18 bytes_out=23 messages=3 close=error error="connection reset \"hard\"""#
+    );
+
+    let proxied: ConnectionRecord = ConnectionRecord { proxy: Some(("10.0.0.2:443".parse().unwrap(), "127.0.0.1:40000".to_owned())), ..record };
+    assert!(proxied.format(LogFormat::Logfmt).starts_with("time=2026-10-18T09:12:03.532Z peer=127.0.0.1:51234 destination=10.0.0.2:443 proxy=127.0.0.1:40000 protocol=line "));
+    assert_eq!(format_time(SystemTime::UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    assert_eq!(format_time(SystemTime::UNIX_EPOCH + Duration::from_secs(951_782_400)), "2000-02-29T00:00:00.000Z");
+}
+
+#[test]
+fn rotating_file_test() {
+    let dir: PathBuf = std::env::temp_dir().join(format!("tcp-echo-server-access-log-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path: PathBuf = dir.join("access.log");
+
+    let mut file: RotatingFile = RotatingFile::open(path.clone(), 10, 2).unwrap();
+    for line in ["one", "two", "three", "four"] {
+        file.write_line(line).unwrap();
+    }
+
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "four\n");
+    assert_eq!(std::fs::read_to_string(dir.join("access.log.1")).unwrap(), "three\n");
+    assert_eq!(std::fs::read_to_string(dir.join("access.log.2")).unwrap(), "one\ntwo\n");
+    assert!(!dir.join("access.log.3").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
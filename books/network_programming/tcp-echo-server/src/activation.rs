@@ -0,0 +1,239 @@
+//! Socket activation: serving sockets a service manager (systemd, or any supervisor
+//! speaking its protocol) bound and passed down, instead of binding our own.
+//!
+//! The manager leaves the sockets open from fd 3 onwards and sets `LISTEN_FDS` to how many
+//! there are, `LISTEN_PID` to the pid they're meant for and, optionally, `LISTEN_FDNAMES`
+//! to their names, separated by colons. A name that is a protocol (`line`, `http`, ...)
+//! picks the protocol of that listener, like `,protocol=..` does for `--listen`.
+
+use std::net::{TcpListener, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+
+use crate::config::Protocol;
+use crate::listen::{ListenAddr, ListenSpec};
+use crate::raw_listener::get_int_option;
+use crate::unix_socket::UnixSocketListener;
+
+/// The first inherited fd, right after stdin, stdout and stderr.
+pub const FIRST_FD: RawFd = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SocketKind {
+    Tcp,
+    Unix,
+    Udp,
+}
+
+/// A socket passed down by the service manager, checked to be one the server can serve.
+/// Clones share the socket, which is closed once the last of them and every listener
+/// made from it are dropped.
+#[derive(Debug, Clone)]
+pub struct InheritedSocket {
+    fd: Arc<OwnedFd>,
+    kind: SocketKind,
+    name: Option<String>,
+}
+
+impl InheritedSocket {
+    /// Takes ownership of `fd`, which has to be a listening TCP or Unix stream socket or a
+    /// UDP socket; anything else is left alone and reported.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must not be owned by anything else, as it's closed once the socket is dropped.
+    pub unsafe fn from_raw_fd(fd: RawFd, name: Option<String>) -> std::io::Result<InheritedSocket> {
+        let invalid = |reason: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("inherited fd {}: {}", fd, reason));
+        let socket_type: libc::c_int = get_int_option(fd, libc::SOL_SOCKET, libc::SO_TYPE).map_err(|err| invalid(format!("not a socket ({})", err)))?;
+        let domain: libc::c_int = get_int_option(fd, libc::SOL_SOCKET, libc::SO_DOMAIN)?;
+
+        let kind: SocketKind = match (domain, socket_type) {
+            (libc::AF_INET | libc::AF_INET6, libc::SOCK_STREAM) => SocketKind::Tcp,
+            (libc::AF_UNIX, libc::SOCK_STREAM) => SocketKind::Unix,
+            (libc::AF_INET | libc::AF_INET6, libc::SOCK_DGRAM) => SocketKind::Udp,
+            _ => return Err(invalid("neither a TCP or Unix stream socket nor a UDP socket".to_owned())),
+        };
+        if kind != SocketKind::Udp && get_int_option(fd, libc::SOL_SOCKET, libc::SO_ACCEPTCONN)? == 0 {
+            return Err(invalid("a connected socket; only listening sockets can be served (Accept=no)".to_owned()));
+        }
+        // Inherited fds come without close-on-exec, unlike everything std opens.
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(InheritedSocket { fd: Arc::new(OwnedFd::from_raw_fd(fd)), kind, name })
+    }
+
+    pub fn kind(&self) -> SocketKind {
+        self.kind
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The protocol the socket is named after, if it is.
+    pub fn protocol(&self) -> Option<Protocol> {
+        self.name.as_deref().and_then(|name| name.parse().ok())
+    }
+}
+
+impl AsRawFd for InheritedSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd.as_raw_fd()
+    }
+}
+
+/// Reads the sockets the service manager passed from the environment, taking ownership of
+/// fds 3 and on. Returns none if there are none for this process, and unsets the variables
+/// either way, so nothing claims the fds twice.
+///
+/// Meant to be called once, at the start of `main`, before any other thread runs.
+pub fn from_env() -> std::io::Result<Vec<InheritedSocket>> {
+    let vars: [Option<String>; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"].map(|var| {
+        let value: Option<String> = std::env::var(var).ok();
+        std::env::remove_var(var);
+        value
+    });
+    let fds: Vec<(RawFd, Option<String>)> = parse(vars[0].as_deref(), vars[1].as_deref(), vars[2].as_deref(), std::process::id())?;
+    // LISTEN_PID naming this process hands fds 3.. over to it.
+    fds.into_iter().map(|(fd, name)| unsafe { InheritedSocket::from_raw_fd(fd, name) }).collect()
+}
+
+/// The fds and names `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES` describe, none if the
+/// variables are missing or meant for another process. Names that are empty or `unknown`,
+/// what systemd passes for sockets without a `FileDescriptorName=`, count as no name.
+pub fn parse(listen_pid: Option<&str>, listen_fds: Option<&str>, listen_fdnames: Option<&str>, own_pid: u32) -> std::io::Result<Vec<(RawFd, Option<String>)>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+    let (listen_pid, listen_fds): (&str, &str) = match (listen_pid, listen_fds) {
+        (Some(pid), Some(fds)) => (pid, fds),
+        _ => return Ok(Vec::new()),
+    };
+    let pid: u32 = listen_pid.trim().parse().map_err(|_| invalid(format!("invalid LISTEN_PID '{}'", listen_pid)))?;
+    if pid != own_pid {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = listen_fds.trim().parse().ok().filter(|&count: &RawFd| (0..=RawFd::MAX - FIRST_FD).contains(&count)).ok_or_else(|| invalid(format!("invalid LISTEN_FDS '{}'", listen_fds)))?;
+
+    let names: Vec<Option<String>> = match listen_fdnames {
+        None => vec![None; count as usize],
+        Some(names) => names.split(':').map(|name| Some(name.to_owned()).filter(|name| !name.is_empty() && name != "unknown")).collect(),
+    };
+    if names.len() != count as usize {
+        return Err(invalid(format!("LISTEN_FDNAMES has {} name(s) for {} fd(s)", names.len(), count)));
+    }
+    Ok((FIRST_FD..FIRST_FD + count).zip(names).collect())
+}
+
+/// A listener made from an inherited socket.
+#[derive(Debug)]
+pub enum InheritedListener {
+    Tcp(TcpListener),
+    Unix(UnixSocketListener),
+}
+
+/// What the server serves instead of binding: the listeners, with the `--listen` targets
+/// they stand for, and the UDP sockets.
+#[derive(Debug, Default)]
+pub struct Activated {
+    pub listeners: Vec<(ListenSpec, InheritedListener)>,
+    pub udp: Vec<UdpSocket>,
+}
+
+/// Makes listeners and UDP sockets of their own from `sockets`, blocking like the ones the
+/// server binds itself.
+pub fn activate(sockets: &[InheritedSocket]) -> std::io::Result<Activated> {
+    let mut activated: Activated = Activated::default();
+    for socket in sockets {
+        let fd: OwnedFd = socket.fd.try_clone()?;
+        match socket.kind {
+            SocketKind::Tcp => {
+                let listener: TcpListener = TcpListener::from(fd);
+                listener.set_nonblocking(false)?;
+                let addr: std::net::SocketAddr = listener.local_addr()?;
+                let only_v6: bool = addr.is_ipv6() && get_int_option(listener.as_raw_fd(), libc::IPPROTO_IPV6, libc::IPV6_V6ONLY)? != 0;
+                let spec: ListenSpec = ListenSpec { addr: ListenAddr::Tcp(addr), only_v6, protocol: socket.protocol(), ..ListenSpec::default() };
+                activated.listeners.push((spec, InheritedListener::Tcp(listener)));
+            }
+            SocketKind::Unix => {
+                let listener: UnixListener = UnixListener::from(fd);
+                listener.set_nonblocking(false)?;
+                let listener: UnixSocketListener = UnixSocketListener::from_listener(listener)?;
+                let spec: ListenSpec = ListenSpec { addr: ListenAddr::Unix(listener.local_addr().clone()), protocol: socket.protocol(), ..ListenSpec::default() };
+                activated.listeners.push((spec, InheritedListener::Unix(listener)));
+            }
+            SocketKind::Udp => {
+                let udp: UdpSocket = UdpSocket::from(fd);
+                udp.set_nonblocking(false)?;
+                activated.udp.push(udp);
+            }
+        }
+    }
+    Ok(activated)
+}
+
+#[test]
+fn parse_env_test() {
+    assert!(parse(None, None, None, 42).unwrap().is_empty());
+    assert!(parse(Some("42"), None, None, 42).unwrap().is_empty());
+    // Meant for the process that exec'd this one.
+    assert!(parse(Some("41"), Some("2"), None, 42).unwrap().is_empty());
+
+    assert_eq!(parse(Some("42"), Some("2"), None, 42).unwrap(), vec![(3, None), (4, None)]);
+    assert_eq!(
+        parse(Some("42"), Some("3"), Some("line:unknown:"), 42).unwrap(),
+        vec![(3, Some("line".to_owned())), (4, None), (5, None)]
+    );
+    assert!(parse(Some("42"), Some("0"), None, 42).unwrap().is_empty());
+
+    assert!(parse(Some("42"), Some("2"), Some("line"), 42).is_err());
+    assert!(parse(Some("42"), Some("two"), None, 42).is_err());
+    assert!(parse(Some("42"), Some("-1"), None, 42).is_err());
+    assert!(parse(Some("me"), Some("1"), None, 42).is_err());
+}
+
+#[test]
+fn inherited_socket_test() {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::fd::IntoRawFd;
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: std::net::SocketAddr = listener.local_addr().unwrap();
+    let udp: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_addr: std::net::SocketAddr = udp.local_addr().unwrap();
+    let sockets: Vec<InheritedSocket> = unsafe {
+        vec![
+            InheritedSocket::from_raw_fd(listener.into_raw_fd(), Some("line".to_owned())).unwrap(),
+            InheritedSocket::from_raw_fd(udp.into_raw_fd(), None).unwrap(),
+        ]
+    };
+    assert_eq!(sockets[0].kind(), SocketKind::Tcp);
+    assert_eq!(sockets[0].protocol(), Some(Protocol::Line));
+    assert_eq!(sockets[1].kind(), SocketKind::Udp);
+
+    // Whatever the builder is told to bind, it serves the inherited sockets.
+    let server: crate::ServerHandle = crate::EchoServer::builder().bind("127.0.0.1:0").inherit(sockets).spawn().unwrap();
+    assert_eq!(server.local_addr(), Some(addr));
+    assert_eq!(server.udp_addrs(), [udp_addr]);
+
+    let mut client: std::net::TcpStream = std::net::TcpStream::connect(addr).unwrap();
+    client.write_all(b"PING\n").unwrap();
+    let mut reply: String = String::new();
+    BufReader::new(&client).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "PONG\n");
+
+    let udp_client: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp_client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    udp_client.send_to(b"ping", udp_addr).unwrap();
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(udp_client.recv(&mut buf).unwrap(), 4);
+    server.shutdown();
+
+    // Once the server is done, nobody holds the socket anymore.
+    assert!(std::net::TcpStream::connect(addr).is_err());
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let connected: std::net::TcpStream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let err: std::io::Error = unsafe { InheritedSocket::from_raw_fd(connected.as_raw_fd(), None) }.unwrap_err();
+    assert!(err.to_string().contains("connected socket"), "{}", err);
+}
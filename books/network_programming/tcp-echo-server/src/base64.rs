@@ -0,0 +1,69 @@
+//! Standard base64 (RFC 4648) with padding.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn encode(data: &[u8]) -> String {
+    let mut out: String = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bytes: [u8; 3] = [chunk[0], chunk.get(1).copied().unwrap_or(0), chunk.get(2).copied().unwrap_or(0)];
+        let bits: u32 = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => out.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char),
+                false => out.push('='),
+            }
+        }
+    }
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeError;
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("invalid base64")
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Decodes padded base64; anything else, whitespace included, is an error.
+pub fn decode(text: &str) -> Result<Vec<u8>, DecodeError> {
+    let text: &[u8] = text.as_bytes();
+    if !text.len().is_multiple_of(4) {
+        return Err(DecodeError);
+    }
+    let mut out: Vec<u8> = Vec::with_capacity(text.len() / 4 * 3);
+    for (index, chunk) in text.chunks(4).enumerate() {
+        let last: bool = index == text.len() / 4 - 1;
+        let padding: usize = chunk.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return Err(DecodeError);
+        }
+        let mut bits: u32 = 0;
+        for &c in &chunk[..4 - padding] {
+            let value: usize = ALPHABET.iter().position(|&a| a == c).ok_or(DecodeError)?;
+            bits = bits << 6 | value as u32;
+        }
+        bits <<= 6 * padding;
+        let bytes: [u8; 4] = bits.to_be_bytes();
+        out.extend_from_slice(&bytes[1..4 - padding]);
+    }
+    Ok(out)
+}
+
+#[test]
+fn base64_test() {
+    for (data, text) in [("", ""), ("f", "Zg=="), ("fo", "Zm8="), ("foo", "Zm9v"), ("foob", "Zm9vYg=="), ("fooba", "Zm9vYmE="), ("foobar", "Zm9vYmFy")] {
+        assert_eq!(encode(data.as_bytes()), text);
+        assert_eq!(decode(text).unwrap(), data.as_bytes());
+    }
+    let binary: Vec<u8> = (0..=255).collect();
+    assert_eq!(decode(&encode(&binary)).unwrap(), binary);
+
+    assert!(decode("Zg=").is_err());
+    assert!(decode("Z===").is_err());
+    assert!(decode("Zg==Zg==").is_err());
+    assert!(decode("Zm9v Y").is_err());
+}
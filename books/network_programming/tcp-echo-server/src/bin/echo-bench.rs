@@ -0,0 +1,223 @@
+//! Load generator for tcp-echo-server's raw echo: opens N connections, sends fixed size
+//! messages on each, checks every echoed byte and reports throughput and latency.
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+usage: echo-bench [options]
+
+options:
+  --addr <addr>          server to connect to (default: 127.0.0.1:8080)
+  --connections <n>      concurrent connections (default: 10)
+  --size <bytes>         message size (default: 64)
+  --rate <msgs/s>        total messages per second over all connections, 0 for as fast as possible (default: 0)
+  --duration <secs>      how long to send for (default: 10)
+  -h, --help             print this help";
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    addr: SocketAddr,
+    connections: usize,
+    size: usize,
+    rate: f64,
+    duration: Duration,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            addr: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            connections: 10,
+            size: 64,
+            rate: 0.0,
+            duration: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Options {
+    fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut options: Options = Options::default();
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("option '{}' expects a value", flag));
+            match flag.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--addr" => options.addr = parse(&flag, value()?)?,
+                "--connections" => options.connections = parse(&flag, value()?)?,
+                "--size" => options.size = parse(&flag, value()?)?,
+                "--rate" => options.rate = parse(&flag, value()?)?,
+                "--duration" => options.duration = Duration::try_from_secs_f64(parse(&flag, value()?)?).map_err(|err| err.to_string())?,
+                _ => return Err(format!("unknown option '{}'\n\n{}", flag, USAGE)),
+            }
+        }
+
+        if options.connections == 0 || options.size == 0 || options.rate < 0.0 {
+            return Err("--connections and --size must be at least 1, --rate can't be negative".to_owned());
+        }
+        if options.rate > 0.0 {
+            // A rate this low would leave more time between two messages than a `Duration` holds.
+            Duration::try_from_secs_f64(options.connections as f64 / options.rate).map_err(|err| format!("--rate is too low: {}", err))?;
+        }
+        Ok(options)
+    }
+
+    /// Time between two messages of one connection, if the rate is limited. `from_args` made sure it fits.
+    fn interval(&self) -> Option<Duration> {
+        (self.rate > 0.0).then(|| Duration::from_secs_f64(self.connections as f64 / self.rate))
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: String) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value '{}' for option '{}'", value, flag))
+}
+
+/// What one connection saw.
+#[derive(Debug, Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    mismatches: u64,
+    errors: Vec<String>,
+}
+
+/// Message number `seq` of connection `id`. It never starts with `bye`, which would end the session.
+fn message(id: usize, seq: u64, size: usize) -> Vec<u8> {
+    const ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let offset: usize = id.wrapping_mul(31).wrapping_add(seq as usize);
+    let mut message: Vec<u8> = (0..size).map(|i| ALPHABET[(offset + i) % ALPHABET.len()]).collect();
+    message[0] = b'0' + (seq % 10) as u8;
+    message
+}
+
+fn run_connection(id: usize, options: &Options, deadline: Instant) -> Report {
+    let mut report: Report = Report::default();
+    let mut stream: TcpStream = match TcpStream::connect(options.addr) {
+        Ok(stream) => stream,
+        Err(err) => {
+            report.errors.push(format!("connect: {}", err));
+            return report;
+        }
+    };
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+
+    let interval: Option<Duration> = options.interval();
+    // Spread the connections' first messages over one interval instead of sending them all at once.
+    let mut next_send: Instant = Instant::now() + interval.map_or(Duration::ZERO, |interval| interval.mul_f64(id as f64 / options.connections as f64));
+    let mut echo: Vec<u8> = vec![0; options.size];
+
+    for seq in 0.. {
+        if interval.is_some() {
+            let now: Instant = Instant::now();
+            if next_send > now {
+                std::thread::sleep(next_send - now);
+            }
+        }
+        if Instant::now() >= deadline {
+            break;
+        }
+
+        // With a target rate, latency counts from when the message was due rather than when it
+        // went out, so a slow server can't hide its stalls by holding up the sender.
+        let sent: Instant = if interval.is_some() { next_send } else { Instant::now() };
+        let message: Vec<u8> = message(id, seq, options.size);
+        if let Err(err) = stream.write_all(&message).and_then(|_| stream.read_exact(&mut echo)) {
+            report.errors.push(err.to_string());
+            break;
+        }
+        report.latencies.push(sent.elapsed());
+        if echo != message {
+            report.mismatches += 1;
+        }
+        if let Some(interval) = interval {
+            next_send += interval;
+        }
+    }
+
+    report
+}
+
+/// The value below which `percent` of the sorted `latencies` fall.
+fn percentile(sorted: &[Duration], percent: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank: usize = ((percent / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn main() {
+    let options: Options = Options::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(if err == USAGE { 0 } else { 1 });
+    });
+
+    println!(
+        "echo-bench: {} connection(s) to {}, {} byte messages, {} for {:?}",
+        options.connections,
+        options.addr,
+        options.size,
+        if options.rate > 0.0 { format!("{} msg/s", options.rate) } else { "as fast as possible".to_owned() },
+        options.duration
+    );
+
+    let started: Instant = Instant::now();
+    let deadline: Instant = started + options.duration;
+    let reports: Vec<Report> = std::thread::scope(|scope| {
+        let options: &Options = &options;
+        let handles: Vec<_> = (0..options.connections).map(|id| scope.spawn(move || run_connection(id, options, deadline))).collect();
+        handles.into_iter().map(|handle| handle.join().unwrap_or_default()).collect()
+    });
+    let elapsed: Duration = started.elapsed();
+
+    let mut latencies: Vec<Duration> = reports.iter().flat_map(|report| report.latencies.iter().copied()).collect();
+    latencies.sort_unstable();
+    let mismatches: u64 = reports.iter().map(|report| report.mismatches).sum();
+    let errors: Vec<&String> = reports.iter().flat_map(|report| &report.errors).collect();
+
+    let messages: usize = latencies.len();
+    let bytes: f64 = (messages * options.size) as f64;
+    println!(
+        "messages={} ({:.1} msg/s) throughput={:.2} MiB/s each way",
+        messages,
+        messages as f64 / elapsed.as_secs_f64(),
+        bytes / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+    );
+    println!(
+        "latency p50={:?} p90={:?} p99={:?} max={:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        latencies.last().copied().unwrap_or_default()
+    );
+    println!("mismatches={} errors={}", mismatches, errors.len());
+    for error in &errors {
+        eprintln!("error: {}", error);
+    }
+
+    if mismatches > 0 || !errors.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+#[test]
+fn percentile_test() {
+    let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+    assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+    assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+    assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+    assert_eq!(percentile(&[], 50.0), Duration::ZERO);
+}
+
+#[test]
+fn options_test() {
+    let args = ["--connections", "4", "--rate", "200", "--size", "16"].iter().map(|s| s.to_string());
+    let options: Options = Options::from_args(args).unwrap();
+    assert_eq!(options.interval(), Some(Duration::from_millis(20)));
+    assert!(!message(3, 7, 16).starts_with(b"bye"));
+    assert!(Options::from_args(["--connections", "0"].iter().map(|s| s.to_string())).is_err());
+    assert!(Options::from_args(["--rate", "1e-20"].iter().map(|s| s.to_string())).is_err());
+}
@@ -0,0 +1,367 @@
+//! Interactive client for tcp-echo-server, in the spirit of netcat: copies stdin to the
+//! socket and the socket to stdout until either side is done.
+
+use std::io::{BufRead, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tcp_echo_server::unix_socket::UnixAddr;
+
+const USAGE: &str = "\
+usage: echo-client [options] <target>
+
+targets:
+  <host>:<port>          TCP, e.g. 127.0.0.1:8080 or localhost:8080
+  udp:<host>:<port>      UDP, every line of stdin goes out as one datagram
+  unix:<path>            Unix domain socket, unix:@name for Linux's abstract namespace
+
+options:
+  --once                 send all of stdin as one message, print the reply and exit
+  --timeout <secs>       how long to wait for a reply (default: 5)
+  -h, --help             print this help
+
+A line starting with 'bye' asks the server to answer 'bye' and hang up. With --once the
+exit status is 0 when the reply matches, 1 on a mismatch, 2 on a timeout and 3 on any
+other error.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Target {
+    Tcp(String),
+    Udp(String),
+    Unix(UnixAddr),
+}
+
+impl std::str::FromStr for Target {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(addr) = s.strip_prefix("udp:") {
+            return if addr.is_empty() { Err(()) } else { Ok(Target::Udp(addr.to_owned())) };
+        }
+        if s.starts_with("unix:") {
+            return Ok(Target::Unix(s.parse()?));
+        }
+        if !s.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()) {
+            return Err(());
+        }
+        Ok(Target::Tcp(s.to_owned()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    target: Target,
+    once: bool,
+    timeout: Duration,
+}
+
+impl Options {
+    fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+        let mut target: Option<Target> = None;
+        let mut once: bool = false;
+        let mut timeout: Duration = Duration::from_secs(5);
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--once" => once = true,
+                "--timeout" => {
+                    let value: String = args.next().ok_or_else(|| format!("option '{}' expects a value", arg))?;
+                    let secs: f64 = value.parse().map_err(|_| format!("invalid value '{}' for option '{}'", value, arg))?;
+                    timeout = Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())?;
+                    if timeout.is_zero() {
+                        return Err("--timeout must be more than 0".to_owned());
+                    }
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'\n\n{}", arg, USAGE)),
+                _ if target.is_some() => return Err(format!("unexpected argument '{}'", arg)),
+                _ => target = Some(arg.parse().map_err(|_| format!("invalid target '{}'\n\n{}", arg, USAGE))?),
+            }
+        }
+
+        let target: Target = target.ok_or_else(|| format!("missing target\n\n{}", USAGE))?;
+        Ok(Options { target, once, timeout })
+    }
+}
+
+/// A connected socket of any of the supported transports.
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn connect(target: &Target) -> std::io::Result<Connection> {
+        match target {
+            Target::Tcp(addr) => Ok(Connection::Tcp(TcpStream::connect(addr.as_str())?)),
+            Target::Udp(addr) => {
+                let peer: std::net::SocketAddr = addr.to_socket_addrs()?.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} has no address", addr)))?;
+                let local: std::net::SocketAddr = if peer.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { (std::net::Ipv6Addr::UNSPECIFIED, 0).into() };
+                let socket: UdpSocket = UdpSocket::bind(local)?;
+                socket.connect(peer)?;
+                Ok(Connection::Udp(socket))
+            }
+            Target::Unix(addr) => Ok(Connection::Unix(addr.connect()?)),
+        }
+    }
+
+    fn try_clone(&self) -> std::io::Result<Connection> {
+        match self {
+            Connection::Tcp(stream) => stream.try_clone().map(Connection::Tcp),
+            Connection::Udp(socket) => socket.try_clone().map(Connection::Udp),
+            Connection::Unix(stream) => stream.try_clone().map(Connection::Unix),
+        }
+    }
+
+    /// Datagram sockets have no stream to end, so neither EOF nor the `bye` handshake apply.
+    fn is_datagram(&self) -> bool {
+        matches!(self, Connection::Udp(_))
+    }
+
+    fn send(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.write_all(data),
+            Connection::Udp(socket) => socket.send(data).map(|_| ()),
+            Connection::Unix(stream) => stream.write_all(data),
+        }
+    }
+
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            Connection::Udp(socket) => socket.recv(buf),
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+
+    /// Tells the server nothing more is coming, while its replies can still be read.
+    fn close_write(&self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            Connection::Udp(_) => Ok(()),
+            Connection::Unix(stream) => stream.shutdown(Shutdown::Write),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_read_timeout(timeout),
+            Connection::Udp(socket) => socket.set_read_timeout(timeout),
+            Connection::Unix(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+/// Copies stdin to `connection` on a second thread and everything the server sends to stdout.
+/// Returns once the server hangs up, or `timeout` after stdin ran out if it never does.
+fn interactive(mut connection: Connection, timeout: Duration) -> std::io::Result<()> {
+    let mut writer: Connection = connection.try_clone()?;
+    let stdin_done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+    std::thread::spawn({
+        let stdin_done: Arc<AtomicBool> = Arc::clone(&stdin_done);
+        move || {
+            let datagram: bool = writer.is_datagram();
+            let mut stdin = std::io::stdin().lock();
+            let mut line: Vec<u8> = Vec::new();
+            loop {
+                line.clear();
+                match stdin.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {}
+                }
+                if let Err(err) = writer.send(&line) {
+                    eprintln!("echo-client: {}", err);
+                    break;
+                }
+                // The server answers `bye` and hangs up, so there is nothing more to send.
+                if !datagram && line.starts_with(b"bye") {
+                    break;
+                }
+            }
+            // The server echoes what it already has, sees EOF and closes in turn.
+            let _ = writer.close_write();
+            stdin_done.store(true, Ordering::Relaxed);
+        }
+    });
+
+    connection.set_read_timeout(Some(timeout))?;
+    let mut stdout = std::io::stdout();
+    let mut buf: Vec<u8> = vec![0; 64 * 1024];
+    loop {
+        match connection.recv(&mut buf) {
+            Ok(0) if !connection.is_datagram() => return Ok(()),
+            Ok(received) => {
+                stdout.write_all(&buf[..received])?;
+                stdout.flush()?;
+            }
+            // Only give up waiting once there is nothing left to send.
+            Err(ref err) if is_timeout(err) => {
+                if stdin_done.load(Ordering::Relaxed) {
+                    return Ok(());
+                }
+            }
+            Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// How `--once` ended, each with its own exit status.
+#[derive(Debug, PartialEq, Eq)]
+enum OnceError {
+    Mismatch { expected: Vec<u8>, received: Vec<u8> },
+    Timeout { received: Vec<u8> },
+    Io(std::io::ErrorKind, String),
+}
+
+impl OnceError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            OnceError::Mismatch { .. } => 1,
+            OnceError::Timeout { .. } => 2,
+            OnceError::Io(..) => 3,
+        }
+    }
+}
+
+impl From<std::io::Error> for OnceError {
+    fn from(err: std::io::Error) -> Self {
+        OnceError::Io(err.kind(), err.to_string())
+    }
+}
+
+impl std::fmt::Display for OnceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            OnceError::Mismatch { expected, received } => write!(
+                f,
+                "reply doesn't match: expected {:?}, got {:?}",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(received)
+            ),
+            OnceError::Timeout { received } => write!(f, "timed out waiting for the reply after {} byte(s)", received.len()),
+            OnceError::Io(_, message) => f.write_str(message),
+        }
+    }
+}
+
+/// Sends `message` and waits for its echo, or for `bye` if the message starts with `bye`.
+fn once(connection: &mut Connection, message: &[u8], timeout: Duration) -> Result<Vec<u8>, OnceError> {
+    let expected: &[u8] = if !connection.is_datagram() && message.starts_with(b"bye") { b"bye" } else { message };
+    let deadline: Instant = Instant::now() + timeout;
+    connection.send(message)?;
+
+    let mut received: Vec<u8> = Vec::new();
+    let mut buf: Vec<u8> = vec![0; 64 * 1024];
+    // A stream may deliver the echo in pieces; a datagram arrives whole or not at all.
+    while received.len() < expected.len() || (connection.is_datagram() && received.is_empty()) {
+        let left: Duration = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(OnceError::Timeout { received });
+        }
+        connection.set_read_timeout(Some(left))?;
+        match connection.recv(&mut buf) {
+            Ok(0) if !connection.is_datagram() => break,
+            Ok(size) => {
+                received.extend_from_slice(&buf[..size]);
+                if connection.is_datagram() {
+                    break;
+                }
+            }
+            Err(ref err) if is_timeout(err) => return Err(OnceError::Timeout { received }),
+            Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    if received != expected {
+        return Err(OnceError::Mismatch { expected: expected.to_vec(), received });
+    }
+    Ok(received)
+}
+
+fn main() {
+    let options: Options = Options::from_args(std::env::args().skip(1)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(if err == USAGE { 0 } else { 3 });
+    });
+
+    let mut connection: Connection = Connection::connect(&options.target).unwrap_or_else(|err| {
+        eprintln!("echo-client: couldn't connect: {}", err);
+        std::process::exit(3);
+    });
+
+    if !options.once {
+        if let Err(err) = interactive(connection, options.timeout) {
+            eprintln!("echo-client: {}", err);
+            std::process::exit(3);
+        }
+        return;
+    }
+
+    let mut message: Vec<u8> = Vec::new();
+    if let Err(err) = std::io::stdin().read_to_end(&mut message) {
+        eprintln!("echo-client: couldn't read stdin: {}", err);
+        std::process::exit(3);
+    }
+    match once(&mut connection, &message, options.timeout) {
+        Ok(reply) => {
+            let mut stdout = std::io::stdout();
+            let _ = stdout.write_all(&reply).and_then(|_| stdout.flush());
+        }
+        Err(err) => {
+            eprintln!("echo-client: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    }
+}
+
+#[test]
+fn options_test() {
+    let args = |args: &[&str]| Options::from_args(args.iter().map(|s| s.to_string()));
+    assert_eq!(args(&["localhost:8080"]).unwrap().target, Target::Tcp("localhost:8080".to_owned()));
+    assert_eq!(args(&["--once", "udp:[::1]:9000"]).unwrap(), Options { target: Target::Udp("[::1]:9000".to_owned()), once: true, timeout: Duration::from_secs(5) });
+    let options: Options = args(&["unix:@echo", "--timeout", "0.5"]).unwrap();
+    assert_eq!((options.target, options.timeout), (Target::Unix(UnixAddr::Abstract("echo".to_owned())), Duration::from_millis(500)));
+    assert!(args(&["8080"]).is_err());
+    assert!(args(&["unix:"]).is_err());
+    assert!(args(&[]).is_err());
+}
+
+#[test]
+fn once_test() {
+    use std::net::TcpListener;
+
+    // Echoes like the server's raw protocol, but in single bytes to exercise reassembly.
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target: Target = Target::Tcp(listener.local_addr().unwrap().to_string());
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream: TcpStream = stream.unwrap();
+            let mut buf = [0; 512];
+            let size: usize = stream.read(&mut buf).unwrap();
+            match &buf[..size] {
+                message if message.starts_with(b"bye") => stream.write_all(b"bye").unwrap(),
+                b"silence" => std::thread::sleep(Duration::from_millis(300)),
+                b"typo" => stream.write_all(b"tpyo").unwrap(),
+                message => message.iter().for_each(|byte| stream.write_all(&[*byte]).unwrap()),
+            }
+        }
+    });
+
+    let run = |message: &[u8]| once(&mut Connection::connect(&target).unwrap(), message, Duration::from_millis(100));
+    assert_eq!(run(b"hello\n"), Ok(b"hello\n".to_vec()));
+    assert_eq!(run(b"bye\n"), Ok(b"bye".to_vec()));
+    assert_eq!(run(b"typo").unwrap_err().exit_code(), 1);
+    assert_eq!(run(b"silence").unwrap_err().exit_code(), 2);
+}
@@ -0,0 +1,330 @@
+//! Chat mode: every line a client sends is relayed to all the other connected clients,
+//! as `<name>: <line>`. A client's name is its peer address until it picks one:
+//!
+//! | request        | effect                                                   |
+//! |----------------|----------------------------------------------------------|
+//! | `<text>`       | everyone else gets `<name>: <text>`                      |
+//! | `/nick <name>` | renames the client, everyone gets `* <old> is now <new>` |
+//! | `bye`          | the client gets `bye`, then the server hangs up          |
+//!
+//! Joins and leaves are announced as `* <name> joined` and `* <name> left`.
+//!
+//! Every client has a queue of lines waiting to go out, drained by a writer thread of its
+//! own, so a client that doesn't read only ever holds up itself. Once its queue is full
+//! it is disconnected instead of growing the queue without bound.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::access_log::CloseReason;
+use crate::line_protocol::{Line, LineReader, MAX_LINE};
+use crate::stats::Counted;
+use crate::stream::ClientStream;
+use crate::timeouts::TimedStream;
+
+/// Lines queued for a client before it counts as too slow and is dropped.
+pub const OUTBOX_SIZE: usize = 256;
+/// How long a client that left has to take the lines still on their way to it.
+const LEAVE_TIMEOUT: Duration = Duration::from_secs(2);
+const MAX_NAME: usize = 32;
+
+struct Member {
+    name: String,
+    outbox: SyncSender<Arc<str>>,
+    /// Another handle on the client's socket, to disconnect it when it falls behind.
+    stream: Box<dyn ClientStream>,
+    dropped: bool,
+}
+
+/// The clients connected in broadcast mode, shared by the threads serving them.
+#[derive(Default)]
+pub struct Room {
+    members: Mutex<HashMap<u64, Member>>,
+    next_id: AtomicU64,
+}
+
+impl Room {
+    /// Adds a client whose lines go to `outbox`; everyone already here hears about it.
+    pub fn join(&self, name: String, outbox: SyncSender<Arc<str>>, stream: Box<dyn ClientStream>) -> u64 {
+        let id: u64 = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut members = self.members.lock().unwrap();
+        let welcome: String = format!("* welcome {}, {} other(s) here; /nick <name> to rename, bye to leave\n", name, members.len());
+        let _ = outbox.try_send(welcome.into());
+        send_all(&mut members, Some(id), format!("* {} joined\n", name));
+        members.insert(id, Member { name, outbox, stream, dropped: false });
+        id
+    }
+
+    /// Relays `text` from client `id` to all the others.
+    pub fn say(&self, id: u64, text: &str) {
+        let mut members = self.members.lock().unwrap();
+        if let Some(member) = members.get(&id) {
+            let line: String = format!("{}: {}\n", member.name, text);
+            send_all(&mut members, Some(id), line);
+        }
+    }
+
+    /// Sends `line` to client `id` only.
+    pub fn tell(&self, id: u64, line: &str) {
+        let mut members = self.members.lock().unwrap();
+        if members.contains_key(&id) {
+            send(&mut members, id, line.into());
+        }
+    }
+
+    pub fn rename(&self, id: u64, name: &str) -> Result<(), &'static str> {
+        if name.is_empty() || name.len() > MAX_NAME || name.contains(char::is_whitespace) {
+            return Err("ERR names are 1 to 32 characters without spaces");
+        }
+        let mut members = self.members.lock().unwrap();
+        if members.values().any(|member| member.name == name) {
+            return Err("ERR name taken");
+        }
+        let old: String = match members.get_mut(&id) {
+            Some(member) => std::mem::replace(&mut member.name, name.to_owned()),
+            None => return Ok(()),
+        };
+        send_all(&mut members, None, format!("* {} is now {}\n", old, name));
+        Ok(())
+    }
+
+    /// Removes client `id`, closing its outbox, and tells the others. Returns whether the
+    /// client was dropped for falling behind.
+    pub fn leave(&self, id: u64) -> bool {
+        let mut members = self.members.lock().unwrap();
+        let member: Member = match members.remove(&id) {
+            Some(member) => member,
+            None => return false,
+        };
+        send_all(&mut members, None, format!("* {} left\n", member.name));
+        member.dropped
+    }
+}
+
+fn send_all(members: &mut HashMap<u64, Member>, except: Option<u64>, line: String) {
+    let line: Arc<str> = line.into();
+    let ids: Vec<u64> = members.keys().copied().filter(|id| Some(*id) != except).collect();
+    for id in ids {
+        send(members, id, line.clone());
+    }
+}
+
+/// Queues `line` for client `id` without ever waiting on it.
+fn send(members: &mut HashMap<u64, Member>, id: u64, line: Arc<str>) {
+    let member: &mut Member = match members.get_mut(&id) {
+        Some(member) => member,
+        None => return,
+    };
+    match member.outbox.try_send(line) {
+        // A closed outbox means its writer failed; the reader is about to notice as well.
+        Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+        Err(TrySendError::Full(_)) if member.dropped => {}
+        Err(TrySendError::Full(_)) => {
+            eprintln!("dropping {}: {} lines behind", member.name, OUTBOX_SIZE);
+            member.dropped = true;
+            // Its reader sees the connection end and leaves the room as usual.
+            let _ = member.stream.shutdown(std::net::Shutdown::Both);
+        }
+    }
+}
+
+/// Serves one client in `room` until it says `bye` or goes away: lines are read from
+/// `stream` on this thread while a second one writes what the room sends to `replies`,
+/// a clone of the same connection. Lines sent count towards `messages`.
+pub fn serve(
+    stream: &mut Counted<TimedStream<Box<dyn ClientStream>>>,
+    replies: &mut Counted<TimedStream<Box<dyn ClientStream>>>,
+    room: &Room,
+    name: &str,
+    messages: &mut u64,
+) -> std::io::Result<CloseReason> {
+    let (outbox, inbox) = std::sync::mpsc::sync_channel::<Arc<str>>(OUTBOX_SIZE);
+    let hangup: Box<dyn ClientStream> = stream.get_ref().get_ref().try_clone_stream()?;
+    let id: u64 = room.join(name.to_owned(), outbox, stream.get_ref().get_ref().try_clone_stream()?);
+
+    std::thread::scope(|scope| {
+        let (delivered, wait_delivered) = std::sync::mpsc::channel::<()>();
+        scope.spawn(move || {
+            let _delivered = delivered;
+            deliver(inbox, replies)
+        });
+        let result: std::io::Result<CloseReason> = chat(stream, room, id, messages);
+        // Leaving closes the outbox, so the writer stops once it sent what's left in it. The
+        // room can't disconnect a client that stopped reading anymore, so that's up to us.
+        let dropped: bool = room.leave(id);
+        if let Err(RecvTimeoutError::Timeout) = wait_delivered.recv_timeout(LEAVE_TIMEOUT) {
+            let _ = hangup.shutdown(std::net::Shutdown::Both);
+        }
+        if dropped {
+            return Ok(CloseReason::Error("dropped for falling behind".to_owned()));
+        }
+        result
+    })
+}
+
+fn deliver<W: Write>(inbox: Receiver<Arc<str>>, replies: &mut W) {
+    for line in inbox {
+        if replies.write_all(line.as_bytes()).is_err() {
+            break;
+        }
+    }
+}
+
+fn chat(stream: &mut Counted<TimedStream<Box<dyn ClientStream>>>, room: &Room, id: u64, messages: &mut u64) -> std::io::Result<CloseReason> {
+    let mut reader: LineReader<&mut Counted<TimedStream<Box<dyn ClientStream>>>> = LineReader::new(stream, MAX_LINE);
+
+    while let Some(line) = reader.read_line()? {
+        // Lines are answered through the room, if at all, so each one ends a request here.
+        reader.get_mut().get_mut().end_request();
+        let line: String = match line {
+            Line::TooLong => {
+                room.tell(id, "ERR line too long\n");
+                continue;
+            }
+            Line::Complete(line) if line.is_empty() => continue,
+            Line::Complete(line) => String::from_utf8_lossy(&line).into_owned(),
+        };
+        *messages += 1;
+
+        if line == "bye" {
+            room.tell(id, "bye\n");
+            return Ok(CloseReason::Bye);
+        }
+        if !line.starts_with('/') {
+            room.say(id, &line);
+            continue;
+        }
+        match line.split_once(' ').unwrap_or((&line, "")) {
+            ("/nick", name) => {
+                if let Err(err) = room.rename(id, name.trim()) {
+                    room.tell(id, &format!("{}\n", err));
+                }
+            }
+            (command, _) => room.tell(id, &format!("ERR unknown command '{}'\n", command)),
+        }
+    }
+
+    Ok(CloseReason::Eof)
+}
+
+#[test]
+fn room_test() {
+    use std::os::unix::net::UnixStream;
+
+    let room: Room = Room::default();
+    let join = |name: &str| {
+        let (outbox, inbox) = std::sync::mpsc::sync_channel::<Arc<str>>(2);
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (room.join(name.to_owned(), outbox, Box::new(stream)), inbox, peer)
+    };
+    let (alice, alice_inbox, _alice_peer) = join("alice");
+    let (bob, bob_inbox, bob_peer) = join("bob");
+    let lines = |inbox: &Receiver<Arc<str>>| inbox.try_iter().map(|line| line.to_string()).collect::<Vec<String>>();
+
+    assert_eq!(lines(&alice_inbox), ["* welcome alice, 0 other(s) here; /nick <name> to rename, bye to leave\n", "* bob joined\n"]);
+    assert_eq!(lines(&bob_inbox), ["* welcome bob, 1 other(s) here; /nick <name> to rename, bye to leave\n"]);
+
+    room.say(alice, "hi");
+    assert_eq!(room.rename(bob, "alice"), Err("ERR name taken"));
+    assert_eq!(room.rename(bob, "robert"), Ok(()));
+    assert_eq!(lines(&alice_inbox), ["* bob is now robert\n"]);
+    assert_eq!(lines(&bob_inbox), ["alice: hi\n", "* bob is now robert\n"]);
+
+    // Bob stops reading: once his queue is full he's disconnected, which alice doesn't notice.
+    for text in ["one", "two", "three", "four"] {
+        room.say(alice, text);
+    }
+    let mut buf = [0; 1];
+    assert_eq!(std::io::Read::read(&mut &bob_peer, &mut buf).unwrap(), 0);
+    assert!(room.leave(bob));
+    assert_eq!(lines(&alice_inbox), ["* robert left\n"]);
+    assert!(!room.leave(alice));
+    assert!(room.members.lock().unwrap().is_empty());
+}
+
+#[test]
+fn serve_test() {
+    use std::io::{BufRead, BufReader};
+    use std::os::unix::net::UnixStream;
+
+    let stats: crate::stats::Stats = crate::stats::Stats::default();
+    let room: Room = Room::default();
+    let timeouts: crate::timeouts::Timeouts = crate::timeouts::Timeouts::default();
+    let read_line = |reader: &mut BufReader<UnixStream>| {
+        let mut line: String = String::new();
+        reader.read_line(&mut line).unwrap();
+        line
+    };
+
+    std::thread::scope(|scope| {
+        let mut clients: Vec<(UnixStream, BufReader<UnixStream>)> = Vec::new();
+        let mut servers = Vec::new();
+        for name in ["first", "second"] {
+            let (client, server) = UnixStream::pair().unwrap();
+            let replies: Box<dyn ClientStream> = Box::new(server.try_clone().unwrap());
+            let (room, stats) = (&room, &stats);
+            servers.push(scope.spawn(move || {
+                let mut stream = Counted::new(TimedStream::new(Box::new(server) as Box<dyn ClientStream>, timeouts).unwrap(), stats);
+                let mut replies = Counted::new(TimedStream::new(replies, timeouts).unwrap(), stats);
+                let mut messages: u64 = 0;
+                let close: CloseReason = serve(&mut stream, &mut replies, room, name, &mut messages).unwrap();
+                (close, messages)
+            }));
+            let mut reader: BufReader<UnixStream> = BufReader::new(client.try_clone().unwrap());
+            assert!(read_line(&mut reader).starts_with(&format!("* welcome {},", name)));
+            clients.push((client, reader));
+        }
+        let [(mut first, mut first_reader), (mut second, mut second_reader)]: [(UnixStream, BufReader<UnixStream>); 2] = clients.try_into().unwrap();
+        assert_eq!(read_line(&mut first_reader), "* second joined\n");
+
+        first.write_all(b"/nick ann\nhello\n/shout\n").unwrap();
+        assert_eq!(read_line(&mut second_reader), "* first is now ann\n");
+        assert_eq!(read_line(&mut second_reader), "ann: hello\n");
+        assert_eq!(read_line(&mut first_reader), "* first is now ann\n");
+        assert_eq!(read_line(&mut first_reader), "ERR unknown command '/shout'\n");
+
+        second.write_all(b"bye\n").unwrap();
+        assert_eq!(read_line(&mut second_reader), "bye\n");
+        assert_eq!(read_line(&mut second_reader), "");
+        assert_eq!(read_line(&mut first_reader), "* second left\n");
+
+        first.shutdown(std::net::Shutdown::Write).unwrap();
+        assert_eq!(read_line(&mut first_reader), "");
+        let results: Vec<(CloseReason, u64)> = servers.into_iter().map(|server| server.join().unwrap()).collect();
+        assert_eq!(results, [(CloseReason::Eof, 3), (CloseReason::Bye, 1)]);
+    });
+}
+
+#[test]
+fn leave_without_reading_test() {
+    use std::os::unix::net::UnixStream;
+
+    let stats: crate::stats::Stats = crate::stats::Stats::default();
+    let room: Room = Room::default();
+    let timeouts: crate::timeouts::Timeouts = crate::timeouts::Timeouts::default();
+    let (mut client, server) = UnixStream::pair().unwrap();
+    let replies: Box<dyn ClientStream> = Box::new(server.try_clone().unwrap());
+
+    std::thread::scope(|scope| {
+        let served = scope.spawn(|| {
+            let mut stream = Counted::new(TimedStream::new(Box::new(server) as Box<dyn ClientStream>, timeouts).unwrap(), &stats);
+            let mut replies = Counted::new(TimedStream::new(replies, timeouts).unwrap(), &stats);
+            serve(&mut stream, &mut replies, &room, "quiet", &mut 0).unwrap()
+        });
+        while room.members.lock().unwrap().is_empty() {
+            std::thread::yield_now();
+        }
+        // More than the socket buffers hold, but not enough to fill the outbox and get the
+        // client dropped: the writer blocks, and the client leaves without reading any of it.
+        let line: String = format!("{}\n", "x".repeat(8000));
+        for _ in 0..OUTBOX_SIZE / 2 {
+            room.tell(0, &line);
+        }
+        client.write_all(b"bye\n").unwrap();
+        assert_eq!(served.join().unwrap(), CloseReason::Bye);
+    });
+}
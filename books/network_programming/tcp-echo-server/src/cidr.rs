@@ -0,0 +1,78 @@
+use std::net::IpAddr;
+
+/// A block of IPv4 or IPv6 addresses in CIDR notation: `10.0.0.0/8`, `2001:db8::/32`.
+/// A bare address is a block of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Dual-stack listeners see IPv4 clients as `::ffff:a.b.c.d`.
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => masked(u32::from(net).into(), self.prefix, 32) == masked(u32::from(ip).into(), self.prefix, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => masked(u128::from(net), self.prefix, 128) == masked(u128::from(ip), self.prefix, 128),
+            _ => false,
+        }
+    }
+}
+
+/// The top `prefix` bits of a `bits` wide address.
+fn masked(addr: u128, prefix: u8, bits: u8) -> u128 {
+    match prefix {
+        0 => 0,
+        prefix => addr >> (bits - prefix),
+    }
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix): (&str, Option<&str>) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse::<IpAddr>().map_err(|_| ())?.to_canonical();
+        let bits: u8 = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| ())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(());
+        }
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+impl std::fmt::Display for Cidr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+#[test]
+fn cidr_test() {
+    let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+    let private: Cidr = "10.0.0.0/8".parse().unwrap();
+    assert!(private.contains(ip("10.20.30.40")));
+    assert!(private.contains(ip("::ffff:10.1.2.3")));
+    assert!(!private.contains(ip("11.0.0.1")));
+    assert!(!private.contains(ip("::a00:1")));
+
+    let docs: Cidr = "2001:db8::/32".parse().unwrap();
+    assert!(docs.contains(ip("2001:db8:1::7")));
+    assert!(!docs.contains(ip("2001:db9::7")));
+
+    let host: Cidr = "192.168.1.5".parse().unwrap();
+    assert_eq!(host.to_string(), "192.168.1.5/32");
+    assert!(host.contains(ip("192.168.1.5")) && !host.contains(ip("192.168.1.6")));
+    assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(ip("8.8.8.8")));
+
+    assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+    assert!("10.0.0/8".parse::<Cidr>().is_err());
+    assert!("::/129".parse::<Cidr>().is_err());
+}
@@ -0,0 +1,625 @@
+pub const USAGE: &str = "\
+usage: tcp-echo-server [options]
+       tcp-echo-server replay [options] <file> <target>   (see 'replay --help')
+
+options:
+  --listen <addr>        address to listen on, may be repeated (default: 127.0.0.1:8080)
+                         e.g. 0.0.0.0:8080, [::1]:8080, [::]:8080 (dual-stack), [::]:8080,v6only,
+                         127.0.0.1:8081,protocol=line, unix:/run/echo.sock,mode=660, unix:@echo (Linux
+                         abstract namespace); stale socket files are removed before binding;
+                         ,proxy=optional or ,proxy=required reads PROXY protocol v1/v2 headers
+  --config <file>        read options from a file with one 'key = value' per line, e.g. 'listen = [::]:8080'
+  --udp <addr>           also echo UDP datagrams on this address, may be repeated
+  --udp-max-datagram <bytes>
+                         datagrams bigger than this are not echoed, at most 65507 (default: 65507)
+  --udp-drop <percent>   drop this share of the datagrams (default: 0)
+  --udp-delay <percent>  echo this share of the datagrams late (default: 0)
+  --udp-delay-ms <ms>    how late delayed datagrams are echoed (default: 200)
+  --io <thread|epoll>    connection handling model (default: thread)
+  --workers <n>          number of event loop workers when --io epoll (default: 1)
+  --protocol <raw|line|framed|http|websocket>
+                         default protocol for listeners without ',protocol=..' (default: raw)
+                         raw echoes bytes back, line speaks the ECHO/PING/STATS/QUIT/HELP commands
+                         to clients whose first line is one and echoes the others' bytes back,
+                         framed echoes messages prefixed with their length as a 4 byte big-endian integer,
+                         http answers HTTP/1.1 requests with a JSON document describing them,
+                         websocket echoes WebSocket messages after the upgrade handshake
+  --mode <echo|broadcast>
+                         echo answers every client on its own, broadcast relays every line a client
+                         sends to all the others, see the broadcast module (default: echo)
+  --upstream <host:port> relay every client to a connection of its own to this address instead of
+                         echoing, see the relay module (default: off)
+  --hex-dump             print everything relayed to --upstream as a hex dump (default: off)
+  --zero-copy            echo raw connections socket to pipe to socket with splice(2), falling back to
+                         read/write where splice can't be used; a leading 'bye' isn't noticed (default: off)
+  --max-frame <bytes>    framed and WebSocket messages bigger than this close the connection (default: 1048576)
+  --http-max-head <bytes>
+                         HTTP request lines and headers bigger than this get a 431 (default: 8192)
+  --http-max-body <bytes>
+                         HTTP request bodies bigger than this get a 413 (default: 1048576)
+  --pool-size <n>        serve connections from a fixed pool of n threads instead of
+                         one thread per connection, --io thread only (default: 0, no pool)
+  --accept-queue <n>     connections waiting for a pool thread before new ones are turned away (default: 64)
+  --max-connections <n>  connections served at once before new ones are turned away (default: 0, no limit)
+  --allow <cidr>         only serve TCP clients from this block, e.g. 10.0.0.0/8 or 2001:db8::/32,
+                         may be repeated (default: everyone)
+  --deny <cidr>          never serve TCP clients from this block, even if allowed, may be repeated
+  --connection-rate <n>  new connections per second from one IP, 0 for no limit (default: 0)
+  --connection-burst <n> connections one IP can open at once after being quiet (default: the rate)
+  --byte-rate <bytes>    bytes per second read from one IP's connections, 0 for no limit (default: 0)
+  --byte-burst <bytes>   bytes one IP can send at once after being quiet (default: the rate)
+  --listener <std|raw>   bind with std::net::TcpListener or the libc based RawListener (default: std)
+  --tcp-nodelay          set TCP_NODELAY, sending small writes right away (default: off)
+  --keepalive            send TCP keepalive probes on idle connections (default: off)
+  --keepalive-idle <secs>
+                         idle time before the first probe, implies --keepalive (default: the kernel's)
+  --keepalive-interval <secs>
+                         time between probes, implies --keepalive (default: the kernel's)
+  --keepalive-count <n>  unanswered probes before the connection is dropped, implies --keepalive
+                         (default: the kernel's)
+  --rcvbuf <bytes>       SO_RCVBUF of TCP sockets (default: the kernel's)
+  --sndbuf <bytes>       SO_SNDBUF of TCP sockets (default: the kernel's)
+  --reuseport            set SO_REUSEPORT, so other processes can listen on the same ports (default: off)
+  --acceptors <n>        accept threads, each with its own SO_REUSEPORT listener per TCP address,
+                         --io thread only (default: 1)
+  --metrics <addr>       serve Prometheus metrics at http://<addr>/metrics, e.g. 127.0.0.1:9100 (default: off)
+  --access-log <-|file>  write one record per connection to stdout (-) or a file (default: off)
+  --access-log-format <json|logfmt>
+                         format of the access log records (default: json)
+  --access-log-max-bytes <bytes>
+                         rotate the access log file once it reaches this size, 0 never (default: 10485760)
+  --access-log-keep <n>  rotated access log files to keep (default: 5)
+  --record <file>        append every byte of every TCP and Unix connection to this file, for replay;
+                         recorded connections aren't spliced by --zero-copy, --io thread only (default: off)
+  --idle-timeout <secs>  close connections that send nothing for this long (default: none)
+  --read-timeout <secs>  close connections that take longer than this to finish a request (default: none)
+  --write-timeout <secs> close connections that don't read their replies for this long (default: none)
+  --grace-period <secs>  how long live connections get to finish on SIGINT/SIGTERM (default: 5)
+  -h, --help             print this help
+
+Started by a service manager that passes sockets (LISTEN_PID, LISTEN_FDS and LISTEN_FDNAMES, as
+systemd does), the server serves those instead of --listen and --udp: listening TCP and Unix stream
+sockets and UDP sockets. A socket named after a protocol, e.g. FileDescriptorName=line, speaks it.";
+
+use crate::access_log::AccessLogOptions;
+use crate::http::HttpLimits;
+use crate::limits::AdmissionOptions;
+use crate::listen::ListenSpec;
+use crate::proxy_protocol::ProxyMode;
+use crate::relay::RelayOptions;
+use crate::socket_options::{Keepalive, SocketOptions};
+use crate::timeouts::Timeouts;
+use crate::udp::UdpOptions;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoModel {
+    /// One `std::thread` per accepted connection.
+    Thread,
+    /// Readiness loop built on `epoll`, with non-blocking sockets.
+    Epoll,
+}
+
+impl std::str::FromStr for IoModel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(IoModel::Thread),
+            "epoll" => Ok(IoModel::Epoll),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerKind {
+    /// `std::net::TcpListener`.
+    Std,
+    /// `raw_listener::RawListener`, built on raw libc calls.
+    Raw,
+}
+
+impl std::str::FromStr for ListenerKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "std" => Ok(ListenerKind::Std),
+            "raw" => Ok(ListenerKind::Raw),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// Every byte received is sent back; a chunk starting with `bye` ends the session.
+    Raw,
+    /// Newline framed commands, see `line_protocol`.
+    Line,
+    /// Length-prefixed messages, echoed back whole, see `framing`.
+    Framed,
+    /// HTTP/1.1 requests, answered with a JSON description of themselves, see `http`.
+    Http,
+    /// WebSocket messages, echoed after the upgrade handshake, see `websocket`.
+    WebSocket,
+}
+
+impl std::str::FromStr for Protocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Protocol::Raw),
+            "line" => Ok(Protocol::Line),
+            "framed" => Ok(Protocol::Framed),
+            "http" => Ok(Protocol::Http),
+            "websocket" => Ok(Protocol::WebSocket),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Raw => f.write_str("raw"),
+            Self::Line => f.write_str("line"),
+            Self::Framed => f.write_str("framed"),
+            Self::Http => f.write_str("http"),
+            Self::WebSocket => f.write_str("websocket"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// Every client talks to the server alone, in its listener's protocol.
+    Echo,
+    /// Lines are relayed between all connected clients, see `broadcast`.
+    Broadcast,
+}
+
+impl std::str::FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "echo" => Ok(Mode::Echo),
+            "broadcast" => Ok(Mode::Broadcast),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<ListenSpec>,
+    pub udp: Vec<std::net::SocketAddr>,
+    pub udp_options: UdpOptions,
+    pub io_model: IoModel,
+    pub workers: usize,
+    pub pool_size: usize,
+    pub accept_queue: usize,
+    pub max_connections: usize,
+    pub admission: AdmissionOptions,
+    pub listener: ListenerKind,
+    pub socket: SocketOptions,
+    /// Accept loops of the thread-per-connection server; more than one needs `SO_REUSEPORT`.
+    pub acceptors: usize,
+    pub protocol: Protocol,
+    pub mode: Mode,
+    pub relay: RelayOptions,
+    /// Echo raw connections with `splice`, see the splice module.
+    pub zero_copy: bool,
+    pub max_frame: usize,
+    pub http: HttpLimits,
+    pub grace_period: std::time::Duration,
+    pub timeouts: Timeouts,
+    pub access_log: AccessLogOptions,
+    /// File to record sessions to, see the recording module.
+    pub record: Option<String>,
+    pub metrics: Option<std::net::SocketAddr>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: Vec::new(),
+            udp: Vec::new(),
+            udp_options: UdpOptions::default(),
+            io_model: IoModel::Thread,
+            workers: 1,
+            pool_size: 0,
+            accept_queue: 64,
+            max_connections: 0,
+            admission: AdmissionOptions::default(),
+            listener: ListenerKind::Std,
+            socket: SocketOptions::default(),
+            acceptors: 1,
+            protocol: Protocol::Raw,
+            mode: Mode::Echo,
+            relay: RelayOptions::default(),
+            zero_copy: false,
+            max_frame: crate::framing::DEFAULT_MAX_FRAME,
+            http: HttpLimits::default(),
+            grace_period: std::time::Duration::from_secs(5),
+            timeouts: Timeouts::default(),
+            access_log: AccessLogOptions::default(),
+            record: None,
+            metrics: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    Help,
+    UnknownFlag(String),
+    MissingValue(String),
+    InvalidValue { flag: String, value: String },
+    File { path: String, reason: String },
+    Conflict(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Help => f.write_str(USAGE),
+            Self::UnknownFlag(flag) => write!(f, "unknown option '{}'", flag),
+            Self::MissingValue(flag) => write!(f, "option '{}' expects a value", flag),
+            Self::InvalidValue { flag, value } => write!(f, "invalid value '{}' for option '{}'", value, flag),
+            Self::File { path, reason } => write!(f, "{}: {}", path, reason),
+            Self::Conflict(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds a configuration from command line arguments, without the program name.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Config, ConfigError> {
+        let mut config: Config = Config::default();
+        config.apply(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Fills in the defaults that depend on other options and rejects combinations the
+    /// server can't run; `from_args` and `EchoServerBuilder::spawn` both go through this.
+    pub fn validate(&mut self) -> Result<(), ConfigError> {
+        if self.listen.is_empty() {
+            self.listen.push(ListenSpec::default());
+        }
+        for spec in &mut self.listen {
+            spec.protocol.get_or_insert(self.protocol);
+        }
+
+        if self.io_model == IoModel::Epoll && self.listen.iter().any(|spec| spec.protocol() != Protocol::Raw) {
+            return Err(ConfigError::Conflict("--io epoll only supports the raw protocol".to_owned()));
+        }
+        if self.io_model == IoModel::Epoll && self.listen.iter().any(ListenSpec::is_unix) {
+            return Err(ConfigError::Conflict("--io epoll only supports TCP listeners".to_owned()));
+        }
+        if self.io_model == IoModel::Epoll && self.listen.iter().any(|spec| spec.proxy != ProxyMode::Off) {
+            return Err(ConfigError::Conflict("--io epoll doesn't read PROXY headers".to_owned()));
+        }
+        if self.mode == Mode::Broadcast && self.io_model == IoModel::Epoll {
+            return Err(ConfigError::Conflict("--mode broadcast only supports --io thread".to_owned()));
+        }
+        if self.mode == Mode::Broadcast && self.listen.iter().any(|spec| spec.protocol() != Protocol::Raw) {
+            return Err(ConfigError::Conflict("--mode broadcast speaks its own line protocol, listeners can't pick one".to_owned()));
+        }
+        if self.relay.upstream.is_some() && self.io_model == IoModel::Epoll {
+            return Err(ConfigError::Conflict("--upstream only supports --io thread".to_owned()));
+        }
+        if self.relay.upstream.is_some() && self.mode == Mode::Broadcast {
+            return Err(ConfigError::Conflict("--upstream and --mode broadcast don't go together".to_owned()));
+        }
+        if self.relay.upstream.is_some() && self.listen.iter().any(|spec| spec.protocol() != Protocol::Raw) {
+            return Err(ConfigError::Conflict("--upstream relays bytes as they are, listeners can't pick a protocol".to_owned()));
+        }
+        if self.relay.hex_dump && self.relay.upstream.is_none() {
+            return Err(ConfigError::Conflict("--hex-dump only applies to --upstream".to_owned()));
+        }
+        if self.zero_copy && self.io_model == IoModel::Epoll {
+            return Err(ConfigError::Conflict("--zero-copy only supports --io thread".to_owned()));
+        }
+        if self.zero_copy && (self.relay.upstream.is_some() || self.mode == Mode::Broadcast) {
+            return Err(ConfigError::Conflict("--zero-copy only applies to the raw echo, not to --upstream or --mode broadcast".to_owned()));
+        }
+        // A zero timeout means none, which is what `set_read_timeout` and friends need to hear,
+        // whether it came from the command line or from code.
+        for timeout in [&mut self.timeouts.idle, &mut self.timeouts.read, &mut self.timeouts.write] {
+            if timeout.is_some_and(|timeout| timeout.is_zero()) {
+                *timeout = None;
+            }
+        }
+        // Checked here rather than while parsing, so configs built in code can't get past it either.
+        if !(1..=crate::udp::MAX_DATAGRAM).contains(&self.udp_options.max_datagram) {
+            return Err(ConfigError::InvalidValue { flag: "--udp-max-datagram".to_owned(), value: self.udp_options.max_datagram.to_string() });
+        }
+        if self.record.is_some() && self.io_model == IoModel::Epoll {
+            return Err(ConfigError::Conflict("--record only supports --io thread".to_owned()));
+        }
+        if self.acceptors > 1 && self.io_model == IoModel::Epoll {
+            return Err(ConfigError::Conflict("--acceptors only applies to --io thread, --io epoll accepts from its --workers".to_owned()));
+        }
+        if self.acceptors > 1 {
+            self.socket.reuse_port = true;
+        }
+        if self.io_model == IoModel::Epoll && self.pool_size > 0 {
+            return Err(ConfigError::Conflict("--pool-size only applies to --io thread".to_owned()));
+        }
+        Ok(())
+    }
+
+    fn apply<I: IntoIterator<Item = String>>(&mut self, args: I) -> Result<(), ConfigError> {
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "-h" | "--help" => return Err(ConfigError::Help),
+                "--listen" => self.listen.push(parse_value(&flag, args.next())?),
+                "--config" => {
+                    let path: String = args.next().ok_or_else(|| ConfigError::MissingValue(flag.clone()))?;
+                    self.apply(read_config_file(&path)?)?;
+                }
+                "--udp" => self.udp.push(parse_value(&flag, args.next())?),
+                "--udp-max-datagram" => self.udp_options.max_datagram = parse_value(&flag, args.next())?,
+                "--udp-drop" => self.udp_options.drop_percent = parse_percent(&flag, args.next())?,
+                "--udp-delay" => self.udp_options.delay_percent = parse_percent(&flag, args.next())?,
+                "--udp-delay-ms" => self.udp_options.delay = std::time::Duration::from_millis(parse_value(&flag, args.next())?),
+                "--io" => self.io_model = parse_value(&flag, args.next())?,
+                "--workers" => {
+                    self.workers = parse_value(&flag, args.next())?;
+                    if self.workers == 0 {
+                        return Err(ConfigError::InvalidValue { flag, value: "0".to_owned() });
+                    }
+                }
+                "--pool-size" => self.pool_size = parse_value(&flag, args.next())?,
+                "--accept-queue" => self.accept_queue = parse_value(&flag, args.next())?,
+                "--max-connections" => self.max_connections = parse_value(&flag, args.next())?,
+                "--allow" => self.admission.allow.push(parse_value(&flag, args.next())?),
+                "--deny" => self.admission.deny.push(parse_value(&flag, args.next())?),
+                "--connection-rate" => self.admission.connection_rate = parse_rate(&flag, args.next())?,
+                "--connection-burst" => self.admission.connection_burst = parse_rate(&flag, args.next())?,
+                "--byte-rate" => self.admission.byte_rate = parse_rate(&flag, args.next())?,
+                "--byte-burst" => self.admission.byte_burst = parse_rate(&flag, args.next())?,
+                "--listener" => self.listener = parse_value(&flag, args.next())?,
+                "--tcp-nodelay" => self.socket.nodelay = true,
+                "--keepalive" => {
+                    self.socket.keepalive.get_or_insert_with(Keepalive::default);
+                }
+                "--keepalive-idle" => self.socket.keepalive.get_or_insert_with(Keepalive::default).idle = Some(parse_value(&flag, args.next())?),
+                "--keepalive-interval" => self.socket.keepalive.get_or_insert_with(Keepalive::default).interval = Some(parse_value(&flag, args.next())?),
+                "--keepalive-count" => self.socket.keepalive.get_or_insert_with(Keepalive::default).count = Some(parse_value(&flag, args.next())?),
+                "--rcvbuf" => self.socket.recv_buffer = Some(parse_value(&flag, args.next())?),
+                "--sndbuf" => self.socket.send_buffer = Some(parse_value(&flag, args.next())?),
+                "--reuseport" => self.socket.reuse_port = true,
+                "--acceptors" => {
+                    self.acceptors = parse_value(&flag, args.next())?;
+                    if self.acceptors == 0 {
+                        return Err(ConfigError::InvalidValue { flag, value: "0".to_owned() });
+                    }
+                }
+                "--protocol" => self.protocol = parse_value(&flag, args.next())?,
+                "--mode" => self.mode = parse_value(&flag, args.next())?,
+                "--upstream" => self.relay.upstream = Some(parse_value(&flag, args.next())?),
+                "--hex-dump" => self.relay.hex_dump = true,
+                "--zero-copy" => self.zero_copy = true,
+                "--max-frame" => self.max_frame = parse_value(&flag, args.next())?,
+                "--http-max-head" => self.http.max_head = parse_value(&flag, args.next())?,
+                "--http-max-body" => self.http.max_body = parse_value(&flag, args.next())?,
+                "--grace-period" => self.grace_period = parse_secs(&flag, args.next())?,
+                "--idle-timeout" => self.timeouts.idle = Some(parse_secs(&flag, args.next())?),
+                "--read-timeout" => self.timeouts.read = Some(parse_secs(&flag, args.next())?),
+                "--write-timeout" => self.timeouts.write = Some(parse_secs(&flag, args.next())?),
+                "--metrics" => self.metrics = Some(parse_value(&flag, args.next())?),
+                "--access-log" => self.access_log.target = Some(parse_value(&flag, args.next())?),
+                "--access-log-format" => self.access_log.format = parse_value(&flag, args.next())?,
+                "--access-log-max-bytes" => self.access_log.max_bytes = parse_value(&flag, args.next())?,
+                "--access-log-keep" => self.access_log.keep = parse_value(&flag, args.next())?,
+                "--record" => self.record = Some(parse_value(&flag, args.next())?),
+                _ => return Err(ConfigError::UnknownFlag(flag)),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Seconds, fractions allowed: `5`, `0.25`.
+fn parse_secs(flag: &str, value: Option<String>) -> Result<std::time::Duration, ConfigError> {
+    let secs: f64 = parse_value(flag, value)?;
+    std::time::Duration::try_from_secs_f64(secs).map_err(|_| ConfigError::InvalidValue { flag: flag.to_owned(), value: secs.to_string() })
+}
+
+/// Per second rates and bursts: not negative, fractions allowed.
+fn parse_rate(flag: &str, value: Option<String>) -> Result<f64, ConfigError> {
+    let rate: f64 = parse_value(flag, value)?;
+    if !rate.is_finite() || rate < 0.0 {
+        return Err(ConfigError::InvalidValue { flag: flag.to_owned(), value: rate.to_string() });
+    }
+    Ok(rate)
+}
+
+fn parse_percent(flag: &str, value: Option<String>) -> Result<u8, ConfigError> {
+    let percent: u8 = parse_value(flag, value)?;
+    if percent > 100 {
+        return Err(ConfigError::InvalidValue { flag: flag.to_owned(), value: percent.to_string() });
+    }
+    Ok(percent)
+}
+
+/// Turns every `key = value` line of a config file into `--key value`.
+/// Blank lines and lines starting with `#` are skipped.
+fn read_config_file(path: &str) -> Result<Vec<String>, ConfigError> {
+    let content: String = std::fs::read_to_string(path).map_err(|err| ConfigError::File { path: path.to_owned(), reason: err.to_string() })?;
+    config_file_args(path, &content)
+}
+
+/// Options that take no value.
+const SWITCHES: &[&str] = &["--hex-dump", "--zero-copy", "--tcp-nodelay", "--keepalive", "--reuseport"];
+
+fn config_file_args(path: &str, content: &str) -> Result<Vec<String>, ConfigError> {
+    let mut args: Vec<String> = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line: &str = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value): (&str, &str) = line.split_once('=').ok_or_else(|| ConfigError::File {
+            path: path.to_owned(),
+            reason: format!("line {}: expected 'key = value'", number + 1),
+        })?;
+        let (flag, value): (String, &str) = (format!("--{}", key.trim()), value.trim());
+        // A file including itself, directly or through others, would never stop loading.
+        if flag == "--config" {
+            return Err(ConfigError::File { path: path.to_owned(), reason: format!("line {}: config files can't include other config files", number + 1) });
+        }
+        if SWITCHES.contains(&flag.as_str()) {
+            // Switches take no value on the command line; in a file they're turned on with `true`.
+            match value {
+                "true" => args.push(flag),
+                "false" => {}
+                _ => return Err(ConfigError::InvalidValue { flag, value: value.to_owned() }),
+            }
+            continue;
+        }
+        args.push(flag);
+        args.push(value.to_owned());
+    }
+
+    Ok(args)
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, ConfigError> {
+    let value: String = value.ok_or_else(|| ConfigError::MissingValue(flag.to_owned()))?;
+    value.parse::<T>().map_err(|_| ConfigError::InvalidValue { flag: flag.to_owned(), value })
+}
+
+#[test]
+fn config_from_args_test() {
+    let args = ["--io", "epoll", "--workers", "4", "--listener", "raw", "--grace-period", "0", "--idle-timeout", "0", "--access-log", "-", "--access-log-format", "logfmt"];
+    let args = args.iter().map(|s| s.to_string());
+    let config: Config = Config::from_args(args).unwrap();
+
+    assert_eq!(config.io_model, IoModel::Epoll);
+    assert_eq!(config.workers, 4);
+    assert_eq!(config.listener, ListenerKind::Raw);
+    assert_eq!(config.grace_period, std::time::Duration::ZERO);
+    assert_eq!(config.timeouts, Timeouts::default());
+    assert_eq!(config.access_log.target.as_deref(), Some("-"));
+    assert_eq!(config.access_log.format, crate::access_log::LogFormat::Logfmt);
+    assert_eq!(config.listen.len(), 1);
+    assert_eq!(config.listen[0].addr, ListenSpec::default().addr);
+    assert_eq!(config.listen[0].protocol(), Protocol::Raw);
+
+    let mut config: Config = Config { timeouts: Timeouts { idle: Some(std::time::Duration::ZERO), read: None, write: Some(std::time::Duration::ZERO) }, ..Config::default() };
+    config.validate().unwrap();
+    assert_eq!(config.timeouts, Timeouts::default());
+}
+
+#[test]
+fn config_protocol_test() {
+    let args = ["--protocol", "line", "--listen", "127.0.0.1:9000", "--listen", "127.0.0.1:9001,protocol=raw"];
+    let config: Config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+    assert_eq!(config.listen[0].protocol(), Protocol::Line);
+    assert_eq!(config.listen[1].protocol(), Protocol::Raw);
+
+    let args = ["--listen", "127.0.0.1:9000,protocol=framed", "--max-frame", "4096"];
+    let config: Config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+    assert_eq!(config.listen[0].protocol(), Protocol::Framed);
+    assert_eq!(config.max_frame, 4096);
+
+    let config: Config = Config::from_args(["--mode", "broadcast"].iter().map(|s| s.to_string())).unwrap();
+    assert_eq!(config.mode, Mode::Broadcast);
+}
+
+#[test]
+fn config_admission_test() {
+    let args = ["--allow", "10.0.0.0/8", "--allow", "::1", "--deny", "10.9.0.0/16", "--connection-rate", "5", "--byte-rate", "1024", "--byte-burst", "4096"];
+    let config: Config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+    assert_eq!(config.admission.allow, ["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]);
+    assert_eq!(config.admission.deny, ["10.9.0.0/16".parse().unwrap()]);
+    assert_eq!(config.admission.connection_rate, 5.0);
+    assert_eq!(config.admission.connection_burst, 0.0);
+    assert_eq!((config.admission.byte_rate, config.admission.byte_burst), (1024.0, 4096.0));
+}
+
+#[test]
+fn config_socket_options_test() {
+    let args = ["--tcp-nodelay", "--keepalive-idle", "30", "--keepalive-count", "3", "--rcvbuf", "65536", "--acceptors", "4"];
+    let config: Config = Config::from_args(args.iter().map(|s| s.to_string())).unwrap();
+
+    assert!(config.socket.nodelay);
+    assert_eq!(config.socket.keepalive, Some(Keepalive { idle: Some(30), interval: None, count: Some(3) }));
+    assert_eq!((config.socket.recv_buffer, config.socket.send_buffer), (Some(65536), None));
+    assert_eq!(config.acceptors, 4);
+    assert!(config.socket.reuse_port);
+
+    let config: Config = Config::from_args(["--keepalive"].iter().map(|s| s.to_string())).unwrap();
+    assert_eq!(config.socket.keepalive, Some(Keepalive::default()));
+    assert!(!config.socket.reuse_port);
+}
+
+#[test]
+fn config_file_test() {
+    let content: &str = "# two listeners\nlisten = 127.0.0.1:9000\n\nlisten = [::]:9000,v6only\nio = epoll\n";
+    let args: Vec<String> = config_file_args("echo.conf", content).unwrap();
+    let config: Config = Config::from_args(args).unwrap();
+
+    assert_eq!(config.io_model, IoModel::Epoll);
+    assert_eq!(config.listen.len(), 2);
+    assert!(config.listen[1].only_v6);
+
+    let args: Vec<String> = config_file_args("echo.conf", "upstream = localhost:5432\nhex-dump = true\n").unwrap();
+    assert_eq!(args, ["--upstream", "localhost:5432", "--hex-dump"]);
+    let config: Config = Config::from_args(args).unwrap();
+    assert_eq!(config.relay.upstream, Some("localhost:5432".parse().unwrap()));
+    assert!(config.relay.hex_dump);
+    assert!(config_file_args("echo.conf", "hex-dump = yes").is_err());
+
+    let err: ConfigError = config_file_args("echo.conf", "listen 127.0.0.1:9000").unwrap_err();
+    assert_eq!(err.to_string(), "echo.conf: line 1: expected 'key = value'");
+
+    let err: ConfigError = config_file_args("echo.conf", "io = epoll\nconfig = echo.conf\n").unwrap_err();
+    assert_eq!(err.to_string(), "echo.conf: line 2: config files can't include other config files");
+}
+
+#[test]
+fn config_from_args_errors_test() {
+    let parse = |args: &[&str]| Config::from_args(args.iter().map(|s| s.to_string())).unwrap_err();
+
+    assert_eq!(parse(&["--io"]), ConfigError::MissingValue("--io".to_owned()));
+    assert_eq!(parse(&["--io", "select"]), ConfigError::InvalidValue { flag: "--io".to_owned(), value: "select".to_owned() });
+    assert_eq!(parse(&["--workers", "0"]), ConfigError::InvalidValue { flag: "--workers".to_owned(), value: "0".to_owned() });
+    assert_eq!(parse(&["--verbose"]), ConfigError::UnknownFlag("--verbose".to_owned()));
+    assert!(matches!(parse(&["--io", "epoll", "--protocol", "line"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--io", "epoll", "--listen", "unix:@echo"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--io", "epoll", "--listen", "127.0.0.1:9000,proxy=optional"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--mode", "broadcast", "--io", "epoll"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--mode", "broadcast", "--listen", "127.0.0.1:9000,protocol=line"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--upstream", "127.0.0.1:5432", "--io", "epoll"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--upstream", "127.0.0.1:5432", "--protocol", "line"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--hex-dump"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--zero-copy", "--io", "epoll"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--acceptors", "4", "--io", "epoll"]), ConfigError::Conflict(_)));
+    assert!(matches!(parse(&["--record", "sessions.jsonl", "--io", "epoll"]), ConfigError::Conflict(_)));
+    assert_eq!(parse(&["--acceptors", "0"]), ConfigError::InvalidValue { flag: "--acceptors".to_owned(), value: "0".to_owned() });
+    assert!(matches!(parse(&["--zero-copy", "--mode", "broadcast"]), ConfigError::Conflict(_)));
+    assert_eq!(parse(&["--upstream", "5432"]), ConfigError::InvalidValue { flag: "--upstream".to_owned(), value: "5432".to_owned() });
+    assert_eq!(parse(&["--idle-timeout", "-1"]), ConfigError::InvalidValue { flag: "--idle-timeout".to_owned(), value: "-1".to_owned() });
+    assert_eq!(parse(&["--allow", "10.0.0.0/40"]), ConfigError::InvalidValue { flag: "--allow".to_owned(), value: "10.0.0.0/40".to_owned() });
+    assert_eq!(parse(&["--byte-rate", "-5"]), ConfigError::InvalidValue { flag: "--byte-rate".to_owned(), value: "-5".to_owned() });
+    assert_eq!(parse(&["--udp-max-datagram", "0"]), ConfigError::InvalidValue { flag: "--udp-max-datagram".to_owned(), value: "0".to_owned() });
+    let too_big: String = usize::MAX.to_string();
+    assert_eq!(parse(&["--udp-max-datagram", &too_big]), ConfigError::InvalidValue { flag: "--udp-max-datagram".to_owned(), value: too_big.clone() });
+    assert_eq!(parse(&["--udp-drop", "101"]), ConfigError::InvalidValue { flag: "--udp-drop".to_owned(), value: "101".to_owned() });
+}
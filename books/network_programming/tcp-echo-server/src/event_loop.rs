@@ -0,0 +1,516 @@
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{AccessLog, CloseReason, ConnectionRecord};
+use crate::config::{Config, Protocol};
+use crate::limits::{self, Admission, RejectReason};
+use crate::rate_limit::RateLimiter;
+use crate::shutdown::{DrainSummary, Shutdown, POLL_INTERVAL};
+use crate::socket_options::SocketOptions;
+use crate::stats::{ActiveConnection, Stats};
+use crate::timeouts::{TimeoutError, TimeoutKind, Timeouts};
+
+/// Listening sockets are registered with this bit set plus their index; connections use their own fd as token.
+const LISTENER_TOKEN: u64 = 1 << 63;
+/// Once this many echoed bytes are waiting to be written we stop reading from the client.
+const MAX_PENDING_WRITE: usize = 64 * 1024;
+const MAX_EVENTS: usize = 256;
+
+/// Thin owner of an epoll instance.
+struct Epoll {
+    fd: RawFd,
+}
+
+impl Epoll {
+    fn new() -> std::io::Result<Epoll> {
+        let fd: RawFd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Epoll { fd })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32, token: u64) -> std::io::Result<()> {
+        let mut event = libc::epoll_event { events, u64: token };
+        if unsafe { libc::epoll_ctl(self.fd, op, fd, &mut event) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn add(&self, fd: RawFd, events: u32, token: u64) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    fn modify(&self, fd: RawFd, events: u32, token: u64) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    fn delete(&self, fd: RawFd) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_DEL, fd, 0, 0)
+    }
+
+    /// Waits for events, returning how many entries of `events` were filled in.
+    /// A wait interrupted by a signal is reported as zero events.
+    fn wait(&self, events: &mut [libc::epoll_event], timeout_ms: i32) -> std::io::Result<usize> {
+        let n: i32 = unsafe { libc::epoll_wait(self.fd, events.as_mut_ptr(), events.len() as i32, timeout_ms) };
+        if n < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(n as usize)
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    peer: SocketAddr,
+    read_buf: [u8; 512],
+    write_buf: Vec<u8>,
+    /// Set once the client said `bye` or closed its side; the connection is closed after `write_buf` is flushed.
+    closing: bool,
+    said_bye: bool,
+    interest: u32,
+    last_read: Instant,
+    /// Since when the client hasn't taken any of the pending echo.
+    write_stalled_since: Option<Instant>,
+    /// When the oldest byte that hasn't been echoed yet arrived, for the round trip histogram.
+    request_started: Option<Instant>,
+    /// Set while the peer is over its byte rate; nothing is read until then.
+    throttled_until: Option<Instant>,
+    connected_at: SystemTime,
+    started: Instant,
+    bytes_in: u64,
+    bytes_out: u64,
+    messages: u64,
+    _active: ActiveConnection,
+}
+
+impl Connection {
+    fn new(stream: TcpStream, peer: SocketAddr, active: ActiveConnection) -> Connection {
+        Connection {
+            stream,
+            peer,
+            read_buf: [0; 512],
+            write_buf: Vec::new(),
+            closing: false,
+            said_bye: false,
+            interest: libc::EPOLLIN as u32,
+            last_read: Instant::now(),
+            write_stalled_since: None,
+            request_started: None,
+            throttled_until: None,
+            connected_at: SystemTime::now(),
+            started: Instant::now(),
+            bytes_in: 0,
+            bytes_out: 0,
+            messages: 0,
+            _active: active,
+        }
+    }
+
+    /// Reads whatever is available, as far as the peer's byte rate allows, and queues it to be echoed back.
+    fn on_readable(&mut self, stats: &Stats, limiter: &RateLimiter) -> std::io::Result<()> {
+        while !self.closing && self.throttled_until.is_none() && self.write_buf.len() < MAX_PENDING_WRITE {
+            let max: usize = self.read_buf.len().min(limiter.max_read());
+            match self.stream.read(&mut self.read_buf[..max]) {
+                Ok(0) => self.closing = true,
+                Ok(bytes_read) => {
+                    stats.add_in(bytes_read);
+                    self.bytes_in += bytes_read as u64;
+                    self.messages += 1;
+                    self.last_read = Instant::now();
+                    self.request_started.get_or_insert(self.last_read);
+                    let chunk: &[u8] = &self.read_buf[..bytes_read];
+                    if String::from_utf8_lossy(chunk).starts_with("bye") {
+                        self.write_buf.extend_from_slice(b"bye");
+                        self.closing = true;
+                        self.said_bye = true;
+                    } else {
+                        self.write_buf.extend_from_slice(chunk);
+                    }
+                    let wait: Duration = limiter.take_bytes(self.peer.ip(), bytes_read);
+                    if !wait.is_zero() {
+                        self.throttled_until = Some(self.last_read + wait);
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the pending buffer as the socket accepts.
+    fn flush(&mut self, stats: &Stats) -> std::io::Result<()> {
+        while !self.write_buf.is_empty() {
+            match self.stream.write(&self.write_buf) {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    stats.add_out(written);
+                    self.bytes_out += written as u64;
+                    if let Some(started) = self.request_started.take() {
+                        stats.latency.observe(started.elapsed());
+                    }
+                    self.write_buf.drain(..written);
+                    self.write_stalled_since = None;
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !self.write_buf.is_empty() {
+            self.write_stalled_since.get_or_insert_with(Instant::now);
+        }
+        Ok(())
+    }
+
+    /// The timeout this connection ran into, if any. Every read is answered right away,
+    /// so a request is never in progress here and only the idle and write timeouts apply.
+    fn expired(&self, timeouts: &Timeouts, now: Instant) -> Option<TimeoutError> {
+        if let (Some(since), Some(after)) = (self.write_stalled_since, timeouts.write) {
+            if now.duration_since(since) >= after {
+                return Some(TimeoutError { kind: TimeoutKind::Write, after });
+            }
+        }
+        match timeouts.idle {
+            Some(after) if self.write_buf.is_empty() && !self.closing && now.duration_since(self.last_read) >= after => {
+                Some(TimeoutError { kind: TimeoutKind::Idle, after })
+            }
+            _ => None,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.closing && self.write_buf.is_empty()
+    }
+
+    fn record(&self, close: CloseReason) -> ConnectionRecord {
+        ConnectionRecord {
+            peer: self.peer.to_string(),
+            proxy: None,
+            protocol: Protocol::Raw,
+            connected_at: self.connected_at,
+            duration: self.started.elapsed(),
+            bytes_in: self.bytes_in,
+            bytes_out: self.bytes_out,
+            messages: self.messages,
+            close,
+        }
+    }
+
+    fn wanted_interest(&self) -> u32 {
+        let mut interest: u32 = 0;
+        if !self.closing && self.throttled_until.is_none() && self.write_buf.len() < MAX_PENDING_WRITE {
+            interest |= (libc::EPOLLIN | libc::EPOLLRDHUP) as u32;
+        }
+        if !self.write_buf.is_empty() {
+            interest |= libc::EPOLLOUT as u32;
+        }
+        interest
+    }
+}
+
+struct Worker {
+    epoll: Epoll,
+    listeners: Arc<Vec<TcpListener>>,
+    connections: HashMap<RawFd, Connection>,
+    stats: Arc<Stats>,
+    access_log: Arc<AccessLog>,
+    admission: Arc<Admission>,
+    shutdown: Shutdown,
+    grace_period: Duration,
+    max_connections: usize,
+    timeouts: Timeouts,
+    socket: SocketOptions,
+}
+
+impl Worker {
+    fn new(listeners: Arc<Vec<TcpListener>>, config: &Config, stats: Arc<Stats>, access_log: Arc<AccessLog>, admission: Arc<Admission>, shutdown: Shutdown) -> std::io::Result<Worker> {
+        let exclusive: bool = config.workers > 1;
+        let epoll: Epoll = Epoll::new()?;
+        let mut events: u32 = libc::EPOLLIN as u32;
+        if exclusive {
+            // Several workers wait on the same listeners; wake only one of them per connection.
+            events |= libc::EPOLLEXCLUSIVE as u32;
+        }
+        for (index, listener) in listeners.iter().enumerate() {
+            epoll.add(listener.as_raw_fd(), events, LISTENER_TOKEN | index as u64)?;
+        }
+
+        Ok(Worker {
+            epoll,
+            listeners,
+            connections: HashMap::new(),
+            stats,
+            access_log,
+            admission,
+            shutdown,
+            grace_period: config.grace_period,
+            max_connections: config.max_connections,
+            timeouts: config.timeouts,
+            socket: config.socket,
+        })
+    }
+
+    /// Runs until a shutdown is requested and the connections are drained or the grace period is over.
+    fn run(&mut self) -> std::io::Result<DrainSummary> {
+        let mut events: Vec<libc::epoll_event> = vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+        // Deadline and number of live connections, once draining started.
+        let mut draining: Option<(Instant, usize)> = None;
+
+        loop {
+            if draining.is_none() && self.shutdown.is_requested() {
+                draining = Some((Instant::now() + self.grace_period, self.begin_drain()));
+            }
+            if let Some((deadline, initial)) = draining {
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    let aborted: usize = self.connections.len();
+                    // Dropping the streams closes whatever is left.
+                    for (_, connection) in self.connections.drain() {
+                        self.access_log.record(&connection.record(CloseReason::Error("aborted at shutdown".to_owned())));
+                    }
+                    return Ok(DrainSummary { drained: initial - aborted, aborted });
+                }
+            }
+
+            let ready: usize = self.epoll.wait(&mut events, self.wait_timeout().as_millis() as i32)?;
+            for event in &events[..ready] {
+                let (token, flags): (u64, u32) = (event.u64, event.events);
+                if token & LISTENER_TOKEN != 0 {
+                    self.accept((token & !LISTENER_TOKEN) as usize);
+                } else {
+                    self.ready(token as RawFd, flags);
+                }
+            }
+            self.expire_timeouts();
+            self.resume_throttled();
+        }
+    }
+
+    /// How long to wait for events: the usual poll interval, or until the first throttled connection may read again.
+    fn wait_timeout(&self) -> Duration {
+        if !self.admission.limiter().limits_bytes() {
+            return POLL_INTERVAL;
+        }
+        match self.connections.values().filter_map(|connection| connection.throttled_until).min() {
+            Some(until) => until.saturating_duration_since(Instant::now()).clamp(Duration::from_millis(1), POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        }
+    }
+
+    /// Starts reading again from connections whose peer is back within its byte rate.
+    fn resume_throttled(&mut self) {
+        let now: Instant = Instant::now();
+        let resumed: Vec<RawFd> = self
+            .connections
+            .iter_mut()
+            .filter(|(_, connection)| connection.throttled_until.is_some_and(|until| until <= now))
+            .map(|(fd, connection)| {
+                connection.throttled_until = None;
+                *fd
+            })
+            .collect();
+
+        // Whatever arrived in the meantime is read right away, the interest list is updated on the way.
+        for fd in resumed {
+            self.ready(fd, libc::EPOLLIN as u32);
+        }
+    }
+
+    fn expire_timeouts(&mut self) {
+        if self.timeouts == Timeouts::default() {
+            return;
+        }
+        let now: Instant = Instant::now();
+        let expired: Vec<(RawFd, TimeoutError)> = self
+            .connections
+            .iter()
+            .filter_map(|(fd, connection)| connection.expired(&self.timeouts, now).map(|timeout| (*fd, timeout)))
+            .collect();
+
+        for (fd, timeout) in expired {
+            println!("closing {}: {}", self.connections[&fd].peer, timeout);
+            self.stats.add_error(std::io::ErrorKind::TimedOut);
+            self.close(fd, CloseReason::Timeout(timeout));
+        }
+    }
+
+    /// Stops accepting and shuts down the read side of every connection, so they finish
+    /// echoing what they already received and close. Returns the number of live connections.
+    fn begin_drain(&mut self) -> usize {
+        for listener in self.listeners.iter() {
+            let _ = self.epoll.delete(listener.as_raw_fd());
+        }
+        for connection in self.connections.values() {
+            let _ = connection.stream.shutdown(std::net::Shutdown::Read);
+        }
+        self.connections.len()
+    }
+
+    fn accept(&mut self, index: usize) {
+        loop {
+            match self.listeners[index].accept() {
+                Ok((stream, addr)) => {
+                    if let Err(reason) = self.admission.check(Some(addr.ip())) {
+                        limits::reject(stream, reason, &self.stats);
+                    } else if let Some(active) = self.stats.reserve(self.max_connections) {
+                        println!("Handling client with IP: {:?}", addr);
+                        if let Err(err) = self.register(stream, addr, active) {
+                            eprintln!("{:?}", err);
+                        }
+                    } else {
+                        limits::reject(stream, RejectReason::ConnectionLimit, &self.stats);
+                    }
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    self.stats.add_error(e.kind());
+                    eprintln!("{}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn register(&mut self, stream: TcpStream, peer: SocketAddr, active: ActiveConnection) -> std::io::Result<()> {
+        stream.set_nonblocking(true)?;
+        if let Err(err) = self.socket.apply_stream(stream.as_raw_fd()) {
+            eprintln!("couldn't set socket options: {}", err);
+        }
+        let fd: RawFd = stream.as_raw_fd();
+        self.stats.count_accepted();
+        let connection: Connection = Connection::new(stream, peer, active);
+        self.epoll.add(fd, connection.wanted_interest(), fd as u64)?;
+        self.connections.insert(fd, connection);
+        Ok(())
+    }
+
+    fn ready(&mut self, fd: RawFd, flags: u32) {
+        let stats: &Stats = &self.stats;
+        let limiter: &RateLimiter = self.admission.limiter();
+        let connection: &mut Connection = match self.connections.get_mut(&fd) {
+            Some(connection) => connection,
+            None => return,
+        };
+
+        let result: std::io::Result<bool> = (|| {
+            if flags & libc::EPOLLERR as u32 != 0 {
+                return Err(connection.stream.take_error()?.unwrap_or_else(|| std::io::ErrorKind::ConnectionReset.into()));
+            }
+            if flags & libc::EPOLLHUP as u32 != 0 {
+                // EPOLLHUP is reported whatever the interest, so a throttled connection would
+                // keep waking the loop up until it may read again. Read it out and close it now.
+                connection.throttled_until = None;
+            }
+            if flags & (libc::EPOLLIN | libc::EPOLLRDHUP | libc::EPOLLHUP) as u32 != 0 {
+                connection.on_readable(stats, limiter)?;
+            }
+            connection.flush(stats)?;
+            Ok(!connection.is_done())
+        })();
+
+        match result {
+            Ok(true) => {
+                let interest: u32 = connection.wanted_interest();
+                if interest != connection.interest {
+                    connection.interest = interest;
+                    if let Err(err) = self.epoll.modify(fd, interest, fd as u64) {
+                        eprintln!("{:?}", err);
+                        self.close(fd, CloseReason::Error(err.to_string()));
+                    }
+                }
+            }
+            Ok(false) => {
+                let close: CloseReason = if connection.said_bye { CloseReason::Bye } else { CloseReason::Eof };
+                self.close(fd, close);
+            }
+            Err(err) => {
+                eprintln!("{:?}", err);
+                self.stats.add_error(err.kind());
+                self.close(fd, CloseReason::Error(err.to_string()));
+            }
+        }
+    }
+
+    fn close(&mut self, fd: RawFd, close: CloseReason) {
+        if let Some(connection) = self.connections.remove(&fd) {
+            // Dropping the stream closes the fd, which also removes it from the interest list,
+            // but be explicit so a dup'ed fd can't keep delivering events.
+            let _ = self.epoll.delete(connection.stream.as_raw_fd());
+            self.access_log.record(&connection.record(close));
+        }
+    }
+}
+
+/// Serves `listeners` with `config.workers` event loops, each on its own thread with its own epoll instance.
+/// The calling thread runs the first worker. Returns once `shutdown` is requested and every worker drained.
+pub fn serve(
+    listeners: Vec<TcpListener>,
+    config: &Config,
+    stats: &Arc<Stats>,
+    access_log: &Arc<AccessLog>,
+    admission: &Arc<Admission>,
+    shutdown: &Shutdown,
+) -> std::io::Result<DrainSummary> {
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    let listeners: Arc<Vec<TcpListener>> = Arc::new(listeners);
+
+    let handles: Vec<std::thread::JoinHandle<std::io::Result<DrainSummary>>> = (1..config.workers)
+        .map(|_| {
+            let listeners: Arc<Vec<TcpListener>> = listeners.clone();
+            let config: Config = config.clone();
+            let stats: Arc<Stats> = stats.clone();
+            let access_log: Arc<AccessLog> = access_log.clone();
+            let admission: Arc<Admission> = admission.clone();
+            let shutdown: Shutdown = shutdown.clone();
+            std::thread::spawn(move || run_worker(listeners, &config, stats, access_log, admission, &shutdown))
+        })
+        .collect();
+
+    let mut summary: DrainSummary = run_worker(listeners, config, stats.clone(), access_log.clone(), admission.clone(), shutdown)?;
+
+    for handle in handles {
+        match handle.join() {
+            Ok(Ok(worker_summary)) => summary += worker_summary,
+            Ok(Err(err)) => eprintln!("{:?}", err),
+            Err(_) => eprintln!("event loop worker panicked"),
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Runs one worker; if it fails, the other workers are asked to stop too instead of serving on with one loop less.
+fn run_worker(
+    listeners: Arc<Vec<TcpListener>>,
+    config: &Config,
+    stats: Arc<Stats>,
+    access_log: Arc<AccessLog>,
+    admission: Arc<Admission>,
+    shutdown: &Shutdown,
+) -> std::io::Result<DrainSummary> {
+    let result: std::io::Result<DrainSummary> = Worker::new(listeners, config, stats, access_log, admission, shutdown.clone()).and_then(|mut worker| worker.run());
+    if result.is_err() {
+        shutdown.request();
+    }
+    result
+}
@@ -0,0 +1,123 @@
+//! Length-prefixed framing: every message is a `u32` big-endian length followed by that many bytes.
+
+use std::io::{Read, Write};
+
+use crate::access_log::CloseReason;
+
+pub const DEFAULT_MAX_FRAME: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The peer announced a frame over the limit; the stream can't be resynchronised after that.
+    TooLarge { len: usize, max: usize },
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooLarge { len, max } => write!(f, "frame of {} bytes is over the {} bytes limit", len, max),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameCodec {
+    max_frame: usize,
+}
+
+impl Default for FrameCodec {
+    fn default() -> Self {
+        FrameCodec { max_frame: DEFAULT_MAX_FRAME }
+    }
+}
+
+impl FrameCodec {
+    pub fn new(max_frame: usize) -> FrameCodec {
+        FrameCodec { max_frame }
+    }
+
+    pub fn max_frame(&self) -> usize {
+        self.max_frame
+    }
+
+    /// Reads one frame. Returns `None` if the stream ends cleanly before a new frame starts;
+    /// a stream ending in the middle of a frame is an `UnexpectedEof` error.
+    pub fn read_frame<R: Read>(&self, reader: &mut R) -> std::io::Result<Option<Vec<u8>>> {
+        let mut header = [0; 4];
+        let mut filled: usize = 0;
+        while filled < header.len() {
+            match reader.read(&mut header[filled..]) {
+                Ok(0) if filled == 0 => return Ok(None),
+                Ok(0) => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                Ok(bytes_read) => filled += bytes_read,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let len: usize = u32::from_be_bytes(header) as usize;
+        if len > self.max_frame {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, FrameError::TooLarge { len, max: self.max_frame }));
+        }
+
+        let mut payload: Vec<u8> = vec![0; len];
+        reader.read_exact(&mut payload)?;
+        Ok(Some(payload))
+    }
+
+    /// Writes `payload` as one frame, header and all, or fails.
+    pub fn write_frame<W: Write>(&self, writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+        let too_large = |max: usize| std::io::Error::new(std::io::ErrorKind::InvalidInput, FrameError::TooLarge { len: payload.len(), max });
+        if payload.len() > self.max_frame {
+            return Err(too_large(self.max_frame));
+        }
+        // A limit set above what the header can say doesn't lift the header's own.
+        let len: u32 = u32::try_from(payload.len()).map_err(|_| too_large(u32::MAX as usize))?;
+        writer.write_all(&len.to_be_bytes())?;
+        writer.write_all(payload)?;
+        writer.flush()
+    }
+}
+
+/// Echoes every frame back unchanged until the client closes the connection, counting them in `messages`.
+pub fn serve<S: Read + Write>(stream: &mut S, codec: FrameCodec, messages: &mut u64) -> std::io::Result<CloseReason> {
+    while let Some(payload) = codec.read_frame(stream)? {
+        codec.write_frame(stream, &payload)?;
+        *messages += 1;
+    }
+    Ok(CloseReason::Eof)
+}
+
+#[test]
+fn frame_round_trip_test() {
+    let codec: FrameCodec = FrameCodec::new(16);
+    let mut wire: Vec<u8> = Vec::new();
+    codec.write_frame(&mut wire, b"\x00binary\xff\x00").unwrap();
+    codec.write_frame(&mut wire, b"").unwrap();
+    assert_eq!(&wire[..4], &[0, 0, 0, 9]);
+
+    let mut reader: &[u8] = &wire;
+    assert_eq!(codec.read_frame(&mut reader).unwrap(), Some(b"\x00binary\xff\x00".to_vec()));
+    assert_eq!(codec.read_frame(&mut reader).unwrap(), Some(Vec::new()));
+    assert_eq!(codec.read_frame(&mut reader).unwrap(), None);
+}
+
+#[test]
+fn frame_errors_test() {
+    let codec: FrameCodec = FrameCodec::new(16);
+
+    let mut too_large: &[u8] = &[0, 0, 1, 0];
+    let err: std::io::Error = codec.read_frame(&mut too_large).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(err.to_string(), "frame of 256 bytes is over the 16 bytes limit");
+
+    let mut truncated: &[u8] = &[0, 0, 0, 5, b'a'];
+    assert_eq!(codec.read_frame(&mut truncated).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+    let mut half_header: &[u8] = &[0, 0];
+    assert_eq!(codec.read_frame(&mut half_header).unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+
+    assert_eq!(codec.write_frame(&mut Vec::new(), &[0; 17]).unwrap_err().kind(), std::io::ErrorKind::InvalidInput);
+}
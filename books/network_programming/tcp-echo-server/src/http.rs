@@ -0,0 +1,528 @@
+//! HTTP/1.1 echo: every request is answered with `200 OK` and a JSON document describing
+//! it, e.g. for `POST /submit?id=7` with a body of `hello`:
+//!
+//! ```text
+//! {"method":"POST","path":"/submit","query":"id=7","version":"HTTP/1.1","headers":{"Host":"localhost:8080","Content-Length":"5"},"body":"hello"}
+//! ```
+//!
+//! Bodies come with a `Content-Length` or `Transfer-Encoding: chunked`; a body that isn't
+//! UTF-8 is echoed as `"body_hex"` instead. Repeated headers are joined with `, `.
+//!
+//! Connections are kept alive unless the client asks otherwise (`Connection: close`, or
+//! HTTP/1.0 without `Connection: keep-alive`). Malformed requests are answered with
+//! `400 Bad Request`, requests over `HttpLimits` with `431` or `413`, and close the
+//! connection, since there's no telling where the next request would start.
+
+use std::io::{Read, Write};
+
+use crate::access_log::{json_escape, CloseReason};
+
+pub const DEFAULT_MAX_HEAD: usize = 8 * 1024;
+pub const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HttpLimits {
+    /// Request line and headers together, and chunked trailers.
+    pub max_head: usize,
+    /// The body, after removing the chunked encoding.
+    pub max_body: usize,
+}
+
+impl Default for HttpLimits {
+    fn default() -> Self {
+        HttpLimits { max_head: DEFAULT_MAX_HEAD, max_body: DEFAULT_MAX_BODY }
+    }
+}
+
+/// Why a request couldn't be served; carried inside `io::ErrorKind::InvalidData` errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest(String),
+    HeadTooLarge { max: usize },
+    BodyTooLarge { max: usize },
+    /// A `Transfer-Encoding` other than `chunked`.
+    NotImplemented(String),
+    VersionNotSupported(String),
+}
+
+impl HttpError {
+    pub fn status(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "400 Bad Request",
+            Self::HeadTooLarge { .. } => "431 Request Header Fields Too Large",
+            Self::BodyTooLarge { .. } => "413 Content Too Large",
+            Self::NotImplemented(_) => "501 Not Implemented",
+            Self::VersionNotSupported(_) => "505 HTTP Version Not Supported",
+        }
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            Self::HeadTooLarge { max } => write!(f, "request head over the {} bytes limit", max),
+            Self::BodyTooLarge { max } => write!(f, "request body over the {} bytes limit", max),
+            Self::NotImplemented(coding) => write!(f, "transfer coding '{}' not supported", coding),
+            Self::VersionNotSupported(version) => write!(f, "version '{}' not supported", version),
+        }
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+fn bad_request(reason: &str) -> std::io::Error {
+    HttpError::BadRequest(reason.to_owned()).into_io()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Request {
+    pub method: String,
+    /// As sent: path and query.
+    pub target: String,
+    pub version: String,
+    /// In the order they were sent, names as they were sent.
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Every value of the header `name`, joined with `, `.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let values: Vec<&str> = self.headers.iter().filter(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str()).collect();
+        match values.is_empty() {
+            true => None,
+            false => Some(values.join(", ")),
+        }
+    }
+
+    fn has_token(&self, name: &str, token: &str) -> bool {
+        self.header(name).is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)))
+    }
+
+    pub fn path(&self) -> &str {
+        self.target.split_once('?').map_or(&self.target, |(path, _)| path)
+    }
+
+    pub fn query(&self) -> &str {
+        self.target.split_once('?').map_or("", |(_, query)| query)
+    }
+
+    /// Whether the connection stays open after this request.
+    pub fn keep_alive(&self) -> bool {
+        match self.version.as_str() {
+            "HTTP/1.0" => self.has_token("Connection", "keep-alive"),
+            _ => !self.has_token("Connection", "close"),
+        }
+    }
+
+    /// The reply body.
+    pub fn to_json(&self) -> String {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in &self.headers {
+            if !names.iter().any(|seen| seen.eq_ignore_ascii_case(name)) {
+                names.push(name);
+            }
+        }
+        let headers: Vec<String> = names
+            .iter()
+            .map(|name| format!("\"{}\":\"{}\"", json_escape(name), json_escape(&self.header(name).unwrap_or_default())))
+            .collect();
+        let body: String = match std::str::from_utf8(&self.body) {
+            Ok(text) => format!("\"body\":\"{}\"", json_escape(text)),
+            Err(_) => format!("\"body_hex\":\"{}\"", self.body.iter().map(|byte| format!("{:02x}", byte)).collect::<String>()),
+        };
+        format!(
+            "{{\"method\":\"{}\",\"path\":\"{}\",\"query\":\"{}\",\"version\":\"{}\",\"headers\":{{{}}},{}}}",
+            json_escape(&self.method),
+            json_escape(self.path()),
+            json_escape(self.query()),
+            json_escape(&self.version),
+            headers.join(","),
+            body,
+        )
+    }
+}
+
+/// Parses the request line and headers; the body is read separately.
+pub fn parse_head(head: &str) -> Result<Request, HttpError> {
+    let bad = |reason: &str| HttpError::BadRequest(reason.to_owned());
+    let mut lines = head.split('\n').map(|line| line.strip_suffix('\r').unwrap_or(line));
+
+    let request_line: &str = lines.next().unwrap_or_default();
+    let parts: Vec<&str> = request_line.split(' ').collect();
+    let (method, target, version): (&str, &str, &str) = match parts[..] {
+        [method, target, version] => (method, target, version),
+        _ => return Err(bad("malformed request line")),
+    };
+    if method.is_empty() || !method.bytes().all(is_token) {
+        return Err(bad("malformed method"));
+    }
+    if target.is_empty() || target.bytes().any(|byte| byte.is_ascii_control()) {
+        return Err(bad("malformed target"));
+    }
+    match version {
+        "HTTP/1.1" | "HTTP/1.0" => {}
+        version if version.starts_with("HTTP/") => return Err(HttpError::VersionNotSupported(version.to_owned())),
+        _ => return Err(bad("malformed version")),
+    }
+
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in lines.take_while(|line| !line.is_empty()) {
+        // Folded headers are obsolete, and a name followed by whitespace is how smuggling starts.
+        let (name, value): (&str, &str) = line.split_once(':').ok_or_else(|| bad("malformed header"))?;
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(bad("malformed header name"));
+        }
+        headers.push((name.to_owned(), value.trim_matches([' ', '\t']).to_owned()));
+    }
+
+    Ok(Request { method: method.to_owned(), target: target.to_owned(), version: version.to_owned(), headers, body: Vec::new() })
+}
+
+/// Characters allowed in methods and header names.
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// How the body of a request is delimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+fn framing(request: &Request, limits: HttpLimits) -> Result<Framing, HttpError> {
+    if let Some(coding) = request.header("Transfer-Encoding") {
+        // With both, which one wins is how smuggling works; refuse instead of guessing.
+        if request.header("Content-Length").is_some() {
+            return Err(HttpError::BadRequest("both Content-Length and Transfer-Encoding".to_owned()));
+        }
+        return match coding.trim().eq_ignore_ascii_case("chunked") {
+            true => Ok(Framing::Chunked),
+            false => Err(HttpError::NotImplemented(coding)),
+        };
+    }
+    let length: String = match request.header("Content-Length") {
+        Some(length) => length,
+        None => return Ok(Framing::Length(0)),
+    };
+    // Repeated headers are fine as long as they agree.
+    let mut lengths = length.split(',').map(str::trim);
+    let first: &str = lengths.next().unwrap_or_default();
+    if first.is_empty() || !first.bytes().all(|byte| byte.is_ascii_digit()) || lengths.any(|other| other != first) {
+        return Err(HttpError::BadRequest("invalid Content-Length".to_owned()));
+    }
+    match first.parse::<usize>() {
+        Ok(length) if length <= limits.max_body => Ok(Framing::Length(length)),
+        _ => Err(HttpError::BodyTooLarge { max: limits.max_body }),
+    }
+}
+
+/// Buffers what was read past the end of a request's head, for its body and the next request.
+pub struct HttpReader<S> {
+    inner: S,
+    buf: Vec<u8>,
+}
+
+impl<S: Read> HttpReader<S> {
+    pub fn new(inner: S) -> HttpReader<S> {
+        HttpReader { inner, buf: Vec::new() }
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Reads more into the buffer, returning how much; 0 at end of stream.
+    fn fill(&mut self) -> std::io::Result<usize> {
+        let mut chunk = [0; 4096];
+        loop {
+            match self.inner.read(&mut chunk) {
+                Ok(bytes_read) => {
+                    self.buf.extend_from_slice(&chunk[..bytes_read]);
+                    return Ok(bytes_read);
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads up to and including the empty line ending a request's head. Returns `None` if
+    /// the stream ends before a new request starts.
+    pub fn read_head(&mut self, max_head: usize) -> std::io::Result<Option<String>> {
+        loop {
+            // Empty lines between requests are skipped.
+            while self.buf.starts_with(b"\r\n") || self.buf.starts_with(b"\n") {
+                let len: usize = if self.buf[0] == b'\r' { 2 } else { 1 };
+                self.buf.drain(..len);
+            }
+            if let Some(end) = head_end(&self.buf) {
+                if end > max_head {
+                    return Err(HttpError::HeadTooLarge { max: max_head }.into_io());
+                }
+                let head: Vec<u8> = self.buf.drain(..end).collect();
+                return String::from_utf8(head).map(Some).map_err(|_| bad_request("head is not utf-8"));
+            }
+            if self.buf.len() > max_head {
+                return Err(HttpError::HeadTooLarge { max: max_head }.into_io());
+            }
+            if self.fill()? == 0 {
+                return match self.buf.iter().all(|&byte| byte == b'\r') {
+                    true => Ok(None),
+                    false => Err(bad_request("incomplete request")),
+                };
+            }
+        }
+    }
+
+    /// Reads one line of the chunked encoding, without its terminator.
+    fn read_line(&mut self, max_line: usize) -> std::io::Result<Vec<u8>> {
+        loop {
+            if let Some(newline) = self.buf.iter().position(|&byte| byte == b'\n') {
+                let mut line: Vec<u8> = self.buf.drain(..=newline).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                return Ok(line);
+            }
+            if self.buf.len() > max_line {
+                return Err(HttpError::HeadTooLarge { max: max_line }.into_io());
+            }
+            if self.fill()? == 0 {
+                return Err(bad_request("incomplete chunked body"));
+            }
+        }
+    }
+
+    fn read_exact(&mut self, len: usize) -> std::io::Result<Vec<u8>> {
+        while self.buf.len() < len {
+            if self.fill()? == 0 {
+                return Err(bad_request("incomplete body"));
+            }
+        }
+        Ok(self.buf.drain(..len).collect())
+    }
+
+    fn read_chunked(&mut self, limits: HttpLimits) -> std::io::Result<Vec<u8>> {
+        let mut body: Vec<u8> = Vec::new();
+        loop {
+            let line: Vec<u8> = self.read_line(limits.max_head)?;
+            let line: String = String::from_utf8(line).map_err(|_| bad_request("invalid chunk size"))?;
+            let size: &str = line.split(';').next().unwrap_or_default().trim();
+            // `from_str_radix` would take a sign as well.
+            if size.is_empty() || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(bad_request("invalid chunk size"));
+            }
+            let size: usize = usize::from_str_radix(size, 16).map_err(|_| HttpError::BodyTooLarge { max: limits.max_body }.into_io())?;
+            if size == 0 {
+                break;
+            }
+            // The body never exceeds the limit, and a huge size mustn't overflow the sum.
+            if size > limits.max_body - body.len() {
+                return Err(HttpError::BodyTooLarge { max: limits.max_body }.into_io());
+            }
+            body.extend_from_slice(&self.read_exact(size)?);
+            if !self.read_line(2)?.is_empty() {
+                return Err(bad_request("chunk longer than its size"));
+            }
+        }
+        // Trailers are read and dropped, up to the same limit as the head.
+        let mut trailers: usize = 0;
+        loop {
+            let line: Vec<u8> = self.read_line(limits.max_head)?;
+            if line.is_empty() {
+                return Ok(body);
+            }
+            trailers += line.len();
+            if trailers > limits.max_head {
+                return Err(HttpError::HeadTooLarge { max: limits.max_head }.into_io());
+            }
+        }
+    }
+}
+
+/// Hands out what's buffered first, e.g. the frames a WebSocket client sent right behind its handshake.
+impl<S: Read> Read for HttpReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.buf.is_empty() {
+            return self.inner.read(buf);
+        }
+        let len: usize = buf.len().min(self.buf.len());
+        buf[..len].copy_from_slice(&self.buf[..len]);
+        self.buf.drain(..len);
+        Ok(len)
+    }
+}
+
+/// Where the empty line ending a head ends, if it's in `buf`.
+fn head_end(buf: &[u8]) -> Option<usize> {
+    buf.iter().enumerate().filter(|(_, &byte)| byte == b'\n').find_map(|(newline, _)| match &buf[newline + 1..] {
+        [b'\n', ..] => Some(newline + 2),
+        [b'\r', b'\n', ..] => Some(newline + 3),
+        _ => None,
+    })
+}
+
+/// Reads one whole request, or `None` if the stream ended in between requests.
+/// A client that sent `Expect: 100-continue` is told to go ahead before its body is read.
+pub fn read_request<S: Read + Write>(reader: &mut HttpReader<S>, limits: HttpLimits) -> std::io::Result<Option<Request>> {
+    let head: String = match reader.read_head(limits.max_head)? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut request: Request = parse_head(&head).map_err(HttpError::into_io)?;
+    let framing: Framing = framing(&request, limits).map_err(HttpError::into_io)?;
+    if framing != Framing::Length(0) && request.has_token("Expect", "100-continue") && request.version == "HTTP/1.1" {
+        reader.get_mut().write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+    request.body = match framing {
+        Framing::Length(len) => reader.read_exact(len)?,
+        Framing::Chunked => reader.read_chunked(limits)?,
+    };
+    Ok(Some(request))
+}
+
+fn respond<W: Write>(stream: &mut W, status: &str, body: &str, head_only: bool, keep_alive: bool) -> std::io::Result<()> {
+    let head: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+        status,
+        body.len(),
+        if keep_alive { "keep-alive" } else { "close" },
+    );
+    match head_only {
+        true => stream.write_all(head.as_bytes()),
+        false => stream.write_all((head + body).as_bytes()),
+    }
+}
+
+/// Answers a request that can't be served with the error's status, closing the connection.
+pub fn respond_error<W: Write>(stream: &mut W, err: &HttpError) -> std::io::Result<()> {
+    let body: String = format!("{{\"error\":\"{}\"}}", json_escape(&err.to_string()));
+    respond(stream, err.status(), &body, false, false)
+}
+
+/// Answers requests until the client closes the connection or asks to, counting them in
+/// `messages`. Requests that can't be served get an error status and end the connection.
+pub fn serve<S: Read + Write>(stream: S, limits: HttpLimits, messages: &mut u64) -> std::io::Result<CloseReason> {
+    let mut reader: HttpReader<S> = HttpReader::new(stream);
+    loop {
+        let request: Request = match read_request(&mut reader, limits) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(CloseReason::Eof),
+            Err(err) => {
+                let http_error: HttpError = match err.get_ref().and_then(|err| err.downcast_ref::<HttpError>()) {
+                    Some(http_error) => http_error.clone(),
+                    None => return Err(err),
+                };
+                respond_error(reader.get_mut(), &http_error)?;
+                *messages += 1;
+                return Ok(CloseReason::Error(http_error.to_string()));
+            }
+        };
+
+        let keep_alive: bool = request.keep_alive();
+        respond(reader.get_mut(), "200 OK", &request.to_json(), request.method == "HEAD", keep_alive)?;
+        *messages += 1;
+        if !keep_alive {
+            return Ok(CloseReason::Bye);
+        }
+    }
+}
+
+#[test]
+fn parse_head_test() {
+    let request: Request = parse_head("GET /search?q=echo&page=2 HTTP/1.1\r\nHost: localhost\r\nAccept: text/plain\r\naccept:  application/json \r\n\r\n").unwrap();
+    assert_eq!((request.method.as_str(), request.path(), request.query()), ("GET", "/search", "q=echo&page=2"));
+    assert_eq!(request.header("accept").as_deref(), Some("text/plain, application/json"));
+    assert!(request.keep_alive());
+    assert_eq!(
+        request.to_json(),
+        r#"{"method":"GET","path":"/search","query":"q=echo&page=2","version":"HTTP/1.1","headers":{"Host":"localhost","Accept":"text/plain, application/json"},"body":""}"#
+    );
+
+    assert!(!parse_head("GET / HTTP/1.0\r\n\r\n").unwrap().keep_alive());
+    assert!(parse_head("GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n").unwrap().keep_alive());
+    assert!(!parse_head("GET / HTTP/1.1\r\nConnection: close\r\n\r\n").unwrap().keep_alive());
+
+    let bad = |head: &str| parse_head(head).unwrap_err();
+    assert_eq!(bad("GET /\r\n\r\n").status(), "400 Bad Request");
+    assert_eq!(bad("GET  / HTTP/1.1\r\n\r\n").status(), "400 Bad Request");
+    assert_eq!(bad("GET / HTTP/1.1\r\nHost : x\r\n\r\n").status(), "400 Bad Request");
+    assert_eq!(bad("GET / HTTP/1.1\r\nno colon\r\n\r\n").status(), "400 Bad Request");
+    assert_eq!(bad("GET / HTTP/2.0\r\n\r\n").status(), "505 HTTP Version Not Supported");
+}
+
+#[test]
+fn read_request_test() {
+    // A reader and writer over byte slices, the writer collecting what's answered.
+    struct Wire<'a>(&'a [u8], Vec<u8>);
+
+    impl Read for Wire<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for Wire<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let limits: HttpLimits = HttpLimits { max_head: 256, max_body: 16 };
+    let wire: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello\r\n\
+        PUT /b HTTP/1.1\r\nTransfer-Encoding: chunked\r\nExpect: 100-continue\r\n\r\n3;ext=1\r\nabc\r\n2\r\n\xff\x00\r\n0\r\nX-Trailer: 1\r\n\r\n";
+    let mut reader: HttpReader<Wire> = HttpReader::new(Wire(wire, Vec::new()));
+
+    let first: Request = read_request(&mut reader, limits).unwrap().unwrap();
+    assert_eq!((first.target.as_str(), first.body.as_slice()), ("/a", &b"hello"[..]));
+    let second: Request = read_request(&mut reader, limits).unwrap().unwrap();
+    assert_eq!(second.body, b"abc\xff\x00");
+    assert!(second.to_json().ends_with(r#""body_hex":"616263ff00"}"#));
+    assert_eq!(reader.get_mut().1, b"HTTP/1.1 100 Continue\r\n\r\n");
+    assert_eq!(read_request(&mut reader, limits).unwrap(), None);
+
+    let error = |wire: &[u8]| {
+        let err: std::io::Error = read_request(&mut HttpReader::new(Wire(wire, Vec::new())), limits).unwrap_err();
+        err.get_ref().and_then(|err| err.downcast_ref::<HttpError>()).unwrap().status()
+    };
+    assert_eq!(error(b"POST / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"), "413 Content Too Large");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n11\r\n"), "413 Content Too Large");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n1\r\na\r\nffffffffffffffff\r\n"), "413 Content Too Large");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nContent-Length: 2\r\n\r\n"), "400 Bad Request");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n"), "400 Bad Request");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabc"), "400 Bad Request");
+    assert_eq!(error(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), "501 Not Implemented");
+    assert_eq!(error(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(300)).as_bytes()), "431 Request Header Fields Too Large");
+}
+
+#[test]
+fn serve_test() {
+    let wire: &[u8] = b"GET /one HTTP/1.1\r\n\r\nHEAD /two HTTP/1.1\r\n\r\nGET /three HTTP/1.1\r\nConnection: close\r\n\r\nGET /never HTTP/1.1\r\n\r\n";
+    let mut stream: std::io::Cursor<Vec<u8>> = std::io::Cursor::new(wire.to_vec());
+    let mut messages: u64 = 0;
+    assert_eq!(serve(&mut stream, HttpLimits::default(), &mut messages).unwrap(), CloseReason::Bye);
+    assert_eq!(messages, 3);
+
+    // The first read takes in all of the input, so the replies are written right after it.
+    let written: String = String::from_utf8_lossy(&stream.get_ref()[wire.len()..]).into_owned();
+    assert_eq!(written.matches("HTTP/1.1 200 OK\r\n").count(), 3);
+    // HEAD gets the headers only, so the next reply follows right after them.
+    assert!(written.contains("Connection: keep-alive\r\n\r\nHTTP/1.1 200 OK"));
+    assert!(written.ends_with("Connection: close\r\n\r\n{\"method\":\"GET\",\"path\":\"/three\",\"query\":\"\",\"version\":\"HTTP/1.1\",\"headers\":{\"Connection\":\"close\"},\"body\":\"\"}"));
+
+    let mut stream: std::io::Cursor<Vec<u8>> = std::io::Cursor::new(b"BROKEN\r\n\r\n".to_vec());
+    let close: CloseReason = serve(&mut stream, HttpLimits::default(), &mut messages).unwrap();
+    assert_eq!(close, CloseReason::Error("bad request: malformed request line".to_owned()));
+}
@@ -0,0 +1,45 @@
+//! Echo server over TCP, UDP and Unix domain sockets, with several protocols and I/O models.
+//!
+//! The `tcp-echo-server` binary is a thin wrapper around `EchoServer`; tests can run as
+//! many servers as they like side by side:
+//!
+//! ```no_run
+//! let server = tcp_echo_server::EchoServer::builder().bind("127.0.0.1:0").spawn().unwrap();
+//! let addr: std::net::SocketAddr = server.local_addr().unwrap();
+//! // ... talk to addr ...
+//! println!("{}", server.shutdown());
+//! ```
+
+pub mod access_log;
+pub mod activation;
+pub mod base64;
+pub mod broadcast;
+pub mod cidr;
+pub mod config;
+mod event_loop;
+pub mod framing;
+pub mod http;
+pub mod limits;
+pub mod line_protocol;
+pub mod listen;
+pub mod metrics;
+mod pool;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod recording;
+pub mod raw_listener;
+pub mod relay;
+pub mod replay;
+mod server;
+pub mod sha1;
+pub mod shutdown;
+pub mod socket_options;
+pub mod splice;
+pub mod stats;
+pub mod stream;
+pub mod timeouts;
+pub mod udp;
+pub mod unix_socket;
+pub mod websocket;
+
+pub use server::{EchoServer, EchoServerBuilder, ServerHandle};
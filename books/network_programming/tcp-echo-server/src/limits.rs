@@ -0,0 +1,173 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::cidr::Cidr;
+use crate::rate_limit::{Rate, RateLimiter, Throttled};
+use crate::stats::Stats;
+use crate::stream::ClientStream;
+
+/// Sent to clients that are turned away for now, right before closing their connection.
+pub const BUSY_REPLY: &[u8] = b"ERR busy, try again later\n";
+
+/// Why a connection was turned away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    ConnectionLimit,
+    AcceptQueueFull,
+    /// The peer is in a `--deny` block.
+    Denied,
+    /// There are `--allow` blocks and the peer is in none of them.
+    NotAllowed,
+    /// The peer opened connections faster than `--connection-rate`.
+    ConnectionRate,
+    /// A proxy listener's client sent a malformed PROXY header, or none when one is required.
+    ProxyHeader,
+}
+
+impl RejectReason {
+    /// Short name for metrics.
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::ConnectionLimit => "connection_limit",
+            Self::AcceptQueueFull => "accept_queue_full",
+            Self::Denied => "denied",
+            Self::NotAllowed => "not_allowed",
+            Self::ConnectionRate => "connection_rate",
+            Self::ProxyHeader => "proxy_header",
+        }
+    }
+
+    /// Clients that may come back later are told so; clients that may not are just closed.
+    fn reply(&self) -> Option<&'static [u8]> {
+        match self {
+            Self::Denied | Self::NotAllowed | Self::ProxyHeader => None,
+            Self::ConnectionLimit | Self::AcceptQueueFull | Self::ConnectionRate => Some(BUSY_REPLY),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::ConnectionLimit => f.write_str("connection limit reached"),
+            Self::AcceptQueueFull => f.write_str("accept queue full"),
+            Self::Denied => f.write_str("denied"),
+            Self::NotAllowed => f.write_str("not in the allow list"),
+            Self::ConnectionRate => f.write_str("too many connections from this address"),
+            Self::ProxyHeader => f.write_str("bad or missing PROXY header"),
+        }
+    }
+}
+
+/// Tells the client why it's turned away if it can retry, and closes the connection, without ever blocking the accept loop.
+pub fn reject<S: ClientStream>(mut stream: S, reason: RejectReason, stats: &Stats) {
+    let rejected: u64 = stats.reject(reason);
+    match stream.peer_name() {
+        Ok(peer) => eprintln!("rejected {}: {} (rejected={})", peer, reason, rejected),
+        Err(_) => eprintln!("rejected connection: {} (rejected={})", reason, rejected),
+    }
+
+    // A fresh socket's send buffer always has room for the reply, but never wait on a client that's gone.
+    if let Some(reply) = reason.reply() {
+        if stream.set_nonblocking(true).is_ok() {
+            let _ = stream.write(reply);
+        }
+    }
+}
+
+/// Who may connect, and how much, by peer IP. Unix domain clients have no IP and are
+/// never held back by these.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AdmissionOptions {
+    /// If any, only peers in one of these blocks are served.
+    pub allow: Vec<Cidr>,
+    /// Never served, even if allowed.
+    pub deny: Vec<Cidr>,
+    /// New connections per second and IP, 0 for no limit.
+    pub connection_rate: f64,
+    /// Connections an IP can open at once after being quiet, 0 for as many as `connection_rate`.
+    pub connection_burst: f64,
+    /// Bytes per second and IP read from clients, 0 for no limit.
+    pub byte_rate: f64,
+    /// Bytes an IP can send at once after being quiet, 0 for as many as `byte_rate`.
+    pub byte_burst: f64,
+}
+
+/// The checks of `AdmissionOptions`, shared by every listener.
+#[derive(Debug)]
+pub struct Admission {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Admission {
+    pub fn new(options: &AdmissionOptions) -> Admission {
+        Admission {
+            allow: options.allow.clone(),
+            deny: options.deny.clone(),
+            limiter: Arc::new(RateLimiter::new(
+                Rate::new(options.connection_rate, options.connection_burst),
+                Rate::new(options.byte_rate, options.byte_burst),
+            )),
+        }
+    }
+
+    /// Whether a new connection from `ip` may be served. Denials are checked before the
+    /// rate, so clients that are turned away anyway don't use up tokens.
+    pub fn check(&self, ip: Option<IpAddr>) -> Result<(), RejectReason> {
+        let ip: IpAddr = match ip {
+            Some(ip) => ip,
+            None => return Ok(()),
+        };
+        if self.deny.iter().any(|block| block.contains(ip)) {
+            return Err(RejectReason::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|block| block.contains(ip)) {
+            return Err(RejectReason::NotAllowed);
+        }
+        if !self.limiter.allow_connection(ip) {
+            return Err(RejectReason::ConnectionRate);
+        }
+        Ok(())
+    }
+
+    /// The limiter, for the event loop to charge the bytes it reads.
+    pub fn limiter(&self) -> &RateLimiter {
+        &self.limiter
+    }
+
+    /// Checks a client accepted by the thread per connection loop, rejecting it or wrapping
+    /// it so its reads are held to its byte rate.
+    pub fn admit(&self, stream: Box<dyn ClientStream>, stats: &Stats) -> Option<Box<dyn ClientStream>> {
+        if let Err(reason) = self.check(stream.peer_ip()) {
+            reject(stream, reason, stats);
+            return None;
+        }
+        match stream.peer_ip() {
+            Some(ip) if self.limiter.limits_bytes() => Some(Box::new(Throttled::new(stream, ip, self.limiter.clone()))),
+            _ => Some(stream),
+        }
+    }
+}
+
+#[test]
+fn admission_test() {
+    let options: AdmissionOptions = AdmissionOptions {
+        allow: vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()],
+        deny: vec!["10.0.0.66".parse().unwrap()],
+        connection_rate: 1.0,
+        connection_burst: 2.0,
+        ..AdmissionOptions::default()
+    };
+    let admission: Admission = Admission::new(&options);
+    let check = |ip: &str| admission.check(Some(ip.parse().unwrap()));
+
+    assert_eq!(check("10.1.2.3"), Ok(()));
+    assert_eq!(check("::ffff:10.1.2.3"), Ok(()));
+    assert_eq!(check("10.1.2.3"), Err(RejectReason::ConnectionRate));
+    assert_eq!(check("10.0.0.66"), Err(RejectReason::Denied));
+    assert_eq!(check("192.168.0.1"), Err(RejectReason::NotAllowed));
+    assert_eq!(check("::1"), Ok(()));
+    assert_eq!(admission.check(None), Ok(()));
+}
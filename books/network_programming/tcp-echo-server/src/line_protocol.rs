@@ -0,0 +1,362 @@
+//! Newline framed command protocol.
+//!
+//! Every request is one line terminated by `\n` (a trailing `\r` is ignored), every
+//! reply is one line as well:
+//!
+//! | request       | reply                                         |
+//! |---------------|-----------------------------------------------|
+//! | `ECHO <text>` | `<text>`                                      |
+//! | `PING`        | `PONG`                                        |
+//! | `STATS`       | `STATS accepted=.. active=.. ... peer=..`     |
+//! | `HELP`        | `OK commands: ...`                            |
+//! | `QUIT`, `bye` | `BYE`, then the server closes the connection  |
+//!
+//! Command names are case insensitive and empty lines are ignored. Anything else is
+//! answered with `ERR unknown command '<name>'`, a line longer than `MAX_LINE` bytes
+//! with `ERR line too long` (the rest of that line is discarded) and a line that is not
+//! UTF-8 with `ERR invalid utf-8`. Errors never close the connection.
+//!
+//! Clients that never send a command still get the raw echo: unless the first line is a
+//! command, the connection is echoed byte for byte, as on a raw listener. The first bytes
+//! decide as soon as they can't be the start of a command any more, so raw clients that
+//! send no newlines aren't held up.
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+
+use crate::access_log::CloseReason;
+use crate::stats::Stats;
+
+pub const MAX_LINE: usize = 1024;
+
+const HELP: &str = "OK commands: ECHO <text>, PING, STATS, QUIT, HELP";
+/// The names `Command::from_str` knows, for `detect`.
+const COMMANDS: [&str; 6] = ["ECHO", "PING", "STATS", "QUIT", "BYE", "HELP"];
+
+/// What `LineReader::read_line` found.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// A full line, without its terminator.
+    Complete(Vec<u8>),
+    /// The line went over the limit; it is skipped up to the next newline.
+    TooLong,
+}
+
+/// Buffered reader that splits a byte stream into lines, however they were split across reads.
+pub struct LineReader<R> {
+    inner: R,
+    buf: Vec<u8>,
+    max_line: usize,
+    /// Set after a `TooLong`, until the newline ending that line shows up.
+    discarding: bool,
+}
+
+impl<R: Read> LineReader<R> {
+    pub fn new(inner: R, max_line: usize) -> LineReader<R> {
+        LineReader { inner, buf: Vec::new(), max_line, discarding: false }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the next line, or `None` at end of stream. Bytes after the last newline
+    /// are returned as a final line.
+    pub fn read_line(&mut self) -> std::io::Result<Option<Line>> {
+        let mut chunk = [0; 512];
+
+        loop {
+            if let Some(newline) = self.buf.iter().position(|&b| b == b'\n') {
+                let mut line: Vec<u8> = self.buf.drain(..=newline).collect();
+                line.pop();
+                if line.last() == Some(&b'\r') {
+                    line.pop();
+                }
+                if std::mem::take(&mut self.discarding) {
+                    continue;
+                }
+                if line.len() > self.max_line {
+                    return Ok(Some(Line::TooLong));
+                }
+                return Ok(Some(Line::Complete(line)));
+            }
+
+            if self.buf.len() > self.max_line {
+                self.buf.clear();
+                if !self.discarding {
+                    self.discarding = true;
+                    return Ok(Some(Line::TooLong));
+                }
+            }
+
+            let bytes_read: usize = match self.inner.read(&mut chunk) {
+                Ok(bytes_read) => bytes_read,
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if bytes_read == 0 {
+                if self.buf.is_empty() || std::mem::take(&mut self.discarding) {
+                    self.buf.clear();
+                    return Ok(None);
+                }
+                return Ok(Some(Line::Complete(std::mem::take(&mut self.buf))));
+            }
+            self.buf.extend_from_slice(&chunk[..bytes_read]);
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Echo(String),
+    Ping,
+    Stats,
+    Quit,
+    Help,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    LineTooLong,
+    InvalidUtf8,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "ERR unknown command '{}'", name),
+            Self::LineTooLong => f.write_str("ERR line too long"),
+            Self::InvalidUtf8 => f.write_str("ERR invalid utf-8"),
+        }
+    }
+}
+
+impl std::str::FromStr for Command {
+    type Err = CommandError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let (name, argument): (&str, &str) = match line.split_once(' ') {
+            Some((name, argument)) => (name, argument),
+            None => (line, ""),
+        };
+
+        match name.to_ascii_uppercase().as_str() {
+            "ECHO" => Ok(Command::Echo(argument.to_owned())),
+            "PING" => Ok(Command::Ping),
+            "STATS" => Ok(Command::Stats),
+            "QUIT" | "BYE" => Ok(Command::Quit),
+            "HELP" => Ok(Command::Help),
+            _ => Err(CommandError::Unknown(name.to_owned())),
+        }
+    }
+}
+
+/// How a client turned out to talk, with the bytes `detect` read to find out.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Detected {
+    Commands(Vec<u8>),
+    Raw(Vec<u8>),
+}
+
+/// Reads the first bytes of a connection until they either make up a command line or
+/// can't be the start of one, whichever comes first.
+pub fn detect<R: Read>(stream: &mut R) -> std::io::Result<Detected> {
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0; 512];
+
+    let command: bool = loop {
+        if let Some(newline) = buf.iter().position(|&b| b == b'\n') {
+            break is_command(&buf[..newline]);
+        }
+        if !may_be_command(&buf) {
+            break false;
+        }
+        let bytes_read: usize = match stream.read(&mut chunk) {
+            Ok(bytes_read) => bytes_read,
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        // Like `LineReader`, the bytes after the last newline make a line of their own.
+        if bytes_read == 0 {
+            break is_command(&buf);
+        }
+        buf.extend_from_slice(&chunk[..bytes_read]);
+    };
+
+    Ok(match command {
+        true => Detected::Commands(buf),
+        false => Detected::Raw(buf),
+    })
+}
+
+/// Whether `line`, without its `\n`, is a command.
+fn is_command(line: &[u8]) -> bool {
+    let line: &[u8] = line.strip_suffix(b"\r").unwrap_or(line);
+    std::str::from_utf8(line).is_ok_and(|line| line.parse::<Command>().is_ok())
+}
+
+/// Whether `buf`, holding no complete line yet, may still turn into a command line.
+fn may_be_command(buf: &[u8]) -> bool {
+    if buf.len() > MAX_LINE {
+        return false;
+    }
+    match buf.iter().position(|&b| b == b' ' || b == b'\r') {
+        Some(end) => COMMANDS.iter().any(|name| name.as_bytes().eq_ignore_ascii_case(&buf[..end])),
+        None => COMMANDS.iter().any(|name| name.len() >= buf.len() && name.as_bytes()[..buf.len()].eq_ignore_ascii_case(buf)),
+    }
+}
+
+/// Runs the command protocol until the client quits or closes the connection, counting
+/// the commands answered in `messages`. `buffered` holds what was read from `stream`
+/// already, by `detect`. `stats` is only used to answer `STATS`; counting bytes is up to
+/// the caller.
+///
+/// `STATS` also tells the client who it is to the server: `peer`, and for clients behind
+/// a proxy, the `destination` from their PROXY header.
+pub fn serve<S: Read + Write>(
+    stream: S,
+    buffered: Vec<u8>,
+    stats: &Stats,
+    peer: &str,
+    destination: Option<SocketAddr>,
+    messages: &mut u64,
+) -> std::io::Result<CloseReason> {
+    let mut reader: LineReader<S> = LineReader { buf: buffered, ..LineReader::new(stream, MAX_LINE) };
+
+    while let Some(line) = reader.read_line()? {
+        let command: Result<Command, CommandError> = match line {
+            Line::TooLong => Err(CommandError::LineTooLong),
+            Line::Complete(line) => {
+                if line.is_empty() {
+                    continue;
+                }
+                match String::from_utf8(line) {
+                    Ok(line) => line.parse(),
+                    Err(_) => Err(CommandError::InvalidUtf8),
+                }
+            }
+        };
+
+        let quit: bool = command == Ok(Command::Quit);
+        let reply: String = match command {
+            Ok(Command::Echo(text)) => text,
+            Ok(Command::Ping) => "PONG".to_owned(),
+            Ok(Command::Stats) => match destination {
+                Some(destination) => format!("STATS {} peer={} destination={}", stats, peer, destination),
+                None => format!("STATS {} peer={}", stats, peer),
+            },
+            Ok(Command::Help) => HELP.to_owned(),
+            Ok(Command::Quit) => "BYE".to_owned(),
+            Err(err) => err.to_string(),
+        };
+
+        let stream: &mut S = reader.get_mut();
+        stream.write_all(format!("{}\n", reply).as_bytes())?;
+        *messages += 1;
+
+        if quit {
+            return Ok(CloseReason::Bye);
+        }
+    }
+
+    Ok(CloseReason::Eof)
+}
+
+#[test]
+fn line_reader_split_reads_test() {
+    // A reader handing out one byte per read, so every line spans several reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    let mut reader = LineReader::new(Trickle(b"PING\r\nECHO a b\nQU"), MAX_LINE);
+    assert_eq!(reader.read_line().unwrap(), Some(Line::Complete(b"PING".to_vec())));
+    assert_eq!(reader.read_line().unwrap(), Some(Line::Complete(b"ECHO a b".to_vec())));
+    assert_eq!(reader.read_line().unwrap(), Some(Line::Complete(b"QU".to_vec())));
+    assert_eq!(reader.read_line().unwrap(), None);
+}
+
+#[test]
+fn line_reader_too_long_test() {
+    let mut input: Vec<u8> = vec![b'x'; 2000];
+    input.extend_from_slice(b"\nPING\n");
+
+    let mut reader = LineReader::new(&input[..], 16);
+    assert_eq!(reader.read_line().unwrap(), Some(Line::TooLong));
+    assert_eq!(reader.read_line().unwrap(), Some(Line::Complete(b"PING".to_vec())));
+    assert_eq!(reader.read_line().unwrap(), None);
+}
+
+#[test]
+fn command_parse_test() {
+    assert_eq!("ECHO hello world".parse(), Ok(Command::Echo("hello world".to_owned())));
+    assert_eq!("echo".parse(), Ok(Command::Echo(String::new())));
+    assert_eq!("ping".parse(), Ok(Command::Ping));
+    assert_eq!("bye".parse(), Ok(Command::Quit));
+    assert_eq!("FETCH x".parse::<Command>(), Err(CommandError::Unknown("FETCH".to_owned())));
+}
+
+#[test]
+fn serve_test() {
+    struct Session {
+        input: &'static [u8],
+        output: Vec<u8>,
+    }
+
+    impl Read for Session {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Session {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut session = Session { input: b"PING\n\nECHO hi\nNOPE\n\xff\nQUIT\nPING\n", output: Vec::new() };
+    let mut messages: u64 = 0;
+    assert_eq!(serve(&mut session, Vec::new(), &Stats::default(), "127.0.0.1:5000", None, &mut messages).unwrap(), CloseReason::Bye);
+    assert_eq!(messages, 5);
+
+    assert_eq!(
+        String::from_utf8(session.output).unwrap(),
+        "PONG\nhi\nERR unknown command 'NOPE'\nERR invalid utf-8\nBYE\n"
+    );
+
+    let mut session = Session { input: b"TS\n", output: Vec::new() };
+    serve(&mut session, b"STA".to_vec(), &Stats::default(), "192.0.2.1:4000", Some("10.0.0.2:443".parse().unwrap()), &mut messages).unwrap();
+    assert!(String::from_utf8(session.output).unwrap().ends_with(" peer=192.0.2.1:4000 destination=10.0.0.2:443\n"));
+}
+
+#[test]
+fn detect_test() {
+    let detect = |input: &[u8]| detect(&mut &input[..]).unwrap();
+    assert_eq!(detect(b"PING\r\nhello"), Detected::Commands(b"PING\r\nhello".to_vec()));
+    assert_eq!(detect(b"echo hi\n"), Detected::Commands(b"echo hi\n".to_vec()));
+    assert_eq!(detect(b"quit"), Detected::Commands(b"quit".to_vec()));
+
+    // Decided without waiting for a newline once it can't be a command any more.
+    assert_eq!(detect(b"hello world"), Detected::Raw(b"hello world".to_vec()));
+    assert_eq!(detect(b"PINGS\n"), Detected::Raw(b"PINGS\n".to_vec()));
+    assert_eq!(detect(b"NOPE\nPING\n"), Detected::Raw(b"NOPE\nPING\n".to_vec()));
+    assert_eq!(detect(b"\xff\n"), Detected::Raw(b"\xff\n".to_vec()));
+    assert_eq!(detect(b"\n"), Detected::Raw(b"\n".to_vec()));
+    assert_eq!(detect(b"PI"), Detected::Raw(b"PI".to_vec()));
+    assert_eq!(detect(b""), Detected::Raw(Vec::new()));
+}
@@ -0,0 +1,244 @@
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::os::unix::io::AsRawFd;
+
+use crate::config::{ListenerKind, Protocol};
+use crate::proxy_protocol::ProxyMode;
+use crate::raw_listener::RawListener;
+use crate::socket_options::SocketOptions;
+use crate::stream::ClientStream;
+use crate::unix_socket::{UnixAddr, UnixSocketListener};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(UnixAddr),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{}", addr),
+            Self::Unix(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+/// One `--listen` target:
+/// `ADDR[,v6only][,mode=<octal>][,protocol=<raw|line|framed|http|websocket>][,proxy=<optional|required>]`,
+/// e.g. `127.0.0.1:8080`, `[::1]:8080`, `[::]:8080`, `127.0.0.1:8081,protocol=line`,
+/// `127.0.0.1:8082,proxy=required`, `unix:/run/echo.sock,mode=660` or `unix:@echo`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListenSpec {
+    pub addr: ListenAddr,
+    /// `IPV6_V6ONLY` for IPv6 addresses. Off by default for `[::]`, so that a single
+    /// listener serves both IPv4 and IPv6 clients; `,v6only` turns it on.
+    pub only_v6: bool,
+    /// Permissions of a Unix socket file; `None` leaves them to the umask.
+    pub mode: Option<u32>,
+    /// Protocol spoken on this listener; `None` until the config fills in the `--protocol` default.
+    pub protocol: Option<Protocol>,
+    /// Whether clients start with a PROXY protocol header, see the proxy_protocol module.
+    pub proxy: ProxyMode,
+}
+
+impl Default for ListenSpec {
+    fn default() -> Self {
+        ListenSpec {
+            addr: ListenAddr::Tcp(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080)),
+            only_v6: false,
+            mode: None,
+            protocol: None,
+            proxy: ProxyMode::Off,
+        }
+    }
+}
+
+impl ListenSpec {
+    pub fn is_dual_stack(&self) -> bool {
+        matches!(self.addr, ListenAddr::Tcp(addr) if addr.is_ipv6() && !self.only_v6)
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self.addr, ListenAddr::Unix(_))
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.unwrap_or(Protocol::Raw)
+    }
+}
+
+impl std::str::FromStr for ListenSpec {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr: &str = parts.next().ok_or(())?;
+        let addr: ListenAddr = match addr.starts_with("unix:") {
+            true => ListenAddr::Unix(addr.parse()?),
+            false => ListenAddr::Tcp(addr.parse().map_err(|_| ())?),
+        };
+        let is_ipv6: bool = matches!(addr, ListenAddr::Tcp(addr) if addr.is_ipv6());
+        let mut only_v6: bool = matches!(addr, ListenAddr::Tcp(addr) if addr.is_ipv6() && !addr.ip().is_unspecified());
+        let mut mode: Option<u32> = None;
+        let mut protocol: Option<Protocol> = None;
+        let mut proxy: ProxyMode = ProxyMode::Off;
+
+        for option in parts {
+            match option.split_once('=') {
+                None if option == "v6only" && is_ipv6 => only_v6 = true,
+                Some(("mode", value)) if matches!(addr, ListenAddr::Unix(UnixAddr::Path(_))) => {
+                    let value: u32 = u32::from_str_radix(value, 8).map_err(|_| ())?;
+                    if value > 0o7777 {
+                        return Err(());
+                    }
+                    mode = Some(value);
+                }
+                Some(("protocol", value)) => protocol = Some(value.parse()?),
+                Some(("proxy", value)) => proxy = value.parse()?,
+                _ => return Err(()),
+            }
+        }
+
+        Ok(ListenSpec { addr, only_v6, mode, protocol, proxy })
+    }
+}
+
+impl std::fmt::Display for ListenSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if self.is_dual_stack() {
+            f.write_str(" (dual-stack)")?;
+        }
+        if let Some(mode) = self.mode {
+            write!(f, " mode={:o}", mode)?;
+        }
+        if let Some(protocol) = self.protocol {
+            write!(f, " protocol={}", protocol)?;
+        }
+        if self.proxy != ProxyMode::Off {
+            write!(f, " proxy={}", self.proxy)?;
+        }
+        Ok(())
+    }
+}
+
+/// Anything the thread-per-connection loop can accept clients from.
+pub trait Acceptor: AsRawFd + Send {
+    fn accept_stream(&self) -> std::io::Result<Box<dyn ClientStream>>;
+    /// The address actually bound, e.g. with the port the kernel picked for port 0.
+    fn local_addr(&self) -> std::io::Result<ListenAddr>;
+}
+
+impl Acceptor for TcpListener {
+    fn accept_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.accept()?.0))
+    }
+
+    fn local_addr(&self) -> std::io::Result<ListenAddr> {
+        TcpListener::local_addr(self).map(ListenAddr::Tcp)
+    }
+}
+
+impl Acceptor for RawListener {
+    fn accept_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.accept()?.0))
+    }
+
+    fn local_addr(&self) -> std::io::Result<ListenAddr> {
+        RawListener::local_addr(self).map(ListenAddr::Tcp)
+    }
+}
+
+impl Acceptor for UnixSocketListener {
+    fn accept_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.accept()?))
+    }
+
+    fn local_addr(&self) -> std::io::Result<ListenAddr> {
+        Ok(ListenAddr::Unix(UnixSocketListener::local_addr(self).clone()))
+    }
+}
+
+/// Binds `spec` with the requested listener implementation; Unix sockets ignore `kind`
+/// and `options`.
+pub fn bind(spec: &ListenSpec, kind: ListenerKind, options: &SocketOptions) -> std::io::Result<Box<dyn Acceptor>> {
+    match (&spec.addr, kind) {
+        (ListenAddr::Unix(addr), _) => Ok(Box::new(UnixSocketListener::bind(addr, spec.mode)?)),
+        (ListenAddr::Tcp(_), ListenerKind::Std) => Ok(Box::new(bind_std(spec, kind, options)?)),
+        (ListenAddr::Tcp(addr), ListenerKind::Raw) => Ok(Box::new(bind_raw(addr, spec.only_v6, options)?)),
+    }
+}
+
+/// Same as `bind`, for callers that want a `std::net::TcpListener` whatever was used to bind it.
+///
+/// `std::net::TcpListener` has no way to set `IPV6_V6ONLY` or socket options before
+/// binding, so IPv6 addresses and tuned sockets always go through `RawListener`.
+pub fn bind_std(spec: &ListenSpec, kind: ListenerKind, options: &SocketOptions) -> std::io::Result<TcpListener> {
+    let addr: &SocketAddr = match &spec.addr {
+        ListenAddr::Tcp(addr) => addr,
+        ListenAddr::Unix(_) => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "not a TCP address")),
+    };
+    if kind == ListenerKind::Std && addr.is_ipv4() && options.is_default() {
+        return TcpListener::bind(addr);
+    }
+    bind_raw(addr, spec.only_v6, options).map(TcpListener::from)
+}
+
+fn bind_raw(addr: &SocketAddr, only_v6: bool, options: &SocketOptions) -> std::io::Result<RawListener> {
+    RawListener::bind_with_options(&addr.ip().to_string(), &addr.port().to_string(), Some(only_v6), options)
+}
+
+#[test]
+fn listen_spec_parse_test() {
+    let spec: ListenSpec = "[::]:8080".parse().unwrap();
+    assert!(spec.is_dual_stack());
+
+    let spec: ListenSpec = "[::]:8080,v6only".parse().unwrap();
+    assert!(!spec.is_dual_stack());
+
+    let spec: ListenSpec = "[::1]:8080".parse().unwrap();
+    assert!(spec.only_v6);
+
+    let spec: ListenSpec = "0.0.0.0:9000".parse().unwrap();
+    assert_eq!(spec.addr, ListenAddr::Tcp("0.0.0.0:9000".parse().unwrap()));
+    assert!(!spec.is_dual_stack());
+
+    let spec: ListenSpec = "0.0.0.0:9000,protocol=line".parse().unwrap();
+    assert_eq!(spec.protocol, Some(Protocol::Line));
+
+    let spec: ListenSpec = "127.0.0.1:9000,protocol=line,proxy=required".parse().unwrap();
+    assert_eq!(spec.proxy, ProxyMode::Required);
+    assert_eq!(spec.to_string(), "127.0.0.1:9000 protocol=line proxy=required");
+
+    assert!("0.0.0.0:9000,v6only".parse::<ListenSpec>().is_err());
+    assert!("0.0.0.0:9000,proxy=yes".parse::<ListenSpec>().is_err());
+    assert!("0.0.0.0:9000,protocol=smtp".parse::<ListenSpec>().is_err());
+    assert!("localhost:9000".parse::<ListenSpec>().is_err());
+
+    let spec: ListenSpec = "unix:/run/echo.sock,mode=660,protocol=line".parse().unwrap();
+    assert!(spec.is_unix());
+    assert_eq!(spec.mode, Some(0o660));
+    assert_eq!(spec.to_string(), "unix:/run/echo.sock mode=660 protocol=line");
+
+    assert!("unix:/run/echo.sock,mode=999".parse::<ListenSpec>().is_err());
+    assert!("unix:@echo,mode=600".parse::<ListenSpec>().is_err());
+    assert!("unix:/run/echo.sock,v6only".parse::<ListenSpec>().is_err());
+}
+
+#[test]
+fn dual_stack_bind_test() {
+    use std::net::TcpStream;
+
+    let spec: ListenSpec = "[::]:0".parse().unwrap();
+    let listener: TcpListener = match bind_std(&spec, ListenerKind::Std, &SocketOptions::default()) {
+        Ok(listener) => listener,
+        // No IPv6 support on this host.
+        Err(ref e) if e.kind() == std::io::ErrorKind::AddrNotAvailable || e.raw_os_error() == Some(libc::EAFNOSUPPORT) => return,
+        Err(e) => panic!("{}", e),
+    };
+    let port: u16 = listener.local_addr().unwrap().port();
+
+    let _client: TcpStream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+    let (_, peer): (TcpStream, SocketAddr) = listener.accept().unwrap();
+    assert_eq!(peer.ip(), IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped()));
+}
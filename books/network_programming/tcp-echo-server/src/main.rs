@@ -1,14 +1,52 @@
 use std::net::{SocketAddr, ToSocketAddrs, IpAddr, Ipv4Addr, Ipv6Addr};
-use std::io::{Read, Write};
+
+use tcp_echo_server::activation::{self, InheritedSocket};
+use tcp_echo_server::config::{self, Config};
+use tcp_echo_server::replay::{self, ReplayOptions};
+use tcp_echo_server::shutdown::{self, DrainSummary};
+use tcp_echo_server::{EchoServer, ServerHandle};
 
 fn main() {
-    unsafe {
-        let _port: &[i8] = &[0x38i8, 0x30i8, 0x38i8, 0x30i8, 0x00i8]; // ['8', '0', '8', '8', '\0'];
-        let _fd: i32 = libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0);
+    let mut args = std::env::args().skip(1).peekable();
+    if args.next_if(|arg| arg == "replay").is_some() {
+        std::process::exit(replay_main(args));
+    }
+
+    let inherited: Vec<InheritedSocket> = activation::from_env().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let config: Config = Config::from_args(args).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(if err == config::ConfigError::Help { 0 } else { 1 });
+    });
+
+    shutdown::install_signal_handlers().expect("couldn't install signal handlers");
+    let server: ServerHandle = EchoServer::builder().config(config).inherit(inherited).spawn().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let summary: DrainSummary = server.wait();
+    println!("shutdown: {}", summary);
+}
 
-        // libc::getaddrinfo(&0x00i8 as *const i8, port[0] as *const i8, &hints, &mut address_to_connect);
+/// `tcp-echo-server replay ...`, see the replay module; returns the exit status.
+fn replay_main<I: Iterator<Item = String>>(args: I) -> i32 {
+    let options: ReplayOptions = match ReplayOptions::from_args(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return if err == replay::USAGE { 0 } else { 2 };
+        }
+    };
+    match replay::run(&options) {
+        Ok(true) => 0,
+        Ok(false) => 1,
+        Err(err) => {
+            eprintln!("replay: {}", err);
+            2
+        }
     }
-    echo_server();
 }
 
 #[allow(dead_code)]
@@ -26,33 +64,3 @@ fn creating_sockets() {
     // 2001:0db8:0000:0000:0000:8a2e:0370:7334
     let _ = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0x2001, 0x0db8, 0x0000, 0x0000, 0x0000, 0x8a2e, 0x0370, 0x7334)), 8080u16);
 }
-
-fn echo_server() {
-    let listener: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:8080").expect("couldn't bind to port 8080");
-
-    for stream in listener.incoming() {
-        match stream {
-            Err(e) => eprintln!("{}", e),
-            Ok(stream) => {
-                std::thread::spawn(move || {
-                    handle_client(stream).unwrap_or_else(|err| eprintln!("{:?}", err));
-                });
-            }
-        }
-    }
-}
-
-fn handle_client(mut stream: std::net::TcpStream) -> Result<(), std::io::Error> {
-    println!("Handling client with IP: {:?}", stream.peer_addr()?);
-
-    loop {
-        let mut buf = [0; 512];
-        let bytes_read: usize = stream.read(&mut buf)?;
-        if bytes_read == 0 { return Ok(()); }
-        if String::from_utf8_lossy(&buf[..bytes_read]).starts_with("bye") {
-            stream.write("bye".as_bytes())?;
-            return Ok(());
-        }
-        stream.write(&buf[..bytes_read])?;
-    }
-}
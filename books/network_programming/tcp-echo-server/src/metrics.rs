@@ -0,0 +1,162 @@
+//! Prometheus text format metrics, served over a minimal HTTP endpoint:
+//! `GET /metrics` gets the metrics, every request gets its own connection.
+
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use crate::shutdown::{wait_readable, Shutdown, POLL_INTERVAL};
+use crate::stats::{Stats, LATENCY_BUCKETS};
+use crate::udp::UdpStats;
+
+/// Requests with a bigger head than this are refused.
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+/// Scrapers that don't send their request within this time are dropped.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Renders every counter in the Prometheus text exposition format.
+pub fn render(stats: &Stats, udp_stats: &UdpStats) -> String {
+    let mut out: String = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}\n{} {}", name, help, name, kind, name, value);
+    };
+
+    metric("echo_connections_active", "gauge", "Connections being served right now.", stats.active.load(Ordering::Relaxed));
+    metric("echo_connections_accepted_total", "counter", "Connections accepted.", stats.accepted.load(Ordering::Relaxed));
+    metric("echo_connections_rejected_total", "counter", "Connections turned away, for any reason.", stats.rejected.load(Ordering::Relaxed));
+    metric("echo_received_bytes_total", "counter", "Bytes read from TCP and Unix clients.", stats.bytes_in.load(Ordering::Relaxed));
+    metric("echo_sent_bytes_total", "counter", "Bytes written to TCP and Unix clients.", stats.bytes_out.load(Ordering::Relaxed));
+    metric("echo_udp_received_datagrams_total", "counter", "UDP datagrams received.", udp_stats.datagrams_in.load(Ordering::Relaxed));
+    metric("echo_udp_sent_datagrams_total", "counter", "UDP datagrams echoed.", udp_stats.datagrams_out.load(Ordering::Relaxed));
+    metric("echo_udp_dropped_datagrams_total", "counter", "UDP datagrams dropped on purpose.", udp_stats.dropped.load(Ordering::Relaxed));
+
+    out.push_str("# HELP echo_rejections_total Connections turned away, by reason.\n");
+    out.push_str("# TYPE echo_rejections_total counter\n");
+    for (reason, count) in stats.rejections.lock().unwrap().iter() {
+        let _ = writeln!(out, "echo_rejections_total{{reason=\"{}\"}} {}", reason, count);
+    }
+
+    out.push_str("# HELP echo_errors_total Failed accepts and connections that ended in an error, by io::ErrorKind.\n");
+    out.push_str("# TYPE echo_errors_total counter\n");
+    for (kind, count) in stats.errors.lock().unwrap().iter() {
+        let _ = writeln!(out, "echo_errors_total{{kind=\"{}\"}} {}", kind, count);
+    }
+
+    let (buckets, sum): (Vec<u64>, Duration) = stats.latency.snapshot();
+    out.push_str("# HELP echo_round_trip_seconds Time from a request's first byte to the first byte of its reply.\n");
+    out.push_str("# TYPE echo_round_trip_seconds histogram\n");
+    for (bound, count) in LATENCY_BUCKETS.iter().zip(&buckets) {
+        let _ = writeln!(out, "echo_round_trip_seconds_bucket{{le=\"{}\"}} {}", bound, count);
+    }
+    let count: u64 = buckets.last().copied().unwrap_or(0);
+    let _ = writeln!(out, "echo_round_trip_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "echo_round_trip_seconds_sum {}", sum.as_secs_f64());
+    let _ = writeln!(out, "echo_round_trip_seconds_count {}", count);
+    out
+}
+
+/// Answers scrapes on `listener` until `shutdown` is requested. Scrapes are served one
+/// at a time, which is plenty for a metrics endpoint.
+pub fn serve(listener: TcpListener, stats: &Stats, udp_stats: &UdpStats, shutdown: &Shutdown) -> std::io::Result<()> {
+    let fd: RawFd = listener.as_raw_fd();
+    while !shutdown.is_requested() {
+        if wait_readable(&[fd], POLL_INTERVAL)?.is_empty() {
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(err) = answer(stream, stats, udp_stats) {
+                    eprintln!("metrics: {}", err);
+                }
+            }
+            Err(e) => eprintln!("metrics: {}", e),
+        }
+    }
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream, stats: &Stats, udp_stats: &UdpStats) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut head: Vec<u8> = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        if head.len() > MAX_REQUEST_HEAD {
+            return respond(&mut stream, "431 Request Header Fields Too Large", "request head too large\n");
+        }
+        let bytes_read: usize = stream.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..bytes_read]);
+    }
+
+    let head: String = String::from_utf8_lossy(&head).into_owned();
+    let mut request_line = head.lines().next().unwrap_or("").split(' ');
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => respond(&mut stream, "200 OK", &render(stats, udp_stats)),
+        (Some("GET"), _) => respond(&mut stream, "404 Not Found", "not found, try /metrics\n"),
+        _ => respond(&mut stream, "405 Method Not Allowed", "only GET is supported\n"),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: &str) -> std::io::Result<()> {
+    let response: String = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
+
+#[test]
+fn render_test() {
+    let stats: Stats = Stats::default();
+    stats.add_in(5);
+    stats.add_error(std::io::ErrorKind::ConnectionReset);
+    stats.reject(crate::limits::RejectReason::Denied);
+    stats.latency.observe(Duration::from_millis(2));
+
+    let text: String = render(&stats, &UdpStats::default());
+    assert!(text.contains("# TYPE echo_connections_active gauge\necho_connections_active 0\n"));
+    assert!(text.contains("echo_received_bytes_total 5\n"));
+    assert!(text.contains("echo_errors_total{kind=\"ConnectionReset\"} 1\n"));
+    assert!(text.contains("echo_connections_rejected_total 1\n"));
+    assert!(text.contains("echo_rejections_total{reason=\"denied\"} 1\n"));
+    assert!(text.contains("echo_round_trip_seconds_bucket{le=\"0.001\"} 0\n"));
+    assert!(text.contains("echo_round_trip_seconds_bucket{le=\"0.0025\"} 1\n"));
+    assert!(text.contains("echo_round_trip_seconds_count 1\n"));
+}
+
+#[test]
+fn metrics_endpoint_test() {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: std::net::SocketAddr = listener.local_addr().unwrap();
+    let shutdown: Shutdown = Shutdown::default();
+    let server = {
+        let shutdown: Shutdown = shutdown.clone();
+        std::thread::spawn(move || serve(listener, &Stats::default(), &UdpStats::default(), &shutdown))
+    };
+
+    let get = |request: &str| -> String {
+        let mut client: TcpStream = TcpStream::connect(addr).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response: String = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    let response: String = get("GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("echo_connections_accepted_total 0\n"));
+    assert!(get("GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(get("POST /metrics HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+
+    shutdown.request();
+    server.join().unwrap().unwrap();
+}
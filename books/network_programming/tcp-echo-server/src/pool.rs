@@ -0,0 +1,80 @@
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed number of threads taking jobs from a bounded queue.
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+    workers: Vec<std::thread::JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Starts `size` threads; at most `queue` jobs can wait for a free thread.
+    pub fn new(size: usize, queue: usize) -> WorkerPool {
+        let (sender, receiver) = std::sync::mpsc::sync_channel::<Job>(queue);
+        let receiver: Arc<Mutex<Receiver<Job>>> = Arc::new(Mutex::new(receiver));
+
+        let workers: Vec<std::thread::JoinHandle<()>> = (0..size)
+            .map(|_| {
+                let receiver: Arc<Mutex<Receiver<Job>>> = receiver.clone();
+                std::thread::spawn(move || loop {
+                    // The lock is only held while waiting for the next job, not while running it.
+                    let job: Result<Job, _> = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+
+        WorkerPool { sender, workers }
+    }
+
+    /// Queues `job`, handing it back if the queue is full.
+    pub fn try_execute(&self, job: Job) -> Result<(), Job> {
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => Err(job),
+        }
+    }
+
+    /// Lets the workers finish the queued jobs and waits for them to exit.
+    pub fn join(self) {
+        drop(self.sender);
+        for worker in self.workers {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[test]
+fn worker_pool_queue_full_test() {
+    let (started, wait_started) = std::sync::mpsc::channel::<()>();
+    let (release, blocked) = std::sync::mpsc::channel::<()>();
+    let blocked: Arc<Mutex<Receiver<()>>> = Arc::new(Mutex::new(blocked));
+
+    let pool: WorkerPool = WorkerPool::new(1, 1);
+    let counter: Arc<std::sync::atomic::AtomicUsize> = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+    let job = |counter: Arc<std::sync::atomic::AtomicUsize>| -> Job {
+        let (started, blocked) = (started.clone(), blocked.clone());
+        Box::new(move || {
+            let _ = started.send(());
+            let _ = blocked.lock().unwrap().recv();
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        })
+    };
+
+    // The only worker is busy with the first job and the queue holds the second.
+    assert!(pool.try_execute(job(counter.clone())).is_ok());
+    wait_started.recv().unwrap();
+    assert!(pool.try_execute(job(counter.clone())).is_ok());
+    assert!(pool.try_execute(job(counter.clone())).is_err());
+
+    release.send(()).unwrap();
+    release.send(()).unwrap();
+    pool.join();
+    assert_eq!(counter.load(std::sync::atomic::Ordering::SeqCst), 2);
+}
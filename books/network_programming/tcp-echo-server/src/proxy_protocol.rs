@@ -0,0 +1,492 @@
+//! PROXY protocol headers, as sent by HAProxy and friends at the start of every connection
+//! they pass on, so the server sees the real client instead of the proxy.
+//!
+//! Both versions are understood, whichever the proxy sends:
+//!
+//! ```text
+//! v1: PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\n
+//! v2: \r\n\r\n\0\r\nQUIT\n, version and command, family, length, addresses, TLVs
+//! ```
+//!
+//! A listener opts in with `,proxy=optional` (clients may send a header) or
+//! `,proxy=required` (clients without one are turned away). Malformed headers are always
+//! rejected. `UNKNOWN` (v1) and `LOCAL` (v2) headers, e.g. the proxy's own health checks,
+//! keep the connection's own addresses.
+
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr};
+use std::time::Duration;
+
+use crate::stream::ClientStream;
+
+/// Longest v1 header, `\r\n` included, per the spec.
+pub const MAX_V1_LEN: usize = 107;
+/// Every v2 header starts with this.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Signature, version and command, family, and the length of the rest.
+const V2_FIXED_LEN: usize = 16;
+/// How long a client gets to send its header, so a silent one can't hold on to a thread.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Whether a listener expects PROXY headers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProxyMode {
+    #[default]
+    Off,
+    /// Connections may start with a header; those that don't are served as they are.
+    Optional,
+    /// Connections without a header are rejected.
+    Required,
+}
+
+impl ProxyMode {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Off => "off",
+            Self::Optional => "optional",
+            Self::Required => "required",
+        }
+    }
+}
+
+impl std::str::FromStr for ProxyMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ProxyMode::Off),
+            "optional" => Ok(ProxyMode::Optional),
+            "required" => Ok(ProxyMode::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// The client as the proxy saw it: who connected, and to which address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+impl std::fmt::Display for ProxyHeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} -> {}", self.source, self.destination)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProxyError {
+    /// The listener requires a header and the connection didn't start with one.
+    Missing,
+    Malformed(&'static str),
+}
+
+impl ProxyError {
+    pub fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Missing => f.write_str("no PROXY header"),
+            Self::Malformed(reason) => write!(f, "malformed PROXY header: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+/// What `parse` made of the first bytes of a connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Parsed {
+    /// Could still be a header; more bytes are needed to tell.
+    Incomplete,
+    /// Not a header, the bytes belong to the client's protocol.
+    NotProxy,
+    /// A header of `len` bytes; `None` for `UNKNOWN` and `LOCAL`.
+    Header { header: Option<ProxyHeader>, len: usize },
+}
+
+/// Looks for a v1 or v2 header at the start of `buf`.
+pub fn parse(buf: &[u8]) -> Result<Parsed, ProxyError> {
+    if starts_like(buf, V1_PREFIX) {
+        return parse_v1(buf);
+    }
+    if starts_like(buf, &V2_SIGNATURE) {
+        return parse_v2(buf);
+    }
+    Ok(Parsed::NotProxy)
+}
+
+/// Whether `buf` and `prefix` agree as far as both go.
+fn starts_like(buf: &[u8], prefix: &[u8]) -> bool {
+    let len: usize = buf.len().min(prefix.len());
+    buf[..len] == prefix[..len]
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Parsed, ProxyError> {
+    let end: usize = match buf.iter().take(MAX_V1_LEN).position(|&byte| byte == b'\n') {
+        Some(end) => end,
+        None if buf.len() >= MAX_V1_LEN => return Err(ProxyError::Malformed("v1 header too long")),
+        None => return Ok(Parsed::Incomplete),
+    };
+    let line: &str = std::str::from_utf8(&buf[..end])
+        .ok()
+        .and_then(|line| line.strip_suffix('\r'))
+        .ok_or(ProxyError::Malformed("v1 header doesn't end with \\r\\n"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    let header: Option<ProxyHeader> = match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => None,
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] => {
+            let ip = |text: &str| -> Result<IpAddr, ProxyError> {
+                let ip: IpAddr = match *family {
+                    "TCP4" => text.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => text.parse::<Ipv6Addr>().map(IpAddr::V6),
+                }
+                .map_err(|_| ProxyError::Malformed("bad address"))?;
+                Ok(ip)
+            };
+            let port = |text: &str| -> Result<u16, ProxyError> {
+                if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Err(ProxyError::Malformed("bad port"));
+                }
+                text.parse().map_err(|_| ProxyError::Malformed("bad port"))
+            };
+            Some(ProxyHeader {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            })
+        }
+        ["PROXY", "TCP4" | "TCP6", ..] => return Err(ProxyError::Malformed("wrong number of fields")),
+        _ => return Err(ProxyError::Malformed("unknown protocol family")),
+    };
+    Ok(Parsed::Header { header, len: end + 1 })
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Parsed, ProxyError> {
+    if buf.len() < V2_FIXED_LEN {
+        return Ok(Parsed::Incomplete);
+    }
+    let (version, command): (u8, u8) = (buf[12] >> 4, buf[12] & 0x0f);
+    if version != 2 {
+        return Err(ProxyError::Malformed("unsupported version"));
+    }
+    let len: usize = V2_FIXED_LEN + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if buf.len() < len {
+        return Ok(Parsed::Incomplete);
+    }
+    let addresses: &[u8] = &buf[V2_FIXED_LEN..len];
+
+    let header: Option<ProxyHeader> = match command {
+        // LOCAL: the proxy talking for itself, the addresses (if any) are to be ignored.
+        0x0 => None,
+        0x1 => match buf[13] >> 4 {
+            // AF_INET
+            0x1 => {
+                let block: &[u8] = addresses.get(..12).ok_or(ProxyError::Malformed("address block too short"))?;
+                let ip = |at: usize| IpAddr::V4(Ipv4Addr::new(block[at], block[at + 1], block[at + 2], block[at + 3]));
+                Some(ProxyHeader {
+                    source: SocketAddr::new(ip(0), u16::from_be_bytes([block[8], block[9]])),
+                    destination: SocketAddr::new(ip(4), u16::from_be_bytes([block[10], block[11]])),
+                })
+            }
+            // AF_INET6
+            0x2 => {
+                let block: &[u8] = addresses.get(..36).ok_or(ProxyError::Malformed("address block too short"))?;
+                let ip = |at: usize| {
+                    let mut octets: [u8; 16] = [0; 16];
+                    octets.copy_from_slice(&block[at..at + 16]);
+                    IpAddr::V6(Ipv6Addr::from(octets))
+                };
+                Some(ProxyHeader {
+                    source: SocketAddr::new(ip(0), u16::from_be_bytes([block[32], block[33]])),
+                    destination: SocketAddr::new(ip(16), u16::from_be_bytes([block[34], block[35]])),
+                })
+            }
+            // AF_UNSPEC and AF_UNIX: nothing an IP based server could use.
+            0x0 | 0x3 => None,
+            _ => return Err(ProxyError::Malformed("unknown address family")),
+        },
+        _ => return Err(ProxyError::Malformed("unknown command")),
+    };
+    // Whatever follows the addresses are TLVs, which the echo server has no use for.
+    Ok(Parsed::Header { header, len })
+}
+
+/// Reads the header a connection starts with, if any. Returns the header and the bytes
+/// read past it, which belong to the client's protocol.
+pub fn read_header(stream: &mut dyn ClientStream, mode: ProxyMode) -> std::io::Result<(Option<ProxyHeader>, Vec<u8>)> {
+    stream.set_read_timeout(Some(HEADER_TIMEOUT))?;
+    let mut buf: Vec<u8> = Vec::new();
+    let mut chunk = [0; 512];
+
+    let parsed: Parsed = loop {
+        match parse(&buf).map_err(ProxyError::into_io)? {
+            Parsed::Incomplete => {}
+            parsed => break parsed,
+        }
+        let bytes_read: usize = match stream.read(&mut chunk) {
+            Ok(bytes_read) => bytes_read,
+            // Nothing yet, e.g. an interactive client waiting for the server to speak first.
+            Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) && !in_header(&buf) => {
+                break Parsed::NotProxy;
+            }
+            Err(e) => return Err(e),
+        };
+        if bytes_read == 0 {
+            // A connection closed in the middle of a signature never sent a header at all.
+            match in_header(&buf) {
+                true => return Err(ProxyError::Malformed("connection closed in the header").into_io()),
+                false => break Parsed::NotProxy,
+            }
+        }
+        buf.extend_from_slice(&chunk[..bytes_read]);
+    };
+    stream.set_read_timeout(None)?;
+
+    match parsed {
+        Parsed::Header { header, len } => Ok((header, buf.split_off(len))),
+        _ if mode == ProxyMode::Required => Err(ProxyError::Missing.into_io()),
+        _ => Ok((None, buf)),
+    }
+}
+
+/// Whether `buf` starts with a whole signature, as opposed to just looking like the start of one.
+fn in_header(buf: &[u8]) -> bool {
+    buf.starts_with(V1_PREFIX) || buf.starts_with(&V2_SIGNATURE)
+}
+
+/// A client stream that went through `read_header`: it hands out the bytes read past the
+/// header first and names the client from the header rather than from the socket.
+pub struct Proxied {
+    inner: Box<dyn ClientStream>,
+    header: Option<ProxyHeader>,
+    /// Read past the header and not handed out yet.
+    prefix: Vec<u8>,
+}
+
+impl Proxied {
+    pub fn new(inner: Box<dyn ClientStream>, header: Option<ProxyHeader>, prefix: Vec<u8>) -> Proxied {
+        Proxied { inner, header, prefix }
+    }
+}
+
+impl Read for Proxied {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.prefix.is_empty() {
+            return self.inner.read(buf);
+        }
+        let len: usize = buf.len().min(self.prefix.len());
+        buf[..len].copy_from_slice(&self.prefix[..len]);
+        self.prefix.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for Proxied {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl ClientStream for Proxied {
+    fn peer_name(&self) -> std::io::Result<String> {
+        match self.header {
+            Some(header) => Ok(header.source.to_string()),
+            None => self.inner.peer_name(),
+        }
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        match self.header {
+            Some(header) => Some(header.source.ip()),
+            None => self.inner.peer_ip(),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Clones are only ever written to, so the bytes read past the header stay with the original.
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(Proxied::new(self.inner.try_clone_stream()?, self.header, Vec::new())))
+    }
+
+    /// Only once the bytes read past the header were handed out.
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match self.prefix.is_empty() {
+            true => self.inner.raw_fd(),
+            false => None,
+        }
+    }
+}
+
+#[test]
+fn parse_v1_test() {
+    let header: &[u8] = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nPING\n";
+    assert_eq!(
+        parse(header),
+        Ok(Parsed::Header {
+            header: Some(ProxyHeader { source: "192.168.0.1:56324".parse().unwrap(), destination: "192.168.0.11:443".parse().unwrap() }),
+            len: header.len() - 5,
+        })
+    );
+    let parsed: Parsed = parse(b"PROXY TCP6 2001:db8::1 ::1 4000 8080\r\n").unwrap();
+    assert!(matches!(parsed, Parsed::Header { header: Some(header), .. } if header.source == "[2001:db8::1]:4000".parse().unwrap()));
+    assert_eq!(parse(b"PROXY UNKNOWN\r\n"), Ok(Parsed::Header { header: None, len: 15 }));
+
+    assert_eq!(parse(b"PRO"), Ok(Parsed::Incomplete));
+    assert_eq!(parse(b"PROXY TCP4 192.168.0.1"), Ok(Parsed::Incomplete));
+    assert_eq!(parse(b"PROXIMITY\n"), Ok(Parsed::NotProxy));
+    assert_eq!(parse(b"GET / HTTP/1.1\r\n"), Ok(Parsed::NotProxy));
+
+    assert!(parse(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 +1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 1 70000\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 1\r\n").is_err());
+    assert!(parse(b"PROXY UDP4 10.0.0.1 10.0.0.2 1 2\r\n").is_err());
+    assert!(parse(b"PROXY TCP4 10.0.0.1 10.0.0.2 1 2\n").is_err());
+    assert!(parse(&[b"PROXY ".as_slice(), &[b'x'; MAX_V1_LEN]].concat()).is_err());
+}
+
+#[test]
+fn parse_v2_test() {
+    let mut header: Vec<u8> = V2_SIGNATURE.to_vec();
+    header.extend_from_slice(&[0x21, 0x11, 0, 12 + 7]);
+    header.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 80]);
+    // A TLV the server skips.
+    header.extend_from_slice(&[0x04, 0, 4, 1, 2, 3, 4]);
+    let len: usize = header.len();
+    header.extend_from_slice(b"hello");
+    assert_eq!(
+        parse(&header),
+        Ok(Parsed::Header {
+            header: Some(ProxyHeader { source: "10.0.0.1:8080".parse().unwrap(), destination: "10.0.0.2:80".parse().unwrap() }),
+            len,
+        })
+    );
+    assert_eq!(parse(&header[..len - 1]), Ok(Parsed::Incomplete));
+    assert_eq!(parse(&header[..5]), Ok(Parsed::Incomplete));
+
+    let mut ipv6: Vec<u8> = V2_SIGNATURE.to_vec();
+    ipv6.extend_from_slice(&[0x21, 0x21, 0, 36]);
+    ipv6.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+    ipv6.extend_from_slice(&"2001:db8::7".parse::<Ipv6Addr>().unwrap().octets());
+    ipv6.extend_from_slice(&[0, 1, 0, 2]);
+    let parsed: Parsed = parse(&ipv6).unwrap();
+    assert!(matches!(parsed, Parsed::Header { header: Some(header), len: 52 } if header.destination == "[2001:db8::7]:2".parse().unwrap()));
+
+    let local: Vec<u8> = [V2_SIGNATURE.as_slice(), &[0x20, 0x00, 0, 0]].concat();
+    assert_eq!(parse(&local), Ok(Parsed::Header { header: None, len: 16 }));
+
+    assert!(parse(&[V2_SIGNATURE.as_slice(), &[0x11, 0x11, 0, 0]].concat()).is_err());
+    assert!(parse(&[V2_SIGNATURE.as_slice(), &[0x22, 0x11, 0, 0]].concat()).is_err());
+    assert!(parse(&[V2_SIGNATURE.as_slice(), &[0x21, 0x11, 0, 4, 1, 2, 3, 4]].concat()).is_err());
+}
+
+#[test]
+fn read_header_test() {
+    use std::os::unix::net::UnixStream;
+
+    let read = |data: &[u8], mode: ProxyMode| {
+        let (mut client, server): (UnixStream, UnixStream) = UnixStream::pair().unwrap();
+        client.write_all(data).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut server: Box<dyn ClientStream> = Box::new(server);
+        read_header(&mut *server, mode).map(|(header, prefix)| {
+            let mut stream: Proxied = Proxied::new(server, header, prefix);
+            let mut rest: Vec<u8> = Vec::new();
+            stream.read_to_end(&mut rest).unwrap();
+            (stream.peer_ip(), rest)
+        })
+    };
+
+    let (ip, rest) = read(b"PROXY TCP4 192.0.2.1 192.0.2.2 1000 80\r\nhello", ProxyMode::Required).unwrap();
+    assert_eq!((ip, rest.as_slice()), (Some("192.0.2.1".parse().unwrap()), b"hello".as_slice()));
+    assert_eq!(read(b"PROXY UNKNOWN\r\nhi", ProxyMode::Required).unwrap(), (None, b"hi".to_vec()));
+
+    // Bytes read while looking for a header are handed out again.
+    assert_eq!(read(b"PROX", ProxyMode::Optional).unwrap(), (None, b"PROX".to_vec()));
+    assert_eq!(read(b"hello", ProxyMode::Optional).unwrap(), (None, b"hello".to_vec()));
+
+    let err: std::io::Error = read(b"hello", ProxyMode::Required).unwrap_err();
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref::<ProxyError>()), Some(&ProxyError::Missing));
+    assert!(read(b"PROXY TCP4 192.0.2.1", ProxyMode::Optional).is_err());
+    assert!(read(b"PROXY SCTP\r\n", ProxyMode::Optional).is_err());
+}
+
+#[test]
+fn silent_client_test() {
+    use std::os::unix::net::UnixStream;
+
+    // Clients that send nothing, or not enough to tell, before the header timeout.
+    let read = |data: &'static [u8], mode: ProxyMode| {
+        std::thread::spawn(move || {
+            let (mut client, server): (UnixStream, UnixStream) = UnixStream::pair().unwrap();
+            client.write_all(data).unwrap();
+            let mut server: Box<dyn ClientStream> = Box::new(server);
+            let result = read_header(&mut *server, mode);
+            drop(client);
+            result
+        })
+    };
+    let (silent, partial, required) = (read(b"", ProxyMode::Optional), read(b"PRO", ProxyMode::Optional), read(b"", ProxyMode::Required));
+    assert_eq!(silent.join().unwrap().unwrap(), (None, Vec::new()));
+    assert_eq!(partial.join().unwrap().unwrap(), (None, b"PRO".to_vec()));
+    let err: std::io::Error = required.join().unwrap().unwrap_err();
+    assert_eq!(err.get_ref().and_then(|err| err.downcast_ref::<ProxyError>()), Some(&ProxyError::Missing));
+}
+
+#[test]
+fn proxy_listener_test() {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpStream;
+    use std::sync::atomic::Ordering;
+
+    let server: crate::ServerHandle = crate::EchoServer::builder().bind("127.0.0.1:0,protocol=line,proxy=required").spawn().unwrap();
+    let mut client: TcpStream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    client.write_all(b"PROXY TCP4 192.0.2.1 198.51.100.7 4000 443\r\nSTATS\n").unwrap();
+    let mut reply: String = String::new();
+    BufReader::new(&client).read_line(&mut reply).unwrap();
+    assert!(reply.ends_with(" peer=192.0.2.1:4000 destination=198.51.100.7:443\n"), "{}", reply);
+    drop(client);
+
+    for request in [b"STATS\n".as_slice(), b"PROXY TCP4 nowhere\r\n"] {
+        let mut client: TcpStream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(request).unwrap();
+        let mut reply: Vec<u8> = Vec::new();
+        let _ = client.read_to_end(&mut reply);
+        assert!(reply.is_empty());
+    }
+    assert_eq!(server.stats().rejections.lock().unwrap().get("proxy_header"), Some(&2));
+    assert_eq!(server.stats().accepted.load(Ordering::Relaxed), 1);
+    server.shutdown();
+}
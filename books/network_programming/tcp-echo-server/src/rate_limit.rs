@@ -0,0 +1,241 @@
+//! Per-IP token buckets for new connections and for bytes received.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::stream::ClientStream;
+
+/// Peers tracked before buckets that refilled completely are forgotten; a full bucket is
+/// no different from a new one.
+const MAX_TRACKED_PEERS: usize = 4096;
+
+/// `per_sec` tokens a second, up to `burst` saved up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rate {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+impl Rate {
+    /// A rate of 0 means no limit. Without a burst, a second's worth of tokens can be saved up.
+    pub fn new(per_sec: f64, burst: f64) -> Option<Rate> {
+        if per_sec <= 0.0 {
+            return None;
+        }
+        let burst: f64 = if burst > 0.0 { burst } else { per_sec.max(1.0) };
+        Some(Rate { per_sec, burst })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: Rate,
+    /// Negative while paying off a debt, see `take_owed`.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: Rate, now: Instant) -> TokenBucket {
+        TokenBucket { rate, tokens: rate.burst, updated: now }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed: f64 = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate.per_sec).min(self.rate.burst);
+        self.updated = now;
+    }
+
+    /// Takes a token if there is one.
+    pub fn try_take(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+
+    /// Takes `amount` tokens whether or not there are that many, for things that already
+    /// happened, and returns how long until the bucket is out of debt again.
+    pub fn take_owed(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= amount;
+        match self.tokens {
+            tokens if tokens >= 0.0 => Duration::ZERO,
+            tokens => Duration::from_secs_f64(-tokens / self.rate.per_sec),
+        }
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed: f64 = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens + elapsed * self.rate.per_sec >= self.rate.burst
+    }
+}
+
+#[derive(Debug)]
+struct PeerBuckets {
+    connections: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+/// Token buckets per peer IP, shared by every connection.
+#[derive(Debug)]
+pub struct RateLimiter {
+    connections: Option<Rate>,
+    bytes: Option<Rate>,
+    peers: Mutex<HashMap<IpAddr, PeerBuckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(connections: Option<Rate>, bytes: Option<Rate>) -> RateLimiter {
+        RateLimiter { connections, bytes, peers: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn limits_bytes(&self) -> bool {
+        self.bytes.is_some()
+    }
+
+    fn with_peer<T>(&self, ip: IpAddr, now: Instant, f: impl FnOnce(&mut PeerBuckets) -> T) -> T {
+        let ip: IpAddr = ip.to_canonical();
+        let mut peers = self.peers.lock().unwrap();
+        if peers.len() >= MAX_TRACKED_PEERS && !peers.contains_key(&ip) {
+            peers.retain(|_, buckets| {
+                !(buckets.connections.as_ref().is_none_or(|bucket| bucket.is_full(now)) && buckets.bytes.as_ref().is_none_or(|bucket| bucket.is_full(now)))
+            });
+        }
+        let buckets: &mut PeerBuckets = peers.entry(ip).or_insert_with(|| PeerBuckets {
+            connections: self.connections.map(|rate| TokenBucket::new(rate, now)),
+            bytes: self.bytes.map(|rate| TokenBucket::new(rate, now)),
+        });
+        f(buckets)
+    }
+
+    /// Whether `ip` may open another connection right now.
+    pub fn allow_connection(&self, ip: IpAddr) -> bool {
+        if self.connections.is_none() {
+            return true;
+        }
+        let now: Instant = Instant::now();
+        self.with_peer(ip, now, |buckets| buckets.connections.as_mut().is_none_or(|bucket| bucket.try_take(now)))
+    }
+
+    /// Charges `ip` for `bytes` received, returning how long to wait before reading from it again.
+    pub fn take_bytes(&self, ip: IpAddr, bytes: usize) -> Duration {
+        if self.bytes.is_none() {
+            return Duration::ZERO;
+        }
+        let now: Instant = Instant::now();
+        self.with_peer(ip, now, |buckets| buckets.bytes.as_mut().map_or(Duration::ZERO, |bucket| bucket.take_owed(bytes as f64, now)))
+    }
+
+    /// Most bytes to read at once, so a single read can't run far past the budget.
+    pub fn max_read(&self) -> usize {
+        self.bytes.map_or(usize::MAX, |rate| (rate.burst as usize).max(1))
+    }
+}
+
+/// A client stream whose reads are held to its IP's byte rate: after every read the
+/// connection waits until its peer's bucket is out of debt again.
+pub struct Throttled {
+    inner: Box<dyn ClientStream>,
+    ip: IpAddr,
+    limiter: Arc<RateLimiter>,
+}
+
+impl Throttled {
+    pub fn new(inner: Box<dyn ClientStream>, ip: IpAddr, limiter: Arc<RateLimiter>) -> Throttled {
+        Throttled { inner, ip, limiter }
+    }
+}
+
+impl Read for Throttled {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max: usize = buf.len().min(self.limiter.max_read());
+        let bytes_read: usize = self.inner.read(&mut buf[..max])?;
+        let wait: Duration = self.limiter.take_bytes(self.ip, bytes_read);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl Write for Throttled {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl ClientStream for Throttled {
+    fn peer_name(&self) -> std::io::Result<String> {
+        self.inner.peer_name()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        Some(self.ip)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Clones share the peer's budget, like every other connection from that IP.
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(Throttled::new(self.inner.try_clone_stream()?, self.ip, self.limiter.clone())))
+    }
+
+    /// Every read has to be charged to the peer's budget.
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+}
+
+#[test]
+fn token_bucket_test() {
+    let start: Instant = Instant::now();
+    let mut bucket: TokenBucket = TokenBucket::new(Rate::new(2.0, 3.0).unwrap(), start);
+
+    assert!((0..3).all(|_| bucket.try_take(start)));
+    assert!(!bucket.try_take(start));
+    assert!(bucket.try_take(start + Duration::from_millis(500)));
+    assert!(!bucket.try_take(start + Duration::from_millis(600)));
+    assert!(bucket.is_full(start + Duration::from_secs(3)));
+
+    let mut bytes: TokenBucket = TokenBucket::new(Rate::new(1000.0, 0.0).unwrap(), start);
+    assert_eq!(bytes.take_owed(600.0, start), Duration::ZERO);
+    assert_eq!(bytes.take_owed(900.0, start), Duration::from_millis(500));
+    assert_eq!(Rate::new(0.0, 10.0), None);
+}
+
+#[test]
+fn rate_limiter_test() {
+    let limiter: RateLimiter = RateLimiter::new(Rate::new(1.0, 2.0), None);
+    let (one, two): (IpAddr, IpAddr) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+
+    assert!(limiter.allow_connection(one));
+    assert!(limiter.allow_connection("::ffff:10.0.0.1".parse().unwrap()));
+    assert!(!limiter.allow_connection(one));
+    assert!(limiter.allow_connection(two));
+    assert_eq!(limiter.take_bytes(one, 1 << 20), Duration::ZERO);
+    assert_eq!(limiter.max_read(), usize::MAX);
+}
@@ -0,0 +1,213 @@
+use std::ffi::{CStr, CString};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, TcpListener, TcpStream};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use crate::socket_options::SocketOptions;
+
+const BACKLOG: i32 = 128;
+
+/// A listening TCP socket built directly on top of libc calls
+/// (`getaddrinfo`, `socket`, `setsockopt`, `bind`, `listen`, `accept`).
+///
+/// It behaves like `std::net::TcpListener::bind`: every address returned by
+/// `getaddrinfo` is tried in order until one of them can be bound.
+/// The file descriptor is closed when the listener is dropped.
+#[derive(Debug)]
+pub struct RawListener {
+    fd: RawFd,
+}
+
+impl RawListener {
+    /// Resolves `node`/`service` (e.g. `"127.0.0.1"`, `"8080"`) and listens on the first usable address.
+    pub fn bind(node: &str, service: &str) -> std::io::Result<RawListener> {
+        RawListener::bind_with(node, service, None)
+    }
+
+    /// Like `bind`, additionally setting `IPV6_V6ONLY` on IPv6 sockets before binding them.
+    /// With `Some(false)` a listener on `::` accepts IPv4 clients too, as v4-mapped addresses.
+    pub fn bind_with(node: &str, service: &str, only_v6: Option<bool>) -> std::io::Result<RawListener> {
+        RawListener::bind_with_options(node, service, only_v6, &SocketOptions::default())
+    }
+
+    /// Like `bind_with`, setting `options` on the socket before binding it.
+    pub fn bind_with_options(node: &str, service: &str, only_v6: Option<bool>, options: &SocketOptions) -> std::io::Result<RawListener> {
+        let node: CString = CString::new(node).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let service: CString = CString::new(service).map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+
+        let mut hints: libc::addrinfo = unsafe { std::mem::zeroed() };
+        hints.ai_family = libc::AF_UNSPEC;
+        hints.ai_socktype = libc::SOCK_STREAM;
+        hints.ai_flags = libc::AI_PASSIVE;
+
+        let mut addresses: *mut libc::addrinfo = std::ptr::null_mut();
+        let status: i32 = unsafe { libc::getaddrinfo(node.as_ptr(), service.as_ptr(), &hints, &mut addresses) };
+        if status != 0 {
+            return Err(gai_error(status));
+        }
+
+        let mut last_error: std::io::Error = std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "getaddrinfo returned no addresses");
+        let mut current: *mut libc::addrinfo = addresses;
+        let mut listener: Option<RawListener> = None;
+
+        while !current.is_null() {
+            let info: &libc::addrinfo = unsafe { &*current };
+            match RawListener::bind_addrinfo(info, only_v6, options) {
+                Ok(bound) => {
+                    listener = Some(bound);
+                    break;
+                }
+                Err(err) => last_error = err,
+            }
+            current = info.ai_next;
+        }
+
+        unsafe { libc::freeaddrinfo(addresses) };
+        listener.ok_or(last_error)
+    }
+
+    fn bind_addrinfo(info: &libc::addrinfo, only_v6: Option<bool>, options: &SocketOptions) -> std::io::Result<RawListener> {
+        let fd: RawFd = cvt(unsafe { libc::socket(info.ai_family, info.ai_socktype | libc::SOCK_CLOEXEC, info.ai_protocol) })?;
+        // From here on `listener` owns the fd, so any early return closes it.
+        let listener: RawListener = RawListener { fd };
+
+        listener.set_reuse_address(true)?;
+        if let (libc::AF_INET6, Some(only_v6)) = (info.ai_family, only_v6) {
+            set_int_option(fd, libc::IPPROTO_IPV6, libc::IPV6_V6ONLY, only_v6 as libc::c_int)?;
+        }
+        options.apply_listener(fd)?;
+        cvt(unsafe { libc::bind(fd, info.ai_addr, info.ai_addrlen) })?;
+        cvt(unsafe { libc::listen(fd, BACKLOG) })?;
+
+        Ok(listener)
+    }
+
+    /// Sets `SO_REUSEADDR`, which is also what the standard library does before binding.
+    pub fn set_reuse_address(&self, reuse: bool) -> std::io::Result<()> {
+        set_int_option(self.fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, reuse as libc::c_int)
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len: libc::socklen_t = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        cvt(unsafe { libc::getsockname(self.fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) })?;
+        sockaddr_to_addr(&storage)
+    }
+
+    /// Blocks until a client connects, handing the accepted fd over to a `std::net::TcpStream`.
+    pub fn accept(&self) -> std::io::Result<(TcpStream, SocketAddr)> {
+        let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut len: libc::socklen_t = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+
+        let fd: RawFd = loop {
+            let fd: i32 = unsafe { libc::accept4(self.fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len, libc::SOCK_CLOEXEC) };
+            match cvt(fd) {
+                Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                result => break result?,
+            }
+        };
+
+        let stream: TcpStream = unsafe { TcpStream::from_raw_fd(fd) };
+        Ok((stream, sockaddr_to_addr(&storage)?))
+    }
+}
+
+impl AsRawFd for RawListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl IntoRawFd for RawListener {
+    fn into_raw_fd(self) -> RawFd {
+        let fd: RawFd = self.fd;
+        std::mem::forget(self);
+        fd
+    }
+}
+
+impl From<RawListener> for TcpListener {
+    fn from(listener: RawListener) -> TcpListener {
+        unsafe { TcpListener::from_raw_fd(listener.into_raw_fd()) }
+    }
+}
+
+impl Drop for RawListener {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+/// Converts the `-1` + `errno` convention into an `io::Result`.
+fn cvt(result: i32) -> std::io::Result<i32> {
+    if result < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+/// `setsockopt` for the many options that take a plain `int`.
+pub fn set_int_option(fd: RawFd, level: i32, name: i32, value: libc::c_int) -> std::io::Result<()> {
+    cvt(unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    })?;
+    Ok(())
+}
+
+/// `getsockopt` for the options that hold a plain `int`.
+pub fn get_int_option(fd: RawFd, level: i32, name: i32) -> std::io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len: libc::socklen_t = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    cvt(unsafe { libc::getsockopt(fd, level, name, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) })?;
+    Ok(value)
+}
+
+fn gai_error(status: i32) -> std::io::Error {
+    if status == libc::EAI_SYSTEM {
+        return std::io::Error::last_os_error();
+    }
+    let message: &CStr = unsafe { CStr::from_ptr(libc::gai_strerror(status)) };
+    std::io::Error::other(format!("getaddrinfo: {}", message.to_string_lossy()))
+}
+
+fn sockaddr_to_addr(storage: &libc::sockaddr_storage) -> std::io::Result<SocketAddr> {
+    match storage.ss_family as i32 {
+        libc::AF_INET => {
+            let addr: &libc::sockaddr_in = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip: Ipv4Addr = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr: &libc::sockaddr_in6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip: Ipv6Addr = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Ok(SocketAddr::V6(SocketAddrV6::new(ip, u16::from_be(addr.sin6_port), addr.sin6_flowinfo, addr.sin6_scope_id)))
+        }
+        family => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("unsupported address family {}", family))),
+    }
+}
+
+#[test]
+fn raw_listener_accept_test() {
+    let listener: RawListener = RawListener::bind("127.0.0.1", "0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    assert_eq!(addr.ip(), std::net::IpAddr::V4(Ipv4Addr::LOCALHOST));
+    assert_ne!(addr.port(), 0);
+
+    let client: TcpStream = TcpStream::connect(addr).unwrap();
+    let (server, peer): (TcpStream, SocketAddr) = listener.accept().unwrap();
+
+    assert_eq!(peer, client.local_addr().unwrap());
+    assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+}
+
+#[test]
+fn raw_listener_resolve_error_test() {
+    let err: std::io::Error = RawListener::bind("127.0.0.1", "no-such-service").unwrap_err();
+    assert!(err.to_string().starts_with("getaddrinfo"), "{}", err);
+}
@@ -0,0 +1,321 @@
+//! Session recording for `--record <file>`: every byte each client sends and receives,
+//! so that a client's session can be run again against any server, see the replay module.
+//!
+//! The file has one JSON object per line, each an event of one session:
+//!
+//! ```text
+//! {"session":1,"elapsed_us":0,"event":"open","time":"2026-10-18T09:12:03.532Z","peer":"127.0.0.1:50312","protocol":"line"}
+//! {"session":1,"elapsed_us":1250,"event":"in","data":"UElORwo="}
+//! {"session":1,"elapsed_us":1310,"event":"out","data":"UE9ORwo="}
+//! {"session":1,"elapsed_us":2020,"event":"eof"}
+//! {"session":1,"elapsed_us":2100,"event":"close"}
+//! ```
+//!
+//! - `session` numbers the connections of a server run from 1; the lines of concurrent
+//!   sessions interleave, and a later run appending to the file starts from 1 again.
+//! - `elapsed_us` is the time since the session opened, in microseconds.
+//! - `event` is `open`, with the wall clock `time`, the client's `peer` and the `protocol`
+//!   of its listener; `in` for bytes read from the client and `out` for bytes written to
+//!   it, base64 encoded in `data`, one event per read or write; `eof` once the client shut
+//!   down its side of the connection; `close` once the server is done with it.
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{format_time, json_escape};
+use crate::base64;
+use crate::config::Protocol;
+use crate::stream::ClientStream;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    Open { time: String, peer: String, protocol: String },
+    In(Vec<u8>),
+    Out(Vec<u8>),
+    Eof,
+    Close,
+}
+
+/// One line of a recording.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub session: u64,
+    pub elapsed: Duration,
+    pub event: Event,
+}
+
+impl std::fmt::Display for Entry {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{{\"session\":{},\"elapsed_us\":{},", self.session, self.elapsed.as_micros())?;
+        match &self.event {
+            Event::Open { time, peer, protocol } => write!(
+                f,
+                "\"event\":\"open\",\"time\":\"{}\",\"peer\":\"{}\",\"protocol\":\"{}\"}}",
+                json_escape(time),
+                json_escape(peer),
+                json_escape(protocol)
+            ),
+            Event::In(data) => write!(f, "\"event\":\"in\",\"data\":\"{}\"}}", base64::encode(data)),
+            Event::Out(data) => write!(f, "\"event\":\"out\",\"data\":\"{}\"}}", base64::encode(data)),
+            Event::Eof => f.write_str("\"event\":\"eof\"}"),
+            Event::Close => f.write_str("\"event\":\"close\"}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Entry {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<(String, String)> = parse_object(s).ok_or(())?;
+        let field = |name: &str| -> Result<&str, ()> { fields.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str()).ok_or(()) };
+        let data = || -> Result<Vec<u8>, ()> { base64::decode(field("data")?).map_err(|_| ()) };
+
+        let event: Event = match field("event")? {
+            "open" => Event::Open { time: field("time")?.to_owned(), peer: field("peer")?.to_owned(), protocol: field("protocol")?.to_owned() },
+            "in" => Event::In(data()?),
+            "out" => Event::Out(data()?),
+            "eof" => Event::Eof,
+            "close" => Event::Close,
+            _ => return Err(()),
+        };
+        Ok(Entry {
+            session: field("session")?.parse().map_err(|_| ())?,
+            elapsed: Duration::from_micros(field("elapsed_us")?.parse().map_err(|_| ())?),
+            event,
+        })
+    }
+}
+
+/// The fields of a flat JSON object of strings and unsigned integers, the only kind the
+/// recorder writes; numbers come back as their digits.
+fn parse_object(text: &str) -> Option<Vec<(String, String)>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut fields: Vec<(String, String)> = Vec::new();
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return chars.next().is_none().then_some(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let key: String = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value: String = match chars.peek()? {
+            '"' => parse_string(&mut chars)?,
+            _ => {
+                let mut digits: String = String::new();
+                while let Some(c) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(c);
+                }
+                if digits.is_empty() {
+                    return None;
+                }
+                digits
+            }
+        };
+        fields.push((key, value));
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut text: String = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => text.push(match chars.next()? {
+                '"' => '"',
+                '\\' => '\\',
+                '/' => '/',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c => text.push(c),
+        }
+    }
+}
+
+/// Where sessions are recorded; shared by every connection, and does nothing unless a file
+/// was given.
+pub struct Recorder {
+    file: Option<Mutex<File>>,
+    sessions: AtomicU64,
+}
+
+impl Recorder {
+    /// Appends to `path`, if given.
+    pub fn open(path: Option<&str>) -> std::io::Result<Recorder> {
+        let file: Option<Mutex<File>> = match path {
+            Some(path) => Some(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)),
+            None => None,
+        };
+        Ok(Recorder { file, sessions: AtomicU64::new(0) })
+    }
+
+    /// Records everything read from and written to `stream` from now on, writing the `open`
+    /// event right away; streams go through untouched when there's no file.
+    pub fn record(self: &Arc<Self>, stream: Box<dyn ClientStream>, peer: &str, protocol: Protocol) -> Box<dyn ClientStream> {
+        if self.file.is_none() {
+            return stream;
+        }
+        let session: Session = Session { recorder: self.clone(), id: self.sessions.fetch_add(1, Ordering::Relaxed) + 1, started: Instant::now() };
+        session.event(Event::Open { time: format_time(SystemTime::now()), peer: peer.to_owned(), protocol: protocol.to_string() });
+        Box::new(Recorded { inner: stream, session: Arc::new(session) })
+    }
+
+    fn write(&self, entry: &Entry) {
+        if let Some(file) = &self.file {
+            // One write per line, so that concurrent sessions never tear each other's lines.
+            if let Err(err) = file.lock().unwrap().write_all(format!("{}\n", entry).as_bytes()) {
+                eprintln!("couldn't write session record: {}", err);
+            }
+        }
+    }
+}
+
+/// A recorded connection, closed once the stream and all its clones are dropped.
+struct Session {
+    recorder: Arc<Recorder>,
+    id: u64,
+    started: Instant,
+}
+
+impl Session {
+    fn event(&self, event: Event) {
+        self.recorder.write(&Entry { session: self.id, elapsed: self.started.elapsed(), event });
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.event(Event::Close);
+    }
+}
+
+/// A client stream recording everything that goes through it.
+pub struct Recorded {
+    inner: Box<dyn ClientStream>,
+    session: Arc<Session>,
+}
+
+impl Read for Recorded {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read: usize = self.inner.read(buf)?;
+        match bytes_read {
+            0 if !buf.is_empty() => self.session.event(Event::Eof),
+            0 => {}
+            _ => self.session.event(Event::In(buf[..bytes_read].to_vec())),
+        }
+        Ok(bytes_read)
+    }
+}
+
+impl Write for Recorded {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written: usize = self.inner.write(buf)?;
+        if written > 0 {
+            self.session.event(Event::Out(buf[..written].to_vec()));
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl ClientStream for Recorded {
+    fn peer_name(&self) -> std::io::Result<String> {
+        self.inner.peer_name()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.inner.peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        self.inner.set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        self.inner.set_nonblocking(nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        self.inner.shutdown(how)
+    }
+
+    /// Clones record into the same session, e.g. the replies of broadcast and relay mode.
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(Recorded { inner: self.inner.try_clone_stream()?, session: self.session.clone() }))
+    }
+
+    /// Every byte has to be seen to be recorded.
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        None
+    }
+}
+
+#[test]
+fn entry_format_test() {
+    let entries: [Entry; 5] = [
+        Entry {
+            session: 1,
+            elapsed: Duration::ZERO,
+            event: Event::Open { time: "2026-10-18T09:12:03.532Z".to_owned(), peer: "unix:pid=7,uid=0".to_owned(), protocol: "line".to_owned() },
+        },
+        Entry { session: 1, elapsed: Duration::from_micros(1250), event: Event::In(b"PING\n".to_vec()) },
+        Entry { session: 1, elapsed: Duration::from_micros(1310), event: Event::Out(vec![0, 255, b'"']) },
+        Entry { session: 12, elapsed: Duration::from_secs(3), event: Event::Eof },
+        Entry { session: 12, elapsed: Duration::from_secs(4), event: Event::Close },
+    ];
+    assert_eq!(entries[1].to_string(), r#"{"session":1,"elapsed_us":1250,"event":"in","data":"UElORwo="}"#);
+    for entry in &entries {
+        assert_eq!(entry.to_string().parse::<Entry>(), Ok(entry.clone()));
+    }
+
+    // Field order and whitespace are up to whoever wrote the line.
+    let entry: Entry = r#" { "event" : "open", "peer":"a\"bé", "protocol":"raw","time":"t","elapsed_us":5,"session":2 } "#.parse().unwrap();
+    assert_eq!(entry.event, Event::Open { time: "t".to_owned(), peer: "a\"b\u{e9}".to_owned(), protocol: "raw".to_owned() });
+
+    assert!(r#"{"session":1,"elapsed_us":0,"event":"in","data":"not base64"}"#.parse::<Entry>().is_err());
+    assert!(r#"{"session":1,"elapsed_us":0,"event":"shout"}"#.parse::<Entry>().is_err());
+    assert!(r#"{"session":-1,"elapsed_us":0,"event":"eof"}"#.parse::<Entry>().is_err());
+    assert!(r#"{"session":1,"elapsed_us":0,"event":"eof"} trailing"#.parse::<Entry>().is_err());
+    assert!("".parse::<Entry>().is_err());
+}
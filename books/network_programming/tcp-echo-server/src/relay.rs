@@ -0,0 +1,204 @@
+//! Relay mode: every client gets a connection of its own to `--upstream`, and whatever
+//! either side sends is passed on to the other, so the server sits transparently in
+//! between, e.g. to watch a service's traffic with `--hex-dump`.
+//!
+//! When one side closes its sending half, the relay closes the sending half of its
+//! connection to the other side with `shutdown(Write)` and keeps passing on what comes
+//! back, so protocols that end a request with a half-close keep working.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+
+use crate::access_log::CloseReason;
+use crate::stats::Counted;
+use crate::stream::ClientStream;
+use crate::timeouts::TimedStream;
+
+const BUF_SIZE: usize = 16 * 1024;
+/// Bytes per line of a hex dump.
+const DUMP_WIDTH: usize = 16;
+
+/// `host:port` to relay to; the host is resolved anew for every client, e.g.
+/// `localhost:5432`, `10.0.0.7:80` or `[::1]:8080`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    host: String,
+    port: u16,
+}
+
+impl Upstream {
+    pub fn connect(&self) -> std::io::Result<TcpStream> {
+        TcpStream::connect((self.host.as_str(), self.port)).map_err(|err| std::io::Error::new(err.kind(), format!("couldn't connect to upstream {}: {}", self, err)))
+    }
+}
+
+impl std::str::FromStr for Upstream {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port): (&str, &str) = s.rsplit_once(':').ok_or(())?;
+        let host: &str = match host.strip_prefix('[') {
+            Some(host) => host.strip_suffix(']').ok_or(())?,
+            None if host.contains(':') => return Err(()),
+            None => host,
+        };
+        if host.is_empty() {
+            return Err(());
+        }
+        Ok(Upstream { host: host.to_owned(), port: port.parse().map_err(|_| ())? })
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.host.contains(':') {
+            true => write!(f, "[{}]:{}", self.host, self.port),
+            false => write!(f, "{}:{}", self.host, self.port),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RelayOptions {
+    /// Relay every client here instead of echoing; `None` echoes.
+    pub upstream: Option<Upstream>,
+    /// Print everything relayed to stdout, as a hex dump.
+    pub hex_dump: bool,
+}
+
+/// Relays between the client and a new connection to `upstream` until both sides closed
+/// their sending half, counting the chunks passed on either way in `messages`.
+///
+/// `stream` only reads from the client and `replies`, a clone of it, only writes, so the
+/// server counters' `bytes_in` and `bytes_out` are what went upstream and downstream.
+pub fn serve(
+    stream: &mut Counted<TimedStream<Box<dyn ClientStream>>>,
+    replies: &mut Counted<TimedStream<Box<dyn ClientStream>>>,
+    peer: &str,
+    options: &RelayOptions,
+    messages: &mut u64,
+) -> std::io::Result<CloseReason> {
+    let upstream: &Upstream = match &options.upstream {
+        Some(upstream) => upstream,
+        None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no upstream to relay to")),
+    };
+    let mut server: TcpStream = upstream.connect()?;
+    let mut server_reader: TcpStream = server.try_clone()?;
+    println!("relaying {} to {}", peer, upstream);
+    let up: Option<String> = options.hex_dump.then(|| format!("{} -> {}", peer, upstream));
+    let down: Option<String> = options.hex_dump.then(|| format!("{} <- {}", peer, upstream));
+
+    let (sent, received): (std::io::Result<u64>, std::io::Result<u64>) = std::thread::scope(|scope| {
+        let downstream = scope.spawn(|| {
+            let result: std::io::Result<u64> = pump(|buf| server_reader.read(buf), replies, down.as_deref());
+            // Either way nothing more goes to the client; after an error, nothing more comes from it either.
+            let how: Shutdown = if result.is_ok() { Shutdown::Write } else { Shutdown::Both };
+            let _ = replies.get_ref().get_ref().shutdown(how);
+            result
+        });
+
+        let result: std::io::Result<u64> = pump(
+            |buf| {
+                let bytes_read: usize = stream.read(buf)?;
+                // Passed on is as answered as it gets, so the idle timeout applies again.
+                stream.get_mut().end_request();
+                Ok(bytes_read)
+            },
+            &mut server,
+            up.as_deref(),
+        );
+        let result: std::io::Result<u64> = result.and_then(|chunks| server.shutdown(Shutdown::Write).map(|_| chunks));
+        if result.is_err() {
+            let _ = server.shutdown(Shutdown::Both);
+        }
+        (result, downstream.join().unwrap_or_else(|_| Err(std::io::Error::other("relay thread panicked"))))
+    });
+
+    let sent: u64 = sent?;
+    let received: u64 = received?;
+    *messages += sent + received;
+    println!("relayed {} <-> {}: {} bytes up, {} bytes down", peer, upstream, stream.totals().0, replies.totals().1);
+    Ok(CloseReason::Eof)
+}
+
+/// Passes everything `read` returns on to `to` until EOF, returning the number of chunks.
+fn pump<W: Write>(mut read: impl FnMut(&mut [u8]) -> std::io::Result<usize>, to: &mut W, dump: Option<&str>) -> std::io::Result<u64> {
+    let mut buf: Vec<u8> = vec![0; BUF_SIZE];
+    let mut chunks: u64 = 0;
+    loop {
+        let bytes_read: usize = read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(chunks);
+        }
+        if let Some(direction) = dump {
+            // One print per chunk, so the dumps of both directions don't interleave.
+            print!("{} {} bytes\n{}", direction, bytes_read, hex_dump(&buf[..bytes_read]));
+        }
+        to.write_all(&buf[..bytes_read])?;
+        chunks += 1;
+    }
+}
+
+/// `hexdump -C` style lines: offset, bytes in hex, and the printable ones as text.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut out: String = String::new();
+    for (line, chunk) in data.chunks(DUMP_WIDTH).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = chunk.iter().map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' }).collect();
+        out += &format!("{:08x}  {:<width$}  |{}|\n", line * DUMP_WIDTH, hex.join(" "), text, width = DUMP_WIDTH * 3 - 1);
+    }
+    out
+}
+
+#[test]
+fn upstream_parse_test() {
+    let upstream: Upstream = "localhost:5432".parse().unwrap();
+    assert_eq!(upstream, Upstream { host: "localhost".to_owned(), port: 5432 });
+    assert_eq!("[::1]:8080".parse::<Upstream>().unwrap().to_string(), "[::1]:8080");
+    assert_eq!("10.0.0.7:80".parse::<Upstream>().unwrap().to_string(), "10.0.0.7:80");
+
+    assert!("localhost".parse::<Upstream>().is_err());
+    assert!(":80".parse::<Upstream>().is_err());
+    assert!("::1:80".parse::<Upstream>().is_err());
+    assert!("localhost:http".parse::<Upstream>().is_err());
+}
+
+#[test]
+fn hex_dump_test() {
+    assert_eq!(hex_dump(b"hi\n"), format!("00000000  68 69 0a{}  |hi.|\n", " ".repeat(39)));
+    let lines: Vec<String> = hex_dump(&[b'a'; 20]).lines().map(str::to_owned).collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[1].starts_with("00000010  61 61 61 61  "));
+    assert!(lines[0].ends_with(&format!("|{}|", "a".repeat(16))));
+}
+
+#[test]
+fn relay_test() {
+    use std::sync::atomic::Ordering;
+
+    // Answers only once the request is complete, which it learns from the half-close.
+    let upstream: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_addr: std::net::SocketAddr = upstream.local_addr().unwrap();
+    let upstream_thread = std::thread::spawn(move || {
+        let (mut stream, _) = upstream.accept().unwrap();
+        let mut request: Vec<u8> = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        stream.write_all(format!("got {} bytes", request.len()).as_bytes()).unwrap();
+    });
+
+    let server: crate::ServerHandle = crate::EchoServer::builder().bind("127.0.0.1:0").upstream(&upstream_addr.to_string()).spawn().unwrap();
+    let mut client: TcpStream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    client.write_all(b"hello ").unwrap();
+    client.write_all(b"upstream").unwrap();
+    client.shutdown(Shutdown::Write).unwrap();
+    let mut reply: String = String::new();
+    client.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "got 14 bytes");
+    upstream_thread.join().unwrap();
+
+    // The relay is done once the client saw EOF, but the counters are updated right before that.
+    drop(client);
+    assert_eq!(server.stats().bytes_in.load(Ordering::Relaxed), 14);
+    assert_eq!(server.stats().bytes_out.load(Ordering::Relaxed), 12);
+    server.shutdown();
+}
@@ -0,0 +1,426 @@
+//! `tcp-echo-server replay`: runs the client sessions of a `--record` file (see the
+//! recording module) against a server again, sending what each client sent and comparing
+//! what comes back with what the server answered then.
+//!
+//! Replies are compared byte for byte, however the server split them into writes; replies
+//! that are different every time, like `STATS` or the client's port in HTTP answers, show
+//! up as differences.
+
+use std::collections::HashMap;
+use std::io::{BufRead, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::time::{Duration, Instant};
+
+use crate::recording::{Entry, Event};
+use crate::stream::ClientStream;
+use crate::unix_socket::UnixAddr;
+
+pub const USAGE: &str = "\
+usage: tcp-echo-server replay [options] <file> <target>
+
+Sends what every client in a --record file sent to <target>, <host>:<port> or unix:<path>
+(unix:@name for Linux's abstract namespace), and compares the replies with the recorded ones.
+
+options:
+  --session <n>          only replay the session with this number, may be repeated (default: all)
+  --timing               wait between sends as long as the client did (default: send right away)
+  --timeout <secs>       how long to wait for each reply (default: 5)
+  -h, --help             print this help
+
+The exit status is 0 when every reply matches, 1 when any differs and 2 on any other error.";
+
+/// Where to replay to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Tcp(String),
+    Unix(UnixAddr),
+}
+
+impl Target {
+    fn connect(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        match self {
+            Target::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr.as_str())?)),
+            Target::Unix(addr) => Ok(Box::new(addr.connect()?)),
+        }
+    }
+}
+
+impl std::str::FromStr for Target {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with("unix:") {
+            return Ok(Target::Unix(s.parse()?));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(Target::Tcp(s.to_owned())),
+            _ => Err(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Target::Tcp(addr) => f.write_str(addr),
+            Target::Unix(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayOptions {
+    pub file: String,
+    pub target: Target,
+    /// Sessions to replay, by number; all of them if empty.
+    pub sessions: Vec<u64>,
+    /// Keep to the recorded time between sends.
+    pub timing: bool,
+    pub timeout: Duration,
+}
+
+impl ReplayOptions {
+    /// Parses the arguments after `replay`; the error is the message to print, `USAGE` for `--help`.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<ReplayOptions, String> {
+        let mut positional: Vec<String> = Vec::new();
+        let mut sessions: Vec<u64> = Vec::new();
+        let mut timing: bool = false;
+        let mut timeout: Duration = Duration::from_secs(5);
+        let mut args = args.into_iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--timing" => timing = true,
+                "--session" | "--timeout" => {
+                    let value: String = args.next().ok_or_else(|| format!("option '{}' expects a value", arg))?;
+                    let invalid = || format!("invalid value '{}' for option '{}'", value, arg);
+                    match arg.as_str() {
+                        "--session" => sessions.push(value.parse().map_err(|_| invalid())?),
+                        _ => {
+                            timeout = value.parse().ok().and_then(|secs: f64| Duration::try_from_secs_f64(secs).ok()).filter(|timeout| !timeout.is_zero()).ok_or_else(invalid)?;
+                        }
+                    }
+                }
+                _ if arg.starts_with('-') => return Err(format!("unknown option '{}'\n\n{}", arg, USAGE)),
+                _ => positional.push(arg),
+            }
+        }
+
+        let (file, target): (String, String) = match <[String; 2]>::try_from(positional) {
+            Ok([file, target]) => (file, target),
+            Err(_) => return Err(format!("expected a file and a target\n\n{}", USAGE)),
+        };
+        let target: Target = target.parse().map_err(|_| format!("invalid target '{}'\n\n{}", target, USAGE))?;
+        Ok(ReplayOptions { file, target, sessions, timing, timeout })
+    }
+}
+
+/// What the client did, in the order it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// Bytes the client sent, `at` this long into the session.
+    Send { at: Duration, data: Vec<u8> },
+    /// Everything the server wrote before the client's next step.
+    Reply(Vec<u8>),
+    /// The client shut down its side of the connection.
+    CloseWrite { at: Duration },
+}
+
+/// A client's session as it was recorded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Session {
+    pub id: u64,
+    pub peer: String,
+    pub protocol: String,
+    pub steps: Vec<Step>,
+}
+
+impl Session {
+    /// Bytes sent and received.
+    pub fn totals(&self) -> (usize, usize) {
+        self.steps.iter().fold((0, 0), |(sent, received), step| match step {
+            Step::Send { data, .. } => (sent + data.len(), received),
+            Step::Reply(data) => (sent, received + data.len()),
+            Step::CloseWrite { .. } => (sent, received),
+        })
+    }
+}
+
+/// Collects the sessions of a recording, in the order they were opened.
+pub fn read_sessions<R: BufRead>(reader: R) -> std::io::Result<Vec<Session>> {
+    let invalid = |line: usize, message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("line {}: {}", line, message));
+    let mut sessions: Vec<Session> = Vec::new();
+    // Sessions still open, by number; numbers start over with every server run.
+    let mut open: HashMap<u64, usize> = HashMap::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line: String = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry: Entry = line.parse().map_err(|()| invalid(index + 1, "not a session record".to_owned()))?;
+        if let Event::Open { peer, protocol, .. } = entry.event {
+            open.insert(entry.session, sessions.len());
+            sessions.push(Session { id: entry.session, peer, protocol, steps: Vec::new() });
+            continue;
+        }
+        let session: &mut Session = match open.get(&entry.session) {
+            Some(&session) => &mut sessions[session],
+            None => return Err(invalid(index + 1, format!("session {} isn't open", entry.session))),
+        };
+        match entry.event {
+            Event::Open { .. } => unreachable!(),
+            Event::In(data) => session.steps.push(Step::Send { at: entry.elapsed, data }),
+            Event::Out(data) => match session.steps.last_mut() {
+                Some(Step::Reply(reply)) => reply.extend_from_slice(&data),
+                _ => session.steps.push(Step::Reply(data)),
+            },
+            Event::Eof => session.steps.push(Step::CloseWrite { at: entry.elapsed }),
+            Event::Close => {
+                open.remove(&entry.session);
+            }
+        }
+    }
+    Ok(sessions)
+}
+
+/// How a replayed session went differently from the recorded one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Difference {
+    /// The `index`th reply (from 1) wasn't the same, or was cut short by the server hanging
+    /// up or going quiet.
+    Reply { index: usize, expected: Vec<u8>, actual: Vec<u8> },
+    /// The server sent this after the last recorded reply.
+    Extra(Vec<u8>),
+    /// The server stopped taking what the client sent.
+    Send(String),
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Difference::Reply { index, expected, actual } if expected.starts_with(actual) => write!(
+                f,
+                "reply {} ended after {} of {} byte(s), missing {:?}",
+                index,
+                actual.len(),
+                expected.len(),
+                excerpt(&expected[actual.len()..])
+            ),
+            Difference::Reply { index, expected, actual } => {
+                let at: usize = expected.iter().zip(actual).take_while(|(expected, actual)| expected == actual).count();
+                write!(f, "reply {} differs at byte {}: expected {:?}, got {:?}", index, at, excerpt(&expected[at..]), excerpt(&actual[at..]))
+            }
+            Difference::Extra(data) => write!(f, "{} more byte(s) after the last reply: {:?}", data.len(), excerpt(data)),
+            Difference::Send(err) => write!(f, "couldn't send: {}", err),
+        }
+    }
+}
+
+/// The start of `data`, enough to tell what it is.
+fn excerpt(data: &[u8]) -> String {
+    const MAX: usize = 64;
+    match data.len() > MAX {
+        true => format!("{}...", String::from_utf8_lossy(&data[..MAX])),
+        false => String::from_utf8_lossy(data).into_owned(),
+    }
+}
+
+/// Runs `session` against `options.target`, returning how the replies differed.
+pub fn replay(session: &Session, options: &ReplayOptions) -> std::io::Result<Vec<Difference>> {
+    let mut stream: Box<dyn ClientStream> = options.target.connect()?;
+    stream.set_read_timeout(Some(options.timeout))?;
+    let started: Instant = Instant::now();
+    let mut differences: Vec<Difference> = Vec::new();
+    let mut replies: usize = 0;
+
+    for step in &session.steps {
+        if let (true, Step::Send { at, .. } | Step::CloseWrite { at }) = (options.timing, step) {
+            std::thread::sleep(at.saturating_sub(started.elapsed()));
+        }
+        match step {
+            Step::Send { data, .. } => {
+                if let Err(err) = stream.write_all(data) {
+                    differences.push(Difference::Send(err.to_string()));
+                    return Ok(differences);
+                }
+            }
+            Step::Reply(expected) => {
+                replies += 1;
+                let actual: Vec<u8> = read_up_to(&mut stream, expected.len())?;
+                if actual != *expected {
+                    let cut_short: bool = actual.len() < expected.len();
+                    differences.push(Difference::Reply { index: replies, expected: expected.clone(), actual });
+                    if cut_short {
+                        return Ok(differences);
+                    }
+                }
+            }
+            // A server that already hung up has nothing left to shut down.
+            Step::CloseWrite { .. } => {
+                let _ = stream.shutdown(Shutdown::Write);
+            }
+        }
+    }
+
+    let extra: Vec<u8> = read_up_to(&mut stream, usize::MAX)?;
+    if !extra.is_empty() {
+        differences.push(Difference::Extra(extra));
+    }
+    Ok(differences)
+}
+
+/// Reads until `len` bytes came in, the server hung up or nothing came for the read timeout.
+fn read_up_to(stream: &mut Box<dyn ClientStream>, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut received: Vec<u8> = Vec::new();
+    let mut buf: Vec<u8> = vec![0; 64 * 1024];
+    while received.len() < len {
+        let max: usize = buf.len().min(len - received.len());
+        match stream.read(&mut buf[..max]) {
+            Ok(0) => break,
+            Ok(bytes_read) => received.extend_from_slice(&buf[..bytes_read]),
+            Err(ref err) if matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::ConnectionReset) => break,
+            Err(ref err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(received)
+}
+
+/// Replays the sessions `options` picks from its file, printing how each went. Returns
+/// whether every reply matched.
+pub fn run(options: &ReplayOptions) -> std::io::Result<bool> {
+    let file: std::fs::File = std::fs::File::open(&options.file).map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", options.file, err)))?;
+    let sessions: Vec<Session> = read_sessions(std::io::BufReader::new(file)).map_err(|err| std::io::Error::new(err.kind(), format!("{}: {}", options.file, err)))?;
+    let selected: Vec<&Session> = sessions.iter().filter(|session| options.sessions.is_empty() || options.sessions.contains(&session.id)).collect();
+    if selected.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{}: no sessions to replay", options.file)));
+    }
+
+    let mut matched: bool = true;
+    for session in selected {
+        let differences: Vec<Difference> = replay(session, options).map_err(|err| std::io::Error::new(err.kind(), format!("session {}: {}: {}", session.id, options.target, err)))?;
+        let (sent, received): (usize, usize) = session.totals();
+        let outcome: String = match differences.len() {
+            0 => "ok".to_owned(),
+            count => format!("{} difference(s)", count),
+        };
+        println!("session {} from {} ({}): {}, {} byte(s) sent, {} received", session.id, session.peer, session.protocol, outcome, sent, received);
+        for difference in &differences {
+            println!("  {}", difference);
+        }
+        matched &= differences.is_empty();
+    }
+    Ok(matched)
+}
+
+#[test]
+fn read_sessions_test() {
+    let entry = |session: u64, elapsed_ms: u64, event: Event| Entry { session, elapsed: Duration::from_millis(elapsed_ms), event }.to_string();
+    let open = |peer: &str| Event::Open { time: "2026-10-18T09:12:03.532Z".to_owned(), peer: peer.to_owned(), protocol: "line".to_owned() };
+    let lines: Vec<String> = vec![
+        entry(1, 0, open("a")),
+        entry(2, 0, open("b")),
+        entry(1, 1, Event::In(b"PING\n".to_vec())),
+        entry(2, 1, Event::In(b"QUIT\n".to_vec())),
+        entry(1, 2, Event::Out(b"PO".to_vec())),
+        entry(1, 2, Event::Out(b"NG\n".to_vec())),
+        entry(2, 2, Event::Out(b"BYE\n".to_vec())),
+        entry(2, 3, Event::Close),
+        String::new(),
+        entry(1, 4, Event::Eof),
+        entry(1, 5, Event::Close),
+        // The next server run starts from 1 again.
+        entry(1, 0, open("c")),
+        entry(1, 1, Event::Close),
+    ];
+    let sessions: Vec<Session> = read_sessions(lines.join("\n").as_bytes()).unwrap();
+    assert_eq!(sessions.iter().map(|session| (session.id, session.peer.as_str())).collect::<Vec<_>>(), [(1, "a"), (2, "b"), (1, "c")]);
+    assert_eq!(
+        sessions[0].steps,
+        [
+            Step::Send { at: Duration::from_millis(1), data: b"PING\n".to_vec() },
+            Step::Reply(b"PONG\n".to_vec()),
+            Step::CloseWrite { at: Duration::from_millis(4) },
+        ]
+    );
+    assert_eq!(sessions[1].totals(), (5, 4));
+    assert!(sessions[2].steps.is_empty());
+
+    let err: std::io::Error = read_sessions(entry(3, 0, Event::Eof).as_bytes()).unwrap_err();
+    assert_eq!(err.to_string(), "line 1: session 3 isn't open");
+    let err: std::io::Error = read_sessions("{\"session\":1}\n".as_bytes()).unwrap_err();
+    assert_eq!(err.to_string(), "line 1: not a session record");
+}
+
+#[test]
+fn replay_options_test() {
+    let parse = |args: &[&str]| ReplayOptions::from_args(args.iter().map(|s| s.to_string()));
+
+    let options: ReplayOptions = parse(&["--session", "3", "sessions.jsonl", "unix:@echo", "--timing", "--session", "4"]).unwrap();
+    assert_eq!(options.target, Target::Unix(UnixAddr::Abstract("echo".to_owned())));
+    assert_eq!((options.file.as_str(), options.sessions, options.timing), ("sessions.jsonl", vec![3, 4], true));
+    assert_eq!(parse(&["f", "localhost:8080", "--timeout", "0.5"]).unwrap().timeout, Duration::from_millis(500));
+
+    assert_eq!(parse(&["--help"]), Err(USAGE.to_owned()));
+    assert!(parse(&["sessions.jsonl"]).is_err());
+    assert!(parse(&["f", "localhost", "--timeout", "0"]).is_err());
+    assert!(parse(&["f", "localhost:8080", "--timeout", "0"]).is_err());
+    assert!(parse(&["f", "localhost:8080", "--session", "x"]).is_err());
+}
+
+#[test]
+fn record_and_replay_test() {
+    use std::io::BufReader;
+
+    let path: std::path::PathBuf = std::env::temp_dir().join(format!("tcp-echo-server-record-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config: crate::config::Config = crate::config::Config { record: Some(path.to_string_lossy().into_owned()), ..crate::config::Config::default() };
+    let server: crate::ServerHandle = crate::EchoServer::builder().config(config).bind("127.0.0.1:0,protocol=line").spawn().unwrap();
+
+    let mut client: TcpStream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let mut reader: BufReader<TcpStream> = BufReader::new(client.try_clone().unwrap());
+    for (command, reply) in [("PING\n", "PONG\n"), ("ECHO hello there\n", "hello there\n")] {
+        client.write_all(command.as_bytes()).unwrap();
+        let mut line: String = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, reply);
+    }
+    client.shutdown(Shutdown::Write).unwrap();
+    assert_eq!(reader.read_line(&mut String::new()).unwrap(), 0);
+    drop((client, reader));
+    // The session is closed, and its last line written, once the server is done with it.
+    server.shutdown();
+
+    let sessions: Vec<Session> = read_sessions(BufReader::new(std::fs::File::open(&path).unwrap())).unwrap();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(sessions[0].protocol, "line");
+    assert_eq!(sessions[0].totals(), (22, 17));
+    assert!(matches!(sessions[0].steps.last(), Some(Step::CloseWrite { .. })));
+
+    let line: crate::ServerHandle = crate::EchoServer::builder().bind("127.0.0.1:0,protocol=line").spawn().unwrap();
+    let raw: crate::ServerHandle = crate::EchoServer::builder().bind("127.0.0.1:0").spawn().unwrap();
+    let options = |server: &crate::ServerHandle| ReplayOptions {
+        file: path.to_string_lossy().into_owned(),
+        target: Target::Tcp(server.local_addr().unwrap().to_string()),
+        sessions: Vec::new(),
+        timing: false,
+        timeout: Duration::from_secs(2),
+    };
+    assert!(replay(&sessions[0], &options(&line)).unwrap().is_empty());
+    assert!(run(&options(&line)).unwrap());
+
+    let differences: Vec<Difference> = replay(&sessions[0], &options(&raw)).unwrap();
+    assert_eq!(differences[0], Difference::Reply { index: 1, expected: b"PONG\n".to_vec(), actual: b"PING\n".to_vec() });
+    assert_eq!(differences[0].to_string(), "reply 1 differs at byte 1: expected \"ONG\\n\", got \"ING\\n\"");
+    // "ECHO hello there\n" comes back whole, one byte longer than the reply it's held against.
+    assert_eq!(differences[1].to_string(), "reply 2 differs at byte 0: expected \"hello there\\n\", got \"ECHO hello t\"");
+    assert_eq!(differences[2], Difference::Extra(b"here\n".to_vec()));
+    assert!(!run(&options(&raw)).unwrap());
+    assert!(run(&ReplayOptions { sessions: vec![7], ..options(&line) }).is_err());
+
+    line.shutdown();
+    raw.shutdown();
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,805 @@
+//! The echo server itself: `EchoServer::builder()` binds every socket up front and then
+//! serves them from background threads until the returned `ServerHandle` shuts it down.
+
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::access_log::{AccessLog, CloseReason, ConnectionRecord};
+use crate::activation::{self, Activated, InheritedListener, InheritedSocket};
+use crate::broadcast::{self, Room};
+use crate::config::{Config, IoModel, Mode, Protocol};
+use crate::event_loop;
+use crate::framing::{self, FrameCodec, FrameError};
+use crate::http::{self, HttpLimits};
+use crate::limits::{self, Admission, RejectReason};
+use crate::line_protocol::{self, Detected};
+use crate::listen::{self, Acceptor, ListenAddr, ListenSpec};
+use crate::metrics;
+use crate::pool::{Job, WorkerPool};
+use crate::proxy_protocol::{self, ProxyHeader, ProxyMode, Proxied};
+use crate::recording::Recorder;
+use crate::relay::{self, RelayOptions};
+use crate::shutdown::{self, ConnectionTracker, DrainSummary, Shutdown, TrackedConnection};
+use crate::socket_options;
+use crate::splice;
+use crate::stats::{ActiveConnection, Counted, Stats};
+use crate::stream::ClientStream;
+use crate::timeouts::{TimedStream, TimeoutError, Timeouts};
+use crate::udp::{self, UdpStats};
+use crate::websocket;
+
+/// Entry point of the library, see `EchoServer::builder`.
+pub struct EchoServer;
+
+impl EchoServer {
+    /// Starts from the same defaults as the command line: raw echo on 127.0.0.1:8080, one
+    /// thread per connection. Tests usually want `bind("127.0.0.1:0")` instead.
+    pub fn builder() -> EchoServerBuilder {
+        EchoServerBuilder::default()
+    }
+}
+
+/// Collects the configuration of a server; nothing is bound before `spawn`.
+#[derive(Debug, Clone, Default)]
+pub struct EchoServerBuilder {
+    config: Config,
+    /// The first `bind` or `udp` address that didn't parse, reported by `spawn`.
+    invalid: Option<String>,
+    /// Sockets passed by a service manager, served instead of binding.
+    inherited: Vec<InheritedSocket>,
+}
+
+impl EchoServerBuilder {
+    /// Replaces everything set so far, e.g. with a `Config` parsed from the command line.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a listener, written like `--listen`: `127.0.0.1:0`, `[::1]:8080,protocol=line`,
+    /// `unix:/tmp/echo.sock`. Port 0 gets a free port, see `ServerHandle::local_addr`.
+    pub fn bind(mut self, addr: &str) -> Self {
+        match addr.parse::<ListenSpec>() {
+            Ok(spec) => self.config.listen.push(spec),
+            Err(()) => {
+                self.invalid.get_or_insert(format!("invalid listen address '{}'", addr));
+            }
+        }
+        self
+    }
+
+    /// Also echoes UDP datagrams on `addr`.
+    pub fn udp(mut self, addr: &str) -> Self {
+        match addr.parse::<SocketAddr>() {
+            Ok(addr) => self.config.udp.push(addr),
+            Err(_) => {
+                self.invalid.get_or_insert(format!("invalid udp address '{}'", addr));
+            }
+        }
+        self
+    }
+
+    /// Relays every client to `addr`, `host:port`, instead of echoing.
+    pub fn upstream(mut self, addr: &str) -> Self {
+        match addr.parse() {
+            Ok(upstream) => self.config.relay.upstream = Some(upstream),
+            Err(()) => {
+                self.invalid.get_or_insert(format!("invalid upstream address '{}'", addr));
+            }
+        }
+        self
+    }
+
+    /// Protocol of the listeners that don't pick their own.
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.config.protocol = protocol;
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.config.mode = mode;
+        self
+    }
+
+    pub fn io_model(mut self, io_model: IoModel) -> Self {
+        self.config.io_model = io_model;
+        self
+    }
+
+    /// How long live connections get to finish once `shutdown` is called.
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period = grace_period;
+        self
+    }
+
+    /// Echoes raw connections with `splice`, see the splice module.
+    pub fn zero_copy(mut self, zero_copy: bool) -> Self {
+        self.config.zero_copy = zero_copy;
+        self
+    }
+
+    /// Serves these sockets instead of binding the `bind` and `udp` addresses, see the
+    /// activation module; nothing changes if there are none.
+    pub fn inherit(mut self, sockets: Vec<InheritedSocket>) -> Self {
+        self.inherited = sockets;
+        self
+    }
+
+    /// Binds every listener, UDP socket and the metrics endpoint, then serves them on
+    /// background threads. Fails if the configuration is inconsistent or anything can't be bound.
+    pub fn spawn(self) -> std::io::Result<ServerHandle> {
+        let invalid_input = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, message);
+        if let Some(invalid) = self.invalid {
+            return Err(invalid_input(invalid));
+        }
+        let mut config: Config = self.config;
+        let activated: Option<Activated> = match self.inherited.is_empty() {
+            true => None,
+            false => Some(activation::activate(&self.inherited)?),
+        };
+        if let Some(activated) = &activated {
+            // Whatever `--listen` and `--udp` say, the parsed config has its default listener at least.
+            println!("serving {} socket(s) passed by the service manager instead of --listen and --udp", self.inherited.len());
+            config.listen = activated.listeners.iter().map(|(spec, _)| spec.clone()).collect();
+            config.udp = activated.udp.iter().map(|socket| socket.local_addr()).collect::<std::io::Result<_>>()?;
+        }
+        config.validate().map_err(|err| invalid_input(err.to_string()))?;
+
+        let (listeners, udp_sockets): (Listeners, Vec<std::net::UdpSocket>) = match activated {
+            None => {
+                let listeners: Listeners = match config.io_model {
+                    IoModel::Thread => Listeners::Thread(bind_acceptors(&config)?),
+                    IoModel::Epoll => Listeners::Epoll(config.listen.iter().map(|spec| bind_context(spec, listen::bind_std(spec, config.listener, &config.socket))).collect::<std::io::Result<_>>()?),
+                };
+                let udp_sockets: Vec<std::net::UdpSocket> = config
+                    .udp
+                    .iter()
+                    .map(|addr| std::net::UdpSocket::bind(addr).map_err(|err| std::io::Error::new(err.kind(), format!("couldn't bind to udp {}: {}", addr, err))))
+                    .collect::<std::io::Result<_>>()?;
+                (listeners, udp_sockets)
+            }
+            Some(activated) => {
+                if config.acceptors > 1 {
+                    return Err(invalid_input("--acceptors binds listeners of its own, which it can't next to sockets passed by a service manager".to_owned()));
+                }
+                // The default listener `validate` adds when only UDP sockets were passed isn't served.
+                config.listen.truncate(activated.listeners.len());
+                (inherited_listeners(activated.listeners, &config)?, activated.udp)
+            }
+        };
+        let listen_addrs: Vec<ListenAddr> = listeners.local_addrs()?;
+        for ((spec, addr), fd) in config.listen.iter().zip(&listen_addrs).zip(listeners.raw_fds()) {
+            println!("listening on {}", ListenSpec { addr: addr.clone(), ..spec.clone() });
+            if !spec.is_unix() && !config.socket.is_default() {
+                println!("socket options on {}: {}", addr, socket_options::describe(fd)?);
+            }
+        }
+        if config.acceptors > 1 {
+            println!("accepting from {} threads", config.acceptors);
+        }
+
+        let access_log: Arc<AccessLog> = Arc::new(AccessLog::open(&config.access_log).map_err(|err| std::io::Error::new(err.kind(), format!("couldn't open access log: {}", err)))?);
+        let recorder: Arc<Recorder> = Arc::new(Recorder::open(config.record.as_deref()).map_err(|err| std::io::Error::new(err.kind(), format!("couldn't open session record: {}", err)))?);
+        let udp_addrs: Vec<SocketAddr> = udp_sockets.iter().map(|socket| socket.local_addr()).collect::<std::io::Result<_>>()?;
+        for addr in &udp_addrs {
+            println!("listening on udp {}", addr);
+        }
+        let metrics_listener: Option<std::net::TcpListener> = match config.metrics {
+            Some(addr) => Some(std::net::TcpListener::bind(addr).map_err(|err| std::io::Error::new(err.kind(), format!("couldn't bind metrics endpoint to {}: {}", addr, err)))?),
+            None => None,
+        };
+        let metrics_addr: Option<SocketAddr> = metrics_listener.as_ref().map(|listener| listener.local_addr()).transpose()?;
+        if let Some(addr) = metrics_addr {
+            println!("serving metrics on http://{}/metrics", addr);
+        }
+
+        let stats: Arc<Stats> = Arc::new(Stats::default());
+        let udp_stats: Arc<UdpStats> = Arc::new(UdpStats::default());
+        let admission: Arc<Admission> = Arc::new(Admission::new(&config.admission));
+        let shutdown: Shutdown = Shutdown::default();
+
+        let thread: JoinHandle<DrainSummary> = {
+            let (stats, udp_stats, shutdown) = (stats.clone(), udp_stats.clone(), shutdown.clone());
+            std::thread::spawn(move || {
+                let udp_handles: Vec<JoinHandle<()>> = udp_sockets.into_iter().map(|socket| spawn_udp(socket, &config, &udp_stats, &shutdown)).collect();
+                let metrics_handle: Option<JoinHandle<()>> = metrics_listener.map(|listener| spawn_metrics(listener, &stats, &udp_stats, &shutdown));
+
+                let summary: DrainSummary = match listeners {
+                    Listeners::Thread(listeners) => thread_per_connection(listeners, &config, &stats, &access_log, &recorder, &admission, &shutdown),
+                    Listeners::Epoll(listeners) => event_loop::serve(listeners, &config, &stats, &access_log, &admission, &shutdown).unwrap_or_else(|err| {
+                        eprintln!("{:?}", err);
+                        DrainSummary::default()
+                    }),
+                };
+
+                // The TCP side only returns once a shutdown was requested, which the UDP threads see as well.
+                for handle in udp_handles.into_iter().chain(metrics_handle) {
+                    let _ = handle.join();
+                }
+
+                println!("stats: {}", stats);
+                if !config.udp.is_empty() {
+                    println!("udp stats: {}", udp_stats);
+                }
+                summary
+            })
+        };
+
+        Ok(ServerHandle { listen_addrs, udp_addrs, metrics_addr, stats, udp_stats, shutdown, thread: Some(thread) })
+    }
+}
+
+/// A running server. Dropping the handle asks the server to shut down without waiting for it.
+pub struct ServerHandle {
+    listen_addrs: Vec<ListenAddr>,
+    udp_addrs: Vec<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    stats: Arc<Stats>,
+    udp_stats: Arc<UdpStats>,
+    shutdown: Shutdown,
+    thread: Option<JoinHandle<DrainSummary>>,
+}
+
+impl ServerHandle {
+    /// Address of the first TCP listener, with the actual port if it was bound to port 0.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.listen_addrs.iter().find_map(|addr| match addr {
+            ListenAddr::Tcp(addr) => Some(*addr),
+            ListenAddr::Unix(_) => None,
+        })
+    }
+
+    /// Every listener's address, in the order they were added.
+    pub fn listen_addrs(&self) -> &[ListenAddr] {
+        &self.listen_addrs
+    }
+
+    pub fn udp_addrs(&self) -> &[SocketAddr] {
+        &self.udp_addrs
+    }
+
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    /// Connection counters, updated live.
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    pub fn udp_stats(&self) -> &Arc<UdpStats> {
+        &self.udp_stats
+    }
+
+    /// Stops accepting, gives live connections the grace period to finish and waits until
+    /// everything is closed.
+    pub fn shutdown(self) -> DrainSummary {
+        self.shutdown.request();
+        self.wait()
+    }
+
+    /// Waits for the server to stop on its own, i.e. on SIGINT or SIGTERM once
+    /// `shutdown::install_signal_handlers` ran.
+    pub fn wait(mut self) -> DrainSummary {
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(summary)) => summary,
+            Some(Err(_)) => {
+                eprintln!("server thread panicked");
+                DrainSummary::default()
+            }
+            None => DrainSummary::default(),
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        self.shutdown.request();
+    }
+}
+
+/// A `--listen` target and what it was bound to.
+type BoundListener = (ListenSpec, Box<dyn Acceptor>);
+
+/// Listeners bound for one of the I/O models.
+enum Listeners {
+    /// One set per accept thread: the first has every listener, the others a listener of
+    /// their own on each TCP address.
+    Thread(Vec<Vec<BoundListener>>),
+    Epoll(Vec<std::net::TcpListener>),
+}
+
+impl Listeners {
+    /// Addresses of the listeners, one per `--listen`.
+    fn local_addrs(&self) -> std::io::Result<Vec<ListenAddr>> {
+        match self {
+            Listeners::Thread(acceptors) => acceptors[0].iter().map(|(_, listener)| listener.local_addr()).collect(),
+            Listeners::Epoll(listeners) => listeners.iter().map(|listener| Ok(ListenAddr::Tcp(listener.local_addr()?))).collect(),
+        }
+    }
+
+    fn raw_fds(&self) -> Vec<RawFd> {
+        match self {
+            Listeners::Thread(acceptors) => acceptors[0].iter().map(|(_, listener)| listener.as_raw_fd()).collect(),
+            Listeners::Epoll(listeners) => listeners.iter().map(|listener| listener.as_raw_fd()).collect(),
+        }
+    }
+}
+
+/// Binds the listeners of every accept thread. The extra threads bind the ports the first
+/// one got, which matters for port 0; `SO_REUSEPORT` lets them share.
+fn bind_acceptors(config: &Config) -> std::io::Result<Vec<Vec<BoundListener>>> {
+    let bind = |spec: &ListenSpec| -> std::io::Result<BoundListener> { Ok((spec.clone(), bind_context(spec, listen::bind(spec, config.listener, &config.socket))?)) };
+    let first: Vec<BoundListener> = config.listen.iter().map(bind).collect::<std::io::Result<_>>()?;
+
+    let mut acceptors: Vec<Vec<BoundListener>> = Vec::with_capacity(config.acceptors);
+    for _ in 1..config.acceptors {
+        let listeners: Vec<BoundListener> = first
+            .iter()
+            .filter(|(spec, _)| !spec.is_unix())
+            .map(|(spec, listener)| bind(&ListenSpec { addr: listener.local_addr()?, ..spec.clone() }))
+            .collect::<std::io::Result<_>>()?;
+        acceptors.push(listeners);
+    }
+    acceptors.insert(0, first);
+    Ok(acceptors)
+}
+
+/// Listeners for the I/O model made from inherited sockets, with `config.listen` as their
+/// targets, which `validate` completed.
+fn inherited_listeners(inherited: Vec<(ListenSpec, InheritedListener)>, config: &Config) -> std::io::Result<Listeners> {
+    let listeners = config.listen.iter().cloned().zip(inherited.into_iter().map(|(_, listener)| listener));
+    match config.io_model {
+        IoModel::Thread => Ok(Listeners::Thread(vec![listeners
+            .map(|(spec, listener)| -> BoundListener {
+                match listener {
+                    InheritedListener::Tcp(listener) => (spec, Box::new(listener)),
+                    InheritedListener::Unix(listener) => (spec, Box::new(listener)),
+                }
+            })
+            .collect()])),
+        IoModel::Epoll => listeners
+            .map(|(spec, listener)| match listener {
+                InheritedListener::Tcp(listener) => Ok(listener),
+                InheritedListener::Unix(_) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("--io epoll can't serve {}", spec))),
+            })
+            .collect::<std::io::Result<_>>()
+            .map(Listeners::Epoll),
+    }
+}
+
+fn bind_context<T>(spec: &ListenSpec, bound: std::io::Result<T>) -> std::io::Result<T> {
+    bound.map_err(|err| std::io::Error::new(err.kind(), format!("couldn't bind to {}: {}", spec, err)))
+}
+
+fn spawn_udp(socket: std::net::UdpSocket, config: &Config, stats: &Arc<UdpStats>, shutdown: &Shutdown) -> JoinHandle<()> {
+    let (options, stats, shutdown) = (config.udp_options.clone(), stats.clone(), shutdown.clone());
+    std::thread::spawn(move || udp::serve(socket, &options, &stats, &shutdown).unwrap_or_else(|err| eprintln!("{:?}", err)))
+}
+
+/// Serves the metrics endpoint next to the echo listeners, on its own thread.
+fn spawn_metrics(listener: std::net::TcpListener, stats: &Arc<Stats>, udp_stats: &Arc<UdpStats>, shutdown: &Shutdown) -> JoinHandle<()> {
+    let (stats, udp_stats, shutdown) = (stats.clone(), udp_stats.clone(), shutdown.clone());
+    std::thread::spawn(move || metrics::serve(listener, &stats, &udp_stats, &shutdown).unwrap_or_else(|err| eprintln!("{:?}", err)))
+}
+
+/// Where accepted connections are handed to.
+enum Dispatch {
+    /// A new thread per connection.
+    Thread,
+    Pool(WorkerPool),
+}
+
+/// Serves every client from its own thread (or a pool thread) until a shutdown is requested,
+/// then drains the live connections. Clients are accepted by one loop per set of listeners,
+/// each on a thread of its own but the first.
+fn thread_per_connection(
+    acceptors: Vec<Vec<BoundListener>>,
+    config: &Config,
+    stats: &Arc<Stats>,
+    access_log: &Arc<AccessLog>,
+    recorder: &Arc<Recorder>,
+    admission: &Arc<Admission>,
+    shutdown: &Shutdown,
+) -> DrainSummary {
+    let tracker: ConnectionTracker = ConnectionTracker::default();
+    let dispatch: Dispatch = match config.pool_size {
+        0 => Dispatch::Thread,
+        size => Dispatch::Pool(WorkerPool::new(size, config.accept_queue)),
+    };
+    let room: Option<Arc<Room>> = (config.mode == Mode::Broadcast).then(|| Arc::new(Room::default()));
+
+    std::thread::scope(|scope| {
+        let mut acceptors = acceptors.into_iter();
+        let first: Vec<BoundListener> = acceptors.next().unwrap_or_default();
+        for listeners in acceptors {
+            let (dispatch, tracker, room) = (&dispatch, &tracker, room.as_ref());
+            scope.spawn(move || accept_loop(listeners, config, dispatch, tracker, room, stats, access_log, recorder, admission, shutdown));
+        }
+        accept_loop(first, config, &dispatch, &tracker, room.as_ref(), stats, access_log, recorder, admission, shutdown);
+    });
+
+    println!("shutting down, draining {} connection(s)", tracker.live());
+    let summary: DrainSummary = tracker.drain(config.grace_period);
+    if let Dispatch::Pool(pool) = dispatch {
+        pool.join();
+    }
+    summary
+}
+
+/// Accepts from `listeners` until a shutdown is requested, then closes them. This is also
+/// where clients are checked against the allow and deny lists and rate limits, except on
+/// proxy listeners, where that has to wait for the PROXY header to name the client.
+#[allow(clippy::too_many_arguments)]
+fn accept_loop(
+    listeners: Vec<BoundListener>,
+    config: &Config,
+    dispatch: &Dispatch,
+    tracker: &ConnectionTracker,
+    room: Option<&Arc<Room>>,
+    stats: &Arc<Stats>,
+    access_log: &Arc<AccessLog>,
+    recorder: &Arc<Recorder>,
+    admission: &Arc<Admission>,
+    shutdown: &Shutdown,
+) {
+    let fds: Vec<RawFd> = listeners.iter().map(|(_, listener)| listener.as_raw_fd()).collect();
+
+    while !shutdown.is_requested() {
+        let ready: Vec<RawFd> = match shutdown::wait_readable(&fds, shutdown::POLL_INTERVAL) {
+            Err(e) => {
+                eprintln!("{}", e);
+                // The other accept loops stop too, rather than serving on with fewer listeners.
+                shutdown.request();
+                break;
+            }
+            Ok(ready) => ready,
+        };
+
+        for (spec, listener) in listeners.iter().filter(|(_, listener)| ready.contains(&listener.as_raw_fd())) {
+            let stream: Box<dyn ClientStream> = match listener.accept_stream() {
+                Err(e) => {
+                    stats.add_error(e.kind());
+                    eprintln!("{}", e);
+                    continue;
+                }
+                Ok(stream) => stream,
+            };
+            if let (false, Some(fd)) = (spec.is_unix(), stream.raw_fd()) {
+                if let Err(e) = config.socket.apply_stream(fd) {
+                    eprintln!("couldn't set socket options: {}", e);
+                }
+            }
+            if spec.proxy != ProxyMode::Off {
+                dispatch_client(stream, spec, config, dispatch, tracker, room, stats, access_log, recorder, admission);
+            } else if let Some(stream) = admission.admit(stream, stats) {
+                dispatch_client(stream, spec, config, dispatch, tracker, room, stats, access_log, recorder, admission);
+            }
+        }
+    }
+    // Dropping the listeners stops accepting before the live connections are waited for.
+}
+
+/// Hands a client to a thread. Clients of proxy listeners are admitted there, once their
+/// PROXY header is read; everybody else already was.
+#[allow(clippy::too_many_arguments)]
+fn dispatch_client(
+    stream: Box<dyn ClientStream>,
+    spec: &ListenSpec,
+    config: &Config,
+    dispatch: &Dispatch,
+    tracker: &ConnectionTracker,
+    room: Option<&Arc<Room>>,
+    stats: &Arc<Stats>,
+    access_log: &Arc<AccessLog>,
+    recorder: &Arc<Recorder>,
+    admission: &Arc<Admission>,
+) {
+    // Counted as active from now on, so connections waiting in the pool queue count towards
+    // the limit; as accepted only once served, as they may not fit into the queue or, behind
+    // a proxy, be turned away once their PROXY header names the client.
+    let active: ActiveConnection = match stats.reserve(config.max_connections) {
+        Some(active) => active,
+        None => {
+            limits::reject(stream, RejectReason::ConnectionLimit, stats);
+            return;
+        }
+    };
+    let tracked: TrackedConnection = match tracker.track(&*stream) {
+        Ok(tracked) => tracked,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let job_stats: Arc<Stats> = stats.clone();
+    let job_log: Arc<AccessLog> = access_log.clone();
+    let job_recorder: Arc<Recorder> = recorder.clone();
+    let job_room: Option<Arc<Room>> = room.cloned();
+    let job_admission: Arc<Admission> = admission.clone();
+    let (protocol, proxy): (Protocol, ProxyMode) = (spec.protocol(), spec.proxy);
+    let relay: RelayOptions = config.relay.clone();
+    let timeouts: Timeouts = config.timeouts;
+    let codec: FrameCodec = FrameCodec::new(config.max_frame);
+    let http_limits: HttpLimits = config.http;
+    let zero_copy: bool = config.zero_copy;
+    let busy: Option<Box<dyn ClientStream>> = match dispatch {
+        Dispatch::Pool(_) => stream.try_clone_stream().ok(),
+        Dispatch::Thread => None,
+    };
+
+    let job: Job = Box::new(move || {
+        let _tracked: TrackedConnection = tracked;
+        let _active: ActiveConnection = active;
+        let (stream, proxied): (Box<dyn ClientStream>, Option<ProxiedBy>) = match proxy {
+            ProxyMode::Off => (stream, None),
+            mode => match admit_proxied(stream, mode, &job_admission, &job_stats) {
+                Some(admitted) => admitted,
+                None => return,
+            },
+        };
+        job_stats.count_accepted();
+        handle_client(stream, proxied, protocol, timeouts, codec, http_limits, zero_copy, job_room.as_deref(), &relay, &job_stats, &job_log, &job_recorder).unwrap_or_else(|err| eprintln!("{:?}", err));
+    });
+
+    match dispatch {
+        Dispatch::Thread => {
+            std::thread::spawn(job);
+        }
+        Dispatch::Pool(pool) => {
+            if let Err(job) = pool.try_execute(job) {
+                // Dropping the job releases its slot before the busy reply goes out.
+                drop(job);
+                if let Some(stream) = busy {
+                    limits::reject(stream, RejectReason::AcceptQueueFull, stats);
+                }
+            }
+        }
+    }
+}
+
+/// Where a client of a proxy listener connected to, from its PROXY header, and the proxy's name.
+type ProxiedBy = (SocketAddr, String);
+
+/// Reads the PROXY header of a client of a proxy listener and checks the client it names
+/// against the allow and deny lists and rate limits. Returns the stream to serve, along
+/// with the destination from the header and the proxy's own name.
+fn admit_proxied(
+    mut stream: Box<dyn ClientStream>,
+    mode: ProxyMode,
+    admission: &Admission,
+    stats: &Stats,
+) -> Option<(Box<dyn ClientStream>, Option<ProxiedBy>)> {
+    let proxy: String = stream.peer_name().unwrap_or_else(|_| "unknown".to_owned());
+    let admitted: Option<(Box<dyn ClientStream>, Option<ProxyHeader>)> = match proxy_protocol::read_header(&mut *stream, mode) {
+        Ok((header, prefix)) => {
+            if let Some(header) = header {
+                println!("PROXY header from {}: {}", proxy, header);
+            }
+            admission.admit(Box::new(Proxied::new(stream, header, prefix)), stats).map(|stream| (stream, header))
+        }
+        Err(err) => {
+            eprintln!("{}: {}", proxy, err);
+            limits::reject(stream, RejectReason::ProxyHeader, stats);
+            None
+        }
+    };
+    admitted.map(|(stream, header)| (stream, header.map(|header| (header.destination, proxy))))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_client(
+    stream: Box<dyn ClientStream>,
+    proxied: Option<ProxiedBy>,
+    protocol: Protocol,
+    timeouts: Timeouts,
+    codec: FrameCodec,
+    http_limits: HttpLimits,
+    zero_copy: bool,
+    room: Option<&Room>,
+    relay: &RelayOptions,
+    stats: &Stats,
+    access_log: &AccessLog,
+    recorder: &Arc<Recorder>,
+) -> Result<(), std::io::Error> {
+    let peer: String = stream.peer_name()?;
+    println!("Handling client with IP: {}", peer);
+    let stream: Box<dyn ClientStream> = recorder.record(stream, &peer, protocol);
+    let connected_at: SystemTime = SystemTime::now();
+    let started: Instant = Instant::now();
+    // In broadcast and relay mode, replies go out from a thread of their own, through a clone.
+    let mut replies: Option<Counted<TimedStream<Box<dyn ClientStream>>>> = match room.is_some() || relay.upstream.is_some() {
+        true => Some(Counted::new(TimedStream::new(stream.try_clone_stream()?, timeouts)?, stats)),
+        false => None,
+    };
+    let mut stream: Counted<TimedStream<Box<dyn ClientStream>>> = Counted::new(TimedStream::new(stream, timeouts)?, stats);
+    let mut messages: u64 = 0;
+
+    let result: std::io::Result<CloseReason> = match (room, replies.as_mut()) {
+        (Some(room), Some(replies)) => broadcast::serve(&mut stream, replies, room, &peer, &mut messages),
+        (None, Some(replies)) => relay::serve(&mut stream, replies, &peer, relay, &mut messages),
+        _ => match protocol {
+            Protocol::Raw if zero_copy => splice::echo(&mut stream, &mut messages).and_then(|close| match close {
+                Some(close) => Ok(close),
+                None => raw_echo(&mut stream, &mut messages),
+            }),
+            Protocol::Raw => raw_echo(&mut stream, &mut messages),
+            Protocol::Line => line_protocol::detect(&mut stream).and_then(|detected| match detected {
+                Detected::Commands(buffered) => line_protocol::serve(&mut stream, buffered, stats, &peer, proxied.as_ref().map(|(destination, _)| *destination), &mut messages),
+                Detected::Raw(buffered) => match echo_chunk(&mut stream, &buffered, &mut messages)? {
+                    Some(close) => Ok(close),
+                    None => raw_echo(&mut stream, &mut messages),
+                },
+            }),
+            Protocol::Framed => framing::serve(&mut stream, codec, &mut messages),
+            Protocol::Http => http::serve(&mut stream, http_limits, &mut messages),
+            Protocol::WebSocket => websocket::serve(&mut stream, http_limits, codec.max_frame(), &mut messages),
+        },
+    };
+
+    let (bytes_in, mut bytes_out): (u64, u64) = stream.totals();
+    bytes_out += replies.map_or(0, |replies| replies.totals().1);
+    access_log.record(&ConnectionRecord {
+        peer: peer.clone(),
+        proxy: proxied,
+        protocol,
+        connected_at,
+        duration: started.elapsed(),
+        bytes_in,
+        bytes_out,
+        messages,
+        close: match &result {
+            Ok(close) => close.clone(),
+            Err(err) => CloseReason::from_error(err),
+        },
+    });
+
+    let err: std::io::Error = match result {
+        Ok(_) => return Ok(()),
+        Err(err) => err,
+    };
+    stats.add_error(err.kind());
+    // Running out of time is how these connections are supposed to end, not an error.
+    if let Some(timeout) = TimeoutError::from_io(&err) {
+        println!("closing {}: {}", peer, timeout);
+        return Ok(());
+    }
+    if let Some(frame_error) = err.get_ref().and_then(|err| err.downcast_ref::<FrameError>()) {
+        println!("closing {}: {}", peer, frame_error);
+        return Ok(());
+    }
+    Err(err)
+}
+
+/// Echoes every chunk back, counting them in `messages`, until EOF or a chunk starting with `bye`.
+fn raw_echo<S: Read + Write>(stream: &mut S, messages: &mut u64) -> Result<CloseReason, std::io::Error> {
+    loop {
+        let mut buf = [0; 512];
+        let bytes_read: usize = stream.read(&mut buf)?;
+        if bytes_read == 0 { return Ok(CloseReason::Eof); }
+        if let Some(close) = echo_chunk(stream, &buf[..bytes_read], messages)? {
+            return Ok(close);
+        }
+    }
+}
+
+/// Echoes one chunk of the raw echo, returning how the session ends if it does. An empty
+/// chunk, which the raw echo never reads, is skipped.
+fn echo_chunk<S: Write>(stream: &mut S, chunk: &[u8], messages: &mut u64) -> Result<Option<CloseReason>, std::io::Error> {
+    if chunk.is_empty() {
+        return Ok(None);
+    }
+    if String::from_utf8_lossy(chunk).starts_with("bye") {
+        stream.write_all("bye".as_bytes())?;
+        *messages += 1;
+        return Ok(Some(CloseReason::Bye));
+    }
+    stream.write_all(chunk)?;
+    *messages += 1;
+    Ok(None)
+}
+
+#[test]
+fn echo_server_builder_test() {
+    use std::sync::atomic::Ordering;
+
+    let first: ServerHandle = EchoServer::builder().bind("127.0.0.1:0").spawn().unwrap();
+    let second: ServerHandle = EchoServer::builder().bind("127.0.0.1:0").io_model(IoModel::Epoll).spawn().unwrap();
+    let addr: SocketAddr = first.local_addr().unwrap();
+    assert_ne!(addr.port(), 0);
+    assert_ne!(addr, second.local_addr().unwrap());
+
+    for server in [&first, &second] {
+        let mut client: std::net::TcpStream = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut reply: [u8; 5] = [0; 5];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+    }
+    assert_eq!(first.stats().accepted.load(Ordering::Relaxed), 1);
+    assert_eq!(first.stats().bytes_in.load(Ordering::Relaxed), 5);
+
+    let started: Instant = Instant::now();
+    let summary: DrainSummary = first.shutdown();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(summary.drained + summary.aborted, 0);
+    second.shutdown();
+
+    assert!(EchoServer::builder().bind("nowhere").spawn().is_err());
+    assert!(EchoServer::builder().bind("127.0.0.1:0,protocol=line").io_model(IoModel::Epoll).spawn().is_err());
+}
+
+#[test]
+fn line_listener_raw_fallback_test() {
+    let server: ServerHandle = EchoServer::builder().bind("127.0.0.1:0,protocol=line").spawn().unwrap();
+    let connect = || std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+
+    // No newline to wait for: the first bytes already can't be a command.
+    let mut raw: std::net::TcpStream = connect();
+    raw.write_all(b"hello").unwrap();
+    let mut reply: [u8; 5] = [0; 5];
+    raw.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"hello");
+    raw.write_all(b"PING\n").unwrap();
+    raw.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"PING\n");
+
+    let mut line: std::net::TcpStream = connect();
+    line.write_all(b"PING\nECHO hi\n").unwrap();
+    let mut reply: [u8; 8] = [0; 8];
+    line.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"PONG\nhi\n");
+    server.shutdown();
+}
+
+#[test]
+fn reuse_port_acceptors_test() {
+    use crate::socket_options::SocketOptions;
+
+    let config: Config = Config { acceptors: 3, socket: SocketOptions { nodelay: true, ..SocketOptions::default() }, ..Config::default() };
+    let server: ServerHandle = EchoServer::builder().config(config).bind("127.0.0.1:0").spawn().unwrap();
+
+    // The kernel spreads the clients over the three listeners by hash; all of them are served.
+    for n in 0..12u8 {
+        let mut client: std::net::TcpStream = std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(&[n]).unwrap();
+        let mut reply: [u8; 1] = [0];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [n]);
+    }
+    assert_eq!(server.stats().accepted.load(std::sync::atomic::Ordering::Relaxed), 12);
+    server.shutdown();
+}
+
+#[test]
+fn accept_queue_full_test() {
+    use std::sync::atomic::Ordering;
+
+    // One pool thread, busy with the first client once it echoes; the second waits in the
+    // queue and the third finds it full.
+    let config: Config = Config { pool_size: 1, accept_queue: 1, ..Config::default() };
+    let server: ServerHandle = EchoServer::builder().config(config).bind("127.0.0.1:0").spawn().unwrap();
+    let connect = || std::net::TcpStream::connect(server.local_addr().unwrap()).unwrap();
+    let mut first: std::net::TcpStream = connect();
+    first.write_all(b"hello").unwrap();
+    first.read_exact(&mut [0; 5]).unwrap();
+    let mut second: std::net::TcpStream = connect();
+    let mut third: std::net::TcpStream = connect();
+    let mut reply: String = String::new();
+    third.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "ERR busy, try again later\n");
+    assert_eq!(server.stats().rejections.lock().unwrap().get("accept_queue_full"), Some(&1));
+    // Queued or turned away, neither counts as accepted yet; the third never will.
+    assert_eq!(server.stats().accepted.load(Ordering::Relaxed), 1);
+
+    drop(first);
+    second.write_all(b"hello").unwrap();
+    second.read_exact(&mut [0; 5]).unwrap();
+    assert_eq!(server.stats().accepted.load(Ordering::Relaxed), 2);
+    drop(second);
+    server.shutdown();
+}
@@ -0,0 +1,102 @@
+//! SHA-1, as needed for the WebSocket handshake; not for anything that needs it to be secure.
+
+pub const DIGEST_LEN: usize = 20;
+
+/// Incremental SHA-1 (RFC 3174).
+#[derive(Debug, Clone)]
+pub struct Sha1 {
+    state: [u32; 5],
+    /// Bytes not yet processed, always less than a block.
+    block: Vec<u8>,
+    len: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Self {
+        Sha1 { state: [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0], block: Vec::with_capacity(64), len: 0 }
+    }
+}
+
+impl Sha1 {
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        while !data.is_empty() {
+            let take: usize = (64 - self.block.len()).min(data.len());
+            self.block.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.block.len() == 64 {
+                let block: Vec<u8> = std::mem::take(&mut self.block);
+                self.compress(&block);
+            }
+        }
+    }
+
+    pub fn finish(mut self) -> [u8; DIGEST_LEN] {
+        let bits: u64 = self.len * 8;
+        // A 1 bit, zeros up to 8 bytes short of a block, then the length in bits.
+        let zeros: usize = (64 + 56 - (self.block.len() + 1) % 64) % 64;
+        let mut padding: Vec<u8> = vec![0x80];
+        padding.resize(1 + zeros, 0);
+        padding.extend_from_slice(&bits.to_be_bytes());
+        let len: u64 = self.len;
+        self.update(&padding);
+        self.len = len;
+
+        let mut digest = [0; DIGEST_LEN];
+        for (chunk, word) in digest.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 80];
+        for (i, chunk) in block.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k): (u32, u32) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp: u32 = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
+
+pub fn digest(data: &[u8]) -> [u8; DIGEST_LEN] {
+    let mut sha1: Sha1 = Sha1::default();
+    sha1.update(data);
+    sha1.finish()
+}
+
+#[test]
+fn sha1_test() {
+    let hex = |digest: [u8; DIGEST_LEN]| digest.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+    assert_eq!(hex(digest(b"")), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    assert_eq!(hex(digest(b"abc")), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    assert_eq!(hex(digest(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq")), "84983e441c3bd26ebaae4aa1f95129e5e54670f1");
+
+    // The same, fed in pieces that don't line up with blocks.
+    let million: Vec<u8> = vec![b'a'; 1_000_000];
+    let mut sha1: Sha1 = Sha1::default();
+    for piece in million.chunks(999) {
+        sha1.update(piece);
+    }
+    assert_eq!(hex(sha1.finish()), "34aa973cd4c4daa4f61eeb2bdbad27316534016f");
+}
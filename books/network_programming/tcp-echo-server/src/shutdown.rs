@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::stream::ClientStream;
+
+/// How often blocking loops wake up to check whether a shutdown was requested.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Set from the signal handler, so it has to be a plain static.
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    SIGNALLED.store(true, Ordering::SeqCst);
+}
+
+/// Routes SIGINT and SIGTERM to the shutdown flag instead of killing the process.
+pub fn install_signal_handlers() -> std::io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Cloneable flag telling accept loops and workers to stop.
+/// It is raised either explicitly with `request` or by a signal once `install_signal_handlers` ran.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    flag: Arc<AtomicBool>,
+}
+
+impl Shutdown {
+    pub fn request(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.flag.load(Ordering::SeqCst) || SIGNALLED.load(Ordering::SeqCst)
+    }
+}
+
+/// Waits until one of `fds` is readable, a signal arrives or `timeout` elapses.
+/// Returns the fds that are ready.
+pub fn wait_readable(fds: &[RawFd], timeout: Duration) -> std::io::Result<Vec<RawFd>> {
+    let mut pollfds: Vec<libc::pollfd> = fds.iter().map(|&fd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 }).collect();
+    let ready: i32 = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout.as_millis() as i32) };
+    if ready < 0 {
+        let err = std::io::Error::last_os_error();
+        if err.kind() == std::io::ErrorKind::Interrupted {
+            return Ok(Vec::new());
+        }
+        return Err(err);
+    }
+    Ok(pollfds.iter().filter(|pollfd| pollfd.revents != 0).map(|pollfd| pollfd.fd).collect())
+}
+
+/// Outcome of a graceful shutdown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrainSummary {
+    /// Connections that finished on their own within the grace period.
+    pub drained: usize,
+    /// Connections that were still open when the grace period ran out and got force-closed.
+    pub aborted: usize,
+}
+
+impl std::ops::AddAssign for DrainSummary {
+    fn add_assign(&mut self, other: DrainSummary) {
+        self.drained += other.drained;
+        self.aborted += other.aborted;
+    }
+}
+
+impl std::fmt::Display for DrainSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "drained {} connection(s), aborted {}", self.drained, self.aborted)
+    }
+}
+
+#[derive(Default)]
+struct TrackerState {
+    next_id: u64,
+    live: HashMap<u64, Box<dyn ClientStream>>,
+}
+
+/// Keeps a handle on every live connection of the thread-per-connection server,
+/// so that they can be told to finish and, if they don't, be closed from outside.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<(Mutex<TrackerState>, Condvar)>,
+}
+
+/// Removes the connection from the tracker when the handling thread is done with it.
+pub struct TrackedConnection {
+    id: u64,
+    tracker: ConnectionTracker,
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        let (state, finished) = &*self.tracker.inner;
+        state.lock().unwrap().live.remove(&self.id);
+        finished.notify_all();
+    }
+}
+
+impl ConnectionTracker {
+    pub fn track(&self, stream: &dyn ClientStream) -> std::io::Result<TrackedConnection> {
+        let clone: Box<dyn ClientStream> = stream.try_clone_stream()?;
+        let mut state = self.inner.0.lock().unwrap();
+        let id: u64 = state.next_id;
+        state.next_id += 1;
+        state.live.insert(id, clone);
+
+        Ok(TrackedConnection { id, tracker: self.clone() })
+    }
+
+    pub fn live(&self) -> usize {
+        self.inner.0.lock().unwrap().live.len()
+    }
+
+    /// Shuts down the read side of every live connection, so blocked reads see EOF and the
+    /// handlers finish what they are doing. Whatever is still open after `grace` is force-closed.
+    pub fn drain(&self, grace: Duration) -> DrainSummary {
+        let (state, finished) = &*self.inner;
+        let mut state = state.lock().unwrap();
+        let initial: usize = state.live.len();
+
+        for stream in state.live.values() {
+            let _ = stream.shutdown(std::net::Shutdown::Read);
+        }
+
+        let deadline: Instant = Instant::now() + grace;
+        while !state.live.is_empty() {
+            let now: Instant = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = finished.wait_timeout(state, deadline - now).unwrap().0;
+        }
+
+        let aborted: usize = state.live.len();
+        for stream in state.live.values() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+
+        DrainSummary { drained: initial - aborted, aborted }
+    }
+}
+
+#[test]
+fn connection_tracker_drain_test() {
+    use std::io::Read;
+    use std::net::TcpStream;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let tracker: ConnectionTracker = ConnectionTracker::default();
+
+    // One client whose handler exits on EOF, one whose handler ignores it and keeps the connection.
+    let _polite = TcpStream::connect(addr).unwrap();
+    let _stubborn = TcpStream::connect(addr).unwrap();
+
+    let (mut polite, _) = listener.accept().unwrap();
+    let guard = tracker.track(&polite).unwrap();
+    std::thread::spawn(move || {
+        let _guard = guard;
+        let _ = polite.read(&mut [0; 16]);
+    });
+
+    let (stubborn, _) = listener.accept().unwrap();
+    let stubborn_guard = tracker.track(&stubborn).unwrap();
+
+    assert_eq!(tracker.live(), 2);
+    let summary: DrainSummary = tracker.drain(Duration::from_millis(200));
+    assert_eq!(summary, DrainSummary { drained: 1, aborted: 1 });
+
+    drop(stubborn_guard);
+    assert_eq!(tracker.live(), 0);
+}
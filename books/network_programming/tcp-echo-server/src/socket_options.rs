@@ -0,0 +1,129 @@
+//! Tuning of TCP sockets: `TCP_NODELAY`, keepalive probes, buffer sizes and `SO_REUSEPORT`.
+//!
+//! Everything is set on the listeners, before binding, so accepted sockets inherit it and
+//! the receive buffer is known before the window scale is negotiated. `TCP_NODELAY` and
+//! keepalive are set on every accepted stream again, as they're what clients depend on.
+
+use std::os::unix::io::RawFd;
+
+use crate::raw_listener::{get_int_option, set_int_option};
+
+/// `SO_KEEPALIVE`, with the probe timing left to the kernel (`net.ipv4.tcp_keepalive_*`)
+/// where not given.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Seconds of silence before the first probe, `TCP_KEEPIDLE`.
+    pub idle: Option<u32>,
+    /// Seconds between probes, `TCP_KEEPINTVL`.
+    pub interval: Option<u32>,
+    /// Unanswered probes before the connection is dropped, `TCP_KEEPCNT`.
+    pub count: Option<u32>,
+}
+
+/// Socket options of every TCP listener and the streams accepted from it; the defaults
+/// leave every option to the kernel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Send small writes right away instead of coalescing them (Nagle's algorithm).
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    /// `SO_RCVBUF` in bytes; the kernel doubles it for its own bookkeeping.
+    pub recv_buffer: Option<usize>,
+    /// `SO_SNDBUF` in bytes, doubled the same way.
+    pub send_buffer: Option<usize>,
+    /// Lets several sockets (threads, processes) listen on the same port, the kernel
+    /// spreading new connections over them.
+    pub reuse_port: bool,
+}
+
+impl SocketOptions {
+    /// Whether anything differs from what the kernel does anyway.
+    pub fn is_default(&self) -> bool {
+        *self == SocketOptions::default()
+    }
+
+    /// Sets everything on a listening socket; `SO_REUSEPORT` only works before `bind`.
+    pub fn apply_listener(&self, fd: RawFd) -> std::io::Result<()> {
+        if self.reuse_port {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, clamp(size))?;
+        }
+        if let Some(size) = self.send_buffer {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, clamp(size))?;
+        }
+        self.apply_stream(fd)
+    }
+
+    /// Sets what applies to a connection, on an accepted stream.
+    pub fn apply_stream(&self, fd: RawFd) -> std::io::Result<()> {
+        if self.nodelay {
+            set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_NODELAY, 1)?;
+        }
+        if let Some(keepalive) = self.keepalive {
+            set_int_option(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+            if let Some(idle) = keepalive.idle {
+                set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPIDLE, clamp(idle as usize))?;
+            }
+            if let Some(interval) = keepalive.interval {
+                set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, clamp(interval as usize))?;
+            }
+            if let Some(count) = keepalive.count {
+                set_int_option(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, clamp(count as usize))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn clamp(value: usize) -> libc::c_int {
+    value.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+/// The options as the kernel actually has them on `fd`, e.g.
+/// `nodelay=1 keepalive=1 keepidle=60 keepintvl=10 keepcnt=5 rcvbuf=131072 sndbuf=16384 reuseport=0`.
+pub fn describe(fd: RawFd) -> std::io::Result<String> {
+    let options: [(&str, i32, i32); 8] = [
+        ("nodelay", libc::IPPROTO_TCP, libc::TCP_NODELAY),
+        ("keepalive", libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+        ("keepidle", libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+        ("keepintvl", libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+        ("keepcnt", libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+        ("rcvbuf", libc::SOL_SOCKET, libc::SO_RCVBUF),
+        ("sndbuf", libc::SOL_SOCKET, libc::SO_SNDBUF),
+        ("reuseport", libc::SOL_SOCKET, libc::SO_REUSEPORT),
+    ];
+    let values: Vec<String> = options
+        .iter()
+        .map(|&(name, level, option)| Ok(format!("{}={}", name, get_int_option(fd, level, option)?)))
+        .collect::<std::io::Result<_>>()?;
+    Ok(values.join(" "))
+}
+
+#[test]
+fn socket_options_test() {
+    use std::os::unix::io::AsRawFd;
+
+    let options: SocketOptions = SocketOptions {
+        nodelay: true,
+        keepalive: Some(Keepalive { idle: Some(30), interval: Some(5), count: Some(3) }),
+        recv_buffer: Some(64 * 1024),
+        send_buffer: None,
+        reuse_port: true,
+    };
+    let listener: crate::raw_listener::RawListener = crate::raw_listener::RawListener::bind_with_options("127.0.0.1", "0", None, &options).unwrap();
+    let described: String = describe(listener.as_raw_fd()).unwrap();
+    assert!(described.starts_with("nodelay=1 keepalive=1 keepidle=30 keepintvl=5 keepcnt=3 rcvbuf="), "{}", described);
+    assert!(described.ends_with(" reuseport=1"), "{}", described);
+
+    // A second listener on the same port, as the extra acceptor threads have.
+    let port: String = listener.local_addr().unwrap().port().to_string();
+    assert!(crate::raw_listener::RawListener::bind_with_options("127.0.0.1", &port, None, &options).is_ok());
+    assert!(crate::raw_listener::RawListener::bind("127.0.0.1", &port).is_err());
+
+    let stream: std::net::TcpStream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    SocketOptions { nodelay: true, ..SocketOptions::default() }.apply_stream(stream.as_raw_fd()).unwrap();
+    assert!(stream.nodelay().unwrap());
+    assert!(SocketOptions::default().is_default());
+}
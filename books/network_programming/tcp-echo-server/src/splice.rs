@@ -0,0 +1,177 @@
+//! Zero-copy echo for `--zero-copy`: bytes move from the client's socket into a pipe and
+//! from the pipe back into the socket with `splice(2)`, so they never enter user space.
+//!
+//! It answers like the raw echo, except that it never sees the data, so a chunk starting
+//! with `bye` is echoed like any other and clients end the connection by closing it.
+//! Connections splice can't be used on are served by the buffered `read`/`write` loop
+//! instead: those whose reads go through a wrapper (a byte rate, bytes read past a PROXY
+//! header) and sockets the kernel won't splice.
+
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::access_log::CloseReason;
+use crate::stats::Counted;
+use crate::stream::ClientStream;
+use crate::timeouts::{TimedStream, TimeoutError, TimeoutKind, Timeouts};
+
+/// Most bytes moved per `splice`; the default capacity of a pipe.
+const CHUNK: usize = 64 * 1024;
+
+/// Both ends of a pipe, closed on drop.
+pub struct Pipe {
+    read: OwnedFd,
+    write: OwnedFd,
+}
+
+impl Pipe {
+    pub fn new() -> std::io::Result<Pipe> {
+        let mut fds: [libc::c_int; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(unsafe { Pipe { read: OwnedFd::from_raw_fd(fds[0]), write: OwnedFd::from_raw_fd(fds[1]) } })
+    }
+}
+
+/// Moves up to `len` bytes from `from` to `to`, one of which has to be a pipe.
+fn splice(from: RawFd, to: RawFd, len: usize) -> std::io::Result<usize> {
+    loop {
+        let moved: isize = unsafe { libc::splice(from, std::ptr::null_mut(), to, std::ptr::null_mut(), len, libc::SPLICE_F_MOVE) };
+        if moved >= 0 {
+            return Ok(moved as usize);
+        }
+        let err: std::io::Error = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// The kernel can't splice between these two, as opposed to the connection failing.
+fn is_unsupported(err: &std::io::Error) -> bool {
+    matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP))
+}
+
+/// Turns running into `SO_RCVTIMEO`/`SO_SNDTIMEO` into the `TimeoutError` `TimedStream` would report.
+fn timed_out(err: std::io::Error, kind: TimeoutKind, after: Option<std::time::Duration>) -> std::io::Error {
+    match after {
+        Some(after) if err.kind() == std::io::ErrorKind::WouldBlock => TimeoutError { kind, after }.into_io(),
+        _ => err,
+    }
+}
+
+/// Echoes everything back through a pipe until EOF, counting the chunks in `messages` and
+/// every byte in `stream`'s counters, as if it had been read and written through it.
+///
+/// Returns `None` once splice turns out not to be possible; everything received so far
+/// has been echoed then, and the caller goes on with the buffered loop.
+pub fn echo<S: ClientStream>(stream: &mut Counted<TimedStream<S>>, messages: &mut u64) -> std::io::Result<Option<CloseReason>> {
+    let fd: RawFd = match stream.get_ref().get_ref().raw_fd() {
+        Some(fd) => fd,
+        None => return Ok(None),
+    };
+    let pipe: Pipe = match Pipe::new() {
+        Ok(pipe) => pipe,
+        Err(err) => {
+            eprintln!("couldn't create a pipe, echoing through user space: {}", err);
+            return Ok(None);
+        }
+    };
+    // Every chunk is answered right away, so the only deadline that applies to reads is the idle one.
+    let timeouts: Timeouts = stream.get_ref().timeouts();
+    stream.get_ref().get_ref().set_read_timeout(timeouts.idle)?;
+
+    loop {
+        let received: usize = match splice(fd, pipe.write.as_raw_fd(), CHUNK) {
+            Ok(0) => return Ok(Some(CloseReason::Eof)),
+            Ok(received) => received,
+            // Nothing is in the pipe yet, so nothing is lost by leaving it to the buffered loop.
+            Err(ref err) if is_unsupported(err) => return Ok(None),
+            Err(err) => return Err(timed_out(err, TimeoutKind::Idle, timeouts.idle)),
+        };
+        stream.count_in(received);
+
+        let mut left: usize = received;
+        while left > 0 {
+            match splice(pipe.read.as_raw_fd(), fd, left) {
+                Ok(sent) => {
+                    stream.count_out(sent);
+                    left -= sent;
+                }
+                Err(ref err) if is_unsupported(err) => {
+                    // Whatever is still in the pipe goes out the buffered way; `write_all` counts it.
+                    let mut buf: Vec<u8> = vec![0; left];
+                    std::fs::File::from(pipe.read.try_clone()?).read_exact(&mut buf)?;
+                    stream.write_all(&buf)?;
+                    *messages += 1;
+                    return Ok(None);
+                }
+                Err(err) => return Err(timed_out(err, TimeoutKind::Write, timeouts.write)),
+            }
+        }
+        *messages += 1;
+    }
+}
+
+#[test]
+fn splice_echo_test() {
+    use std::net::{Shutdown, TcpListener, TcpStream};
+
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let data: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let sender = {
+        let (mut client, data) = (client.try_clone().unwrap(), data.clone());
+        std::thread::spawn(move || {
+            client.write_all(&data).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+        })
+    };
+    let stats: crate::stats::Stats = crate::stats::Stats::default();
+    let echoed = std::thread::scope(|scope| {
+        let reader = scope.spawn(|| {
+            let mut echoed: Vec<u8> = Vec::new();
+            client.read_to_end(&mut echoed).unwrap();
+            echoed
+        });
+        let mut stream: Counted<TimedStream<Box<dyn ClientStream>>> = Counted::new(TimedStream::new(Box::new(server) as Box<dyn ClientStream>, Timeouts::default()).unwrap(), &stats);
+        let mut messages: u64 = 0;
+        assert_eq!(echo(&mut stream, &mut messages).unwrap(), Some(CloseReason::Eof));
+        assert!(messages > 0);
+        assert_eq!(stream.totals(), (data.len() as u64, data.len() as u64));
+        drop(stream);
+        reader.join().unwrap()
+    });
+    sender.join().unwrap();
+    assert!(echoed == data);
+    assert_eq!(stats.bytes_out.load(std::sync::atomic::Ordering::Relaxed), data.len() as u64);
+}
+
+#[test]
+fn zero_copy_server_test() {
+    use std::net::{Shutdown, TcpStream};
+    use std::sync::atomic::Ordering;
+
+    // With a byte rate, reads have to go through the limiter and the buffered loop takes over.
+    let mut throttled: crate::config::Config = crate::config::Config::default();
+    throttled.admission.byte_rate = 1e9;
+    let servers: [(crate::ServerHandle, &[u8]); 2] = [
+        (crate::EchoServer::builder().bind("127.0.0.1:0").zero_copy(true).spawn().unwrap(), b"bye, but not to splice"),
+        (crate::EchoServer::builder().config(throttled).bind("127.0.0.1:0").zero_copy(true).spawn().unwrap(), b"bye"),
+    ];
+
+    for (server, expected) in servers {
+        let mut client: TcpStream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"bye, but not to splice").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply: Vec<u8> = Vec::new();
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, expected);
+        assert_eq!(server.stats().bytes_in.load(Ordering::Relaxed), 22);
+        assert_eq!(server.stats().bytes_out.load(Ordering::Relaxed), expected.len() as u64);
+        server.shutdown();
+    }
+}
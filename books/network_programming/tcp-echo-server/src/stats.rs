@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::limits::RejectReason;
+
+/// Upper bounds, in seconds, of the round trip latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 12] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0];
+
+/// Time from a request's first byte to the first byte of its reply.
+#[derive(Debug, Default)]
+pub struct LatencyHistogram {
+    /// Observations per bucket, not cumulative; the last one counts everything above `LATENCY_BUCKETS`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+    pub fn observe(&self, latency: Duration) {
+        let secs: f64 = latency.as_secs_f64();
+        let bucket: usize = LATENCY_BUCKETS.iter().position(|&bound| secs <= bound).unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Cumulative counts per bucket (the last one being `+Inf`, i.e. the total count) and the sum of all observations.
+    pub fn snapshot(&self) -> (Vec<u64>, Duration) {
+        let mut total: u64 = 0;
+        let cumulative: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| {
+                total += bucket.load(Ordering::Relaxed);
+                total
+            })
+            .collect();
+        (cumulative, Duration::from_micros(self.sum_micros.load(Ordering::Relaxed)))
+    }
+}
+
+/// Server wide counters, shared by every connection.
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub accepted: AtomicU64,
+    pub active: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    /// Connections turned away, for whatever reason.
+    pub rejected: AtomicU64,
+    /// The same, by `RejectReason::label`.
+    pub rejections: Mutex<BTreeMap<&'static str, u64>>,
+    /// Failed accepts and connections that ended in an error, by `io::ErrorKind`.
+    pub errors: Mutex<BTreeMap<String, u64>>,
+    pub latency: LatencyHistogram,
+}
+
+impl Stats {
+    /// Counts a connection as active until the returned guard is dropped, unless that would
+    /// go over `max_connections` (0 means no limit). Checking and counting are one step, so
+    /// acceptors on several threads can't all take the last slot.
+    ///
+    /// The connection isn't counted as accepted before `count_accepted`: it may still be
+    /// turned away, and `accepted` is a counter, which never goes down.
+    pub fn reserve(self: &Arc<Self>, max_connections: usize) -> Option<ActiveConnection> {
+        self.active
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| (max_connections == 0 || active < max_connections as u64).then_some(active + 1))
+            .ok()
+            .map(|_| ActiveConnection { stats: self.clone() })
+    }
+
+    pub fn count_accepted(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a rejected connection, returning the total so far.
+    pub fn reject(&self, reason: RejectReason) -> u64 {
+        *self.rejections.lock().unwrap().entry(reason.label()).or_insert(0) += 1;
+        self.rejected.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn add_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_error(&self, kind: std::io::ErrorKind) {
+        *self.errors.lock().unwrap().entry(format!("{:?}", kind)).or_insert(0) += 1;
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "accepted={} active={} rejected={} bytes_in={} bytes_out={}",
+            self.accepted.load(Ordering::Relaxed),
+            self.active.load(Ordering::Relaxed),
+            self.rejected.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+}
+
+pub struct ActiveConnection {
+    stats: Arc<Stats>,
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        self.stats.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps a connection's stream and adds every byte read or written to the server counters,
+/// keeping the connection's own totals as well.
+///
+/// The time between the first byte read after a write and the next write goes into the
+/// round trip latency histogram.
+pub struct Counted<'a, S> {
+    inner: S,
+    stats: &'a Stats,
+    bytes_in: u64,
+    bytes_out: u64,
+    request_started: Option<Instant>,
+}
+
+impl<'a, S> Counted<'a, S> {
+    pub fn new(inner: S, stats: &'a Stats) -> Counted<'a, S> {
+        Counted { inner, stats, bytes_in: 0, bytes_out: 0, request_started: None }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    /// Bytes read and written through this stream so far.
+    pub fn totals(&self) -> (u64, u64) {
+        (self.bytes_in, self.bytes_out)
+    }
+
+    /// Counts bytes received without going through `read`, e.g. spliced into a pipe.
+    pub fn count_in(&mut self, bytes: usize) {
+        self.stats.add_in(bytes);
+        self.bytes_in += bytes as u64;
+        if bytes > 0 && self.request_started.is_none() {
+            self.request_started = Some(Instant::now());
+        }
+    }
+
+    /// Counts bytes sent without going through `write`.
+    pub fn count_out(&mut self, bytes: usize) {
+        self.stats.add_out(bytes);
+        self.bytes_out += bytes as u64;
+        if let Some(started) = self.request_started.take() {
+            self.stats.latency.observe(started.elapsed());
+        }
+    }
+}
+
+impl<S: Read> Read for Counted<'_, S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read: usize = self.inner.read(buf)?;
+        self.count_in(bytes_read);
+        Ok(bytes_read)
+    }
+}
+
+impl<S: Write> Write for Counted<'_, S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written: usize = self.inner.write(buf)?;
+        self.count_out(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn latency_histogram_test() {
+    let histogram: LatencyHistogram = LatencyHistogram::default();
+    histogram.observe(Duration::from_micros(50));
+    histogram.observe(Duration::from_millis(3));
+    histogram.observe(Duration::from_secs(2));
+
+    let (buckets, sum): (Vec<u64>, Duration) = histogram.snapshot();
+    assert_eq!(buckets, [1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 3]);
+    assert_eq!(sum, Duration::from_micros(2_003_050));
+}
+
+#[test]
+fn reserve_test() {
+    let stats: Arc<Stats> = Arc::new(Stats::default());
+    let first: ActiveConnection = stats.reserve(2).unwrap();
+    let _second: ActiveConnection = stats.reserve(2).unwrap();
+    assert!(stats.reserve(2).is_none());
+    assert_eq!(stats.active.load(Ordering::Relaxed), 2);
+    drop(first);
+    assert!(stats.reserve(2).is_some());
+    assert!(stats.reserve(0).is_some());
+    assert_eq!(stats.accepted.load(Ordering::Relaxed), 0);
+}
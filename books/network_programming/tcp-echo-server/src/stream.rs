@@ -0,0 +1,133 @@
+use std::io::{Read, Write};
+use std::net::{IpAddr, Shutdown, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+/// What the per-connection code needs from a client's socket, so TCP and Unix domain
+/// clients go through the same `handle_client`.
+pub trait ClientStream: Read + Write + Send + 'static {
+    /// Who is on the other end, for logging.
+    fn peer_name(&self) -> std::io::Result<String>;
+    /// The peer's address, for the allow and deny lists and rate limits; Unix clients have none.
+    fn peer_ip(&self) -> Option<IpAddr>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()>;
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()>;
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()>;
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>>;
+    /// The socket itself, for `splice`; `None` when reads have to go through this wrapper,
+    /// e.g. to be held to a byte rate.
+    fn raw_fd(&self) -> Option<RawFd>;
+}
+
+impl ClientStream for TcpStream {
+    fn peer_name(&self) -> std::io::Result<String> {
+        self.peer_addr().map(|addr| addr.to_string())
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|addr| addr.ip())
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl ClientStream for UnixStream {
+    /// Unix clients rarely bind a name, so they are told apart by the process on the other end.
+    fn peer_name(&self) -> std::io::Result<String> {
+        let mut credentials: libc::ucred = unsafe { std::mem::zeroed() };
+        let mut len: libc::socklen_t = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let status: i32 = unsafe {
+            libc::getsockopt(self.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut credentials as *mut libc::ucred as *mut libc::c_void, &mut len)
+        };
+        if status < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(format!("unix:pid={},uid={}", credentials.pid, credentials.uid))
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl ClientStream for Box<dyn ClientStream> {
+    fn peer_name(&self) -> std::io::Result<String> {
+        (**self).peer_name()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        (**self).peer_ip()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        (**self).shutdown(how)
+    }
+
+    fn try_clone_stream(&self) -> std::io::Result<Box<dyn ClientStream>> {
+        (**self).try_clone_stream()
+    }
+
+    fn raw_fd(&self) -> Option<RawFd> {
+        (**self).raw_fd()
+    }
+}
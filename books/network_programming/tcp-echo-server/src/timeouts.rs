@@ -0,0 +1,181 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::stream::ClientStream;
+
+/// Per connection deadlines; `None` waits forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// How long a connection may sit without sending anything while no request is in progress.
+    pub idle: Option<Duration>,
+    /// How long a client has to finish a request once its first byte arrived.
+    /// Catches slowloris style clients, which trickle bytes and never finish.
+    pub read: Option<Duration>,
+    /// How long a single write may block, i.e. how long a client may refuse to read.
+    pub write: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeoutKind {
+    Idle,
+    Read,
+    Write,
+}
+
+/// Error carried inside the `io::ErrorKind::TimedOut` errors of `TimedStream`,
+/// telling which deadline ran out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimeoutError {
+    pub kind: TimeoutKind,
+    pub after: Duration,
+}
+
+impl std::fmt::Display for TimeoutError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind: &str = match self.kind {
+            TimeoutKind::Idle => "idle",
+            TimeoutKind::Read => "read",
+            TimeoutKind::Write => "write",
+        };
+        write!(f, "{} timeout after {:?}", kind, self.after)
+    }
+}
+
+impl std::error::Error for TimeoutError {}
+
+impl TimeoutError {
+    /// Finds the `TimeoutError` inside an `io::Error`, if that's what it is.
+    pub fn from_io(err: &std::io::Error) -> Option<TimeoutError> {
+        err.get_ref()?.downcast_ref::<TimeoutError>().copied()
+    }
+
+    pub fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::TimedOut, self)
+    }
+}
+
+/// Client stream enforcing `Timeouts` through `set_read_timeout`/`set_write_timeout`.
+///
+/// A request counts as in progress from the first byte received after the last write
+/// until the next write, which fits both the raw echo (every read is answered right
+/// away) and line based protocols (a partial line gets no reply).
+pub struct TimedStream<S> {
+    stream: S,
+    timeouts: Timeouts,
+    /// When the first unanswered byte arrived.
+    request_started: Option<Instant>,
+}
+
+impl<S: ClientStream> TimedStream<S> {
+    pub fn new(stream: S, timeouts: Timeouts) -> std::io::Result<TimedStream<S>> {
+        stream.set_write_timeout(timeouts.write)?;
+        Ok(TimedStream { stream, timeouts, request_started: None })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        self.timeouts
+    }
+
+    /// Marks the request as answered without writing anything, for protocols where
+    /// not every request gets a reply on the same stream.
+    pub fn end_request(&mut self) {
+        self.request_started = None;
+    }
+
+    /// The deadline that applies to the next read.
+    fn read_deadline(&self) -> Option<TimeoutError> {
+        match self.request_started {
+            Some(_) => self.timeouts.read.map(|after| TimeoutError { kind: TimeoutKind::Read, after }),
+            None => self.timeouts.idle.map(|after| TimeoutError { kind: TimeoutKind::Idle, after }),
+        }
+    }
+}
+
+fn is_timeout(err: &std::io::Error) -> bool {
+    matches!(err.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+}
+
+impl<S: ClientStream> Read for TimedStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let deadline: Option<TimeoutError> = self.read_deadline();
+        let timeout: Option<Duration> = match (deadline, self.request_started) {
+            (Some(deadline), Some(started)) => {
+                let left: Duration = deadline.after.saturating_sub(started.elapsed());
+                if left.is_zero() {
+                    return Err(deadline.into_io());
+                }
+                Some(left)
+            }
+            (deadline, _) => deadline.map(|deadline| deadline.after),
+        };
+        self.stream.set_read_timeout(timeout)?;
+
+        match self.stream.read(buf) {
+            Ok(bytes_read) => {
+                if bytes_read > 0 && self.request_started.is_none() {
+                    self.request_started = Some(Instant::now());
+                }
+                Ok(bytes_read)
+            }
+            Err(e) => match deadline {
+                Some(deadline) if is_timeout(&e) => Err(deadline.into_io()),
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+impl<S: ClientStream> Write for TimedStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.request_started = None;
+        match (self.stream.write(buf), self.timeouts.write) {
+            (Err(ref e), Some(after)) if is_timeout(e) => Err(TimeoutError { kind: TimeoutKind::Write, after }.into_io()),
+            (result, _) => result,
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[test]
+fn idle_and_read_timeout_test() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    use std::net::TcpStream;
+
+    let mut client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+
+    let timeouts: Timeouts = Timeouts { idle: Some(Duration::from_millis(50)), read: Some(Duration::from_millis(150)), write: None };
+    let mut server: TimedStream<TcpStream> = TimedStream::new(server, timeouts).unwrap();
+    let mut buf = [0; 16];
+
+    // Nothing sent at all: idle.
+    let err: std::io::Error = server.read(&mut buf).unwrap_err();
+    assert_eq!(TimeoutError::from_io(&err).map(|e| e.kind), Some(TimeoutKind::Idle));
+
+    // A request started but never finished: read, even though bytes keep trickling in
+    // faster than the idle timeout.
+    let trickle = std::thread::spawn(move || {
+        for _ in 0..10 {
+            if client.write_all(b"x").is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(30));
+        }
+    });
+    let err: std::io::Error = loop {
+        if let Err(err) = server.read(&mut buf) {
+            break err;
+        }
+    };
+    assert_eq!(TimeoutError::from_io(&err).map(|e| e.kind), Some(TimeoutKind::Read));
+    assert_eq!(err.to_string(), "read timeout after 150ms");
+    drop(server);
+    trickle.join().unwrap();
+}
@@ -0,0 +1,178 @@
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::shutdown::{Shutdown, POLL_INTERVAL};
+
+/// Largest payload of an IPv4 UDP datagram.
+pub const MAX_DATAGRAM: usize = 65507;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpOptions {
+    /// Datagrams bigger than this are counted as oversized and not echoed.
+    pub max_datagram: usize,
+    /// Share of datagrams, in percent, that are silently dropped.
+    pub drop_percent: u8,
+    /// Share of datagrams, in percent, that are echoed only after `delay`.
+    pub delay_percent: u8,
+    pub delay: Duration,
+}
+
+impl Default for UdpOptions {
+    fn default() -> Self {
+        UdpOptions {
+            max_datagram: MAX_DATAGRAM,
+            drop_percent: 0,
+            delay_percent: 0,
+            delay: Duration::from_millis(200),
+        }
+    }
+}
+
+/// Counters of the UDP echo, kept apart from the TCP ones.
+#[derive(Debug, Default)]
+pub struct UdpStats {
+    pub datagrams_in: AtomicU64,
+    pub datagrams_out: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub dropped: AtomicU64,
+    pub delayed: AtomicU64,
+    pub oversized: AtomicU64,
+}
+
+impl std::fmt::Display for UdpStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "datagrams_in={} datagrams_out={} bytes_in={} bytes_out={} dropped={} delayed={} oversized={}",
+            self.datagrams_in.load(Ordering::Relaxed),
+            self.datagrams_out.load(Ordering::Relaxed),
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+            self.dropped.load(Ordering::Relaxed),
+            self.delayed.load(Ordering::Relaxed),
+            self.oversized.load(Ordering::Relaxed),
+        )
+    }
+}
+
+/// xorshift64: plenty for deciding which packets to drop, and no extra dependency.
+struct Random(u64);
+
+impl Random {
+    fn from_clock() -> Random {
+        let nanos: u64 = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        Random(nanos | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// True `percent` times out of a hundred.
+    fn chance(&mut self, percent: u8) -> bool {
+        percent > 0 && self.next() % 100 < percent as u64
+    }
+}
+
+/// Reflects every datagram back to its sender until `shutdown` is requested.
+pub fn serve(socket: UdpSocket, options: &UdpOptions, stats: &UdpStats, shutdown: &Shutdown) -> std::io::Result<()> {
+    // One extra byte tells datagrams of exactly `max_datagram` bytes from bigger ones.
+    let mut buf: Vec<u8> = vec![0; options.max_datagram + 1];
+    let mut random: Random = Random::from_clock();
+    // The delay is the same for every datagram, so the queue stays ordered by due time.
+    let mut delayed: VecDeque<(Instant, SocketAddr, Vec<u8>)> = VecDeque::new();
+
+    while !shutdown.is_requested() {
+        while let Some((due, peer, payload)) = delayed.front() {
+            if *due > Instant::now() {
+                break;
+            }
+            send(&socket, payload, *peer, stats);
+            delayed.pop_front();
+        }
+
+        let timeout: Duration = match delayed.front() {
+            Some((due, _, _)) => due.saturating_duration_since(Instant::now()).clamp(Duration::from_millis(1), POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        };
+        socket.set_read_timeout(Some(timeout))?;
+
+        let (size, peer): (usize, SocketAddr) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(ref e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut | std::io::ErrorKind::Interrupted) => continue,
+            Err(e) => return Err(e),
+        };
+
+        stats.datagrams_in.fetch_add(1, Ordering::Relaxed);
+        stats.bytes_in.fetch_add(size as u64, Ordering::Relaxed);
+
+        if size > options.max_datagram {
+            stats.oversized.fetch_add(1, Ordering::Relaxed);
+        } else if random.chance(options.drop_percent) {
+            stats.dropped.fetch_add(1, Ordering::Relaxed);
+        } else if random.chance(options.delay_percent) {
+            stats.delayed.fetch_add(1, Ordering::Relaxed);
+            delayed.push_back((Instant::now() + options.delay, peer, buf[..size].to_vec()));
+        } else {
+            send(&socket, &buf[..size], peer, stats);
+        }
+    }
+
+    Ok(())
+}
+
+fn send(socket: &UdpSocket, payload: &[u8], peer: SocketAddr, stats: &UdpStats) {
+    match socket.send_to(payload, peer) {
+        Ok(sent) => {
+            stats.datagrams_out.fetch_add(1, Ordering::Relaxed);
+            stats.bytes_out.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+        Err(e) => eprintln!("udp: couldn't echo to {}: {}", peer, e),
+    }
+}
+
+#[test]
+fn random_chance_test() {
+    let mut random: Random = Random(42);
+    assert!(!(0..1000).any(|_| random.chance(0)));
+    assert!((0..1000).all(|_| random.chance(100)));
+
+    let hits: usize = (0..10_000).filter(|_| random.chance(30)).count();
+    assert!((2500..3500).contains(&hits), "{}", hits);
+}
+
+#[test]
+fn udp_echo_test() {
+    let server: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = server.local_addr().unwrap();
+    let shutdown: Shutdown = Shutdown::default();
+    let stats: std::sync::Arc<UdpStats> = std::sync::Arc::new(UdpStats::default());
+
+    let handle = {
+        let (shutdown, stats) = (shutdown.clone(), stats.clone());
+        let options: UdpOptions = UdpOptions { max_datagram: 8, ..UdpOptions::default() };
+        std::thread::spawn(move || serve(server, &options, &stats, &shutdown))
+    };
+
+    let client: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+    client.send_to(b"too long for it", addr).unwrap();
+    client.send_to(b"hello", addr).unwrap();
+
+    let mut buf = [0; 64];
+    let (size, from): (usize, SocketAddr) = client.recv_from(&mut buf).unwrap();
+    assert_eq!(&buf[..size], b"hello");
+    assert_eq!(from, addr);
+
+    shutdown.request();
+    handle.join().unwrap().unwrap();
+    assert_eq!(stats.datagrams_in.load(Ordering::Relaxed), 2);
+    assert_eq!(stats.datagrams_out.load(Ordering::Relaxed), 1);
+    assert_eq!(stats.oversized.load(Ordering::Relaxed), 1);
+}
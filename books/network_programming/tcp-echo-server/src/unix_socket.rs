@@ -0,0 +1,188 @@
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// Address of a Unix domain socket listener: `unix:/path/to.sock`, or `unix:@name` for
+/// Linux's abstract namespace, which has no file and vanishes with the last socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnixAddr {
+    Path(PathBuf),
+    Abstract(String),
+}
+
+impl std::str::FromStr for UnixAddr {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:").ok_or(())? {
+            "" | "@" => Err(()),
+            name if name.starts_with('@') => Ok(UnixAddr::Abstract(name[1..].to_owned())),
+            path => Ok(UnixAddr::Path(PathBuf::from(path))),
+        }
+    }
+}
+
+impl UnixAddr {
+    /// Connects to a listener on this address, as a client.
+    pub fn connect(&self) -> std::io::Result<UnixStream> {
+        match self {
+            UnixAddr::Path(path) => UnixStream::connect(path),
+            UnixAddr::Abstract(name) => connect_abstract(name),
+        }
+    }
+}
+
+impl std::fmt::Display for UnixAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Path(path) => write!(f, "unix:{}", path.display()),
+            Self::Abstract(name) => write!(f, "unix:@{}", name),
+        }
+    }
+}
+
+/// A `UnixListener` that removes its socket file when dropped, if it created it.
+#[derive(Debug)]
+pub struct UnixSocketListener {
+    listener: UnixListener,
+    addr: UnixAddr,
+    /// Unset for sockets bound by someone else, whose file is theirs to remove.
+    owns_file: bool,
+}
+
+impl UnixSocketListener {
+    /// Binds `addr`, setting the socket file's permissions to `mode` if given.
+    ///
+    /// A socket file left behind by a server that didn't shut down cleanly is removed first;
+    /// one some server still accepts on is not, and neither is anything that isn't a socket.
+    pub fn bind(addr: &UnixAddr, mode: Option<u32>) -> std::io::Result<UnixSocketListener> {
+        match addr {
+            UnixAddr::Path(path) => {
+                remove_stale_socket(path)?;
+                let listener: UnixListener = UnixListener::bind(path)?;
+                let bound: UnixSocketListener = UnixSocketListener { listener, addr: addr.clone(), owns_file: true };
+                if let Some(mode) = mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+                Ok(bound)
+            }
+            UnixAddr::Abstract(name) => Ok(UnixSocketListener { listener: bind_abstract(name)?, addr: addr.clone(), owns_file: true }),
+        }
+    }
+
+    /// Serves a listener bound by someone else, e.g. a service manager; its socket file is
+    /// left alone on drop.
+    pub fn from_listener(listener: UnixListener) -> std::io::Result<UnixSocketListener> {
+        let local: std::os::unix::net::SocketAddr = listener.local_addr()?;
+        let addr: UnixAddr = match local.as_pathname() {
+            Some(path) => UnixAddr::Path(path.to_owned()),
+            None => UnixAddr::Abstract(abstract_name(&local).ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "unix socket has no name"))?),
+        };
+        Ok(UnixSocketListener { listener, addr, owns_file: false })
+    }
+
+    pub fn accept(&self) -> std::io::Result<UnixStream> {
+        self.listener.accept().map(|(stream, _)| stream)
+    }
+
+    pub fn local_addr(&self) -> &UnixAddr {
+        &self.addr
+    }
+}
+
+impl AsRawFd for UnixSocketListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for UnixSocketListener {
+    fn drop(&mut self) {
+        if let (UnixAddr::Path(path), true) = (&self.addr, self.owns_file) {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+fn remove_stale_socket(path: &std::path::Path) -> std::io::Result<()> {
+    let metadata: std::fs::Metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, format!("{} exists and is not a socket", path.display())));
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, format!("{} is in use by a running server", path.display()))),
+        Err(ref e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            println!("removing stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> std::io::Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    UnixListener::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> std::io::Result<UnixListener> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract unix sockets are only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn connect_abstract(name: &str) -> std::io::Result<UnixStream> {
+    use std::os::linux::net::SocketAddrExt;
+
+    UnixStream::connect_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn connect_abstract(_name: &str) -> std::io::Result<UnixStream> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "abstract unix sockets are only available on Linux"))
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_name(addr: &std::os::unix::net::SocketAddr) -> Option<String> {
+    use std::os::linux::net::SocketAddrExt;
+
+    addr.as_abstract_name().map(|name| String::from_utf8_lossy(name).into_owned())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn abstract_name(_addr: &std::os::unix::net::SocketAddr) -> Option<String> {
+    None
+}
+
+#[test]
+fn unix_socket_stale_file_test() {
+    let path: PathBuf = std::env::temp_dir().join(format!("tcp-echo-server-test-{}.sock", std::process::id()));
+    let addr: UnixAddr = format!("unix:{}", path.display()).parse().unwrap();
+
+    // Left behind by a listener that never got to clean up.
+    drop(UnixListener::bind(&path).unwrap());
+    let listener: UnixSocketListener = UnixSocketListener::bind(&addr, Some(0o600)).unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+
+    // Still accepting, so it's not stale.
+    assert_eq!(UnixSocketListener::bind(&addr, None).unwrap_err().kind(), std::io::ErrorKind::AddrInUse);
+
+    drop(listener);
+    assert!(!path.exists());
+}
+
+#[test]
+fn unix_addr_parse_test() {
+    assert_eq!("unix:/run/echo.sock".parse(), Ok(UnixAddr::Path(PathBuf::from("/run/echo.sock"))));
+    assert_eq!("unix:@echo".parse(), Ok(UnixAddr::Abstract("echo".to_owned())));
+    assert_eq!("unix:@echo".parse::<UnixAddr>().unwrap().to_string(), "unix:@echo");
+    assert!("unix:".parse::<UnixAddr>().is_err());
+    assert!("/run/echo.sock".parse::<UnixAddr>().is_err());
+}
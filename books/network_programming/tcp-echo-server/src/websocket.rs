@@ -0,0 +1,430 @@
+//! WebSocket echo (RFC 6455): after the HTTP upgrade handshake, every text or binary
+//! message is sent back as it came, pings are answered with pongs and a close is
+//! answered with a close carrying the same status code.
+//!
+//! Fragmented messages are put back together and echoed as one frame. Protocol
+//! violations (unmasked client frames, reserved bits, bad control frames), text that
+//! isn't UTF-8 and messages over the size limit end the connection with a close frame
+//! saying so: 1002, 1007 and 1009 respectively.
+
+use std::io::{Read, Write};
+
+use crate::access_log::CloseReason;
+use crate::http::{self, HttpError, HttpLimits, HttpReader, Request};
+use crate::{base64, sha1};
+
+/// Appended to the client's key before hashing it into `Sec-WebSocket-Accept`.
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Payload limit of control frames.
+const MAX_CONTROL: usize = 125;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Opcode {
+    Continuation,
+    Text,
+    Binary,
+    Close,
+    Ping,
+    Pong,
+}
+
+impl Opcode {
+    fn from_u8(opcode: u8) -> Option<Opcode> {
+        match opcode {
+            0x0 => Some(Opcode::Continuation),
+            0x1 => Some(Opcode::Text),
+            0x2 => Some(Opcode::Binary),
+            0x8 => Some(Opcode::Close),
+            0x9 => Some(Opcode::Ping),
+            0xA => Some(Opcode::Pong),
+            _ => None,
+        }
+    }
+
+    fn as_u8(&self) -> u8 {
+        match self {
+            Self::Continuation => 0x0,
+            Self::Text => 0x1,
+            Self::Binary => 0x2,
+            Self::Close => 0x8,
+            Self::Ping => 0x9,
+            Self::Pong => 0xA,
+        }
+    }
+
+    fn is_control(&self) -> bool {
+        matches!(self, Self::Close | Self::Ping | Self::Pong)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub fin: bool,
+    pub opcode: Opcode,
+    /// Unmasked.
+    pub payload: Vec<u8>,
+}
+
+/// Why the server closes the connection; carried inside `io::ErrorKind::InvalidData` errors.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WebSocketError {
+    Protocol(String),
+    InvalidUtf8,
+    TooLarge { max: usize },
+}
+
+impl WebSocketError {
+    /// Status code of the close frame telling the client.
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::Protocol(_) => 1002,
+            Self::InvalidUtf8 => 1007,
+            Self::TooLarge { .. } => 1009,
+        }
+    }
+
+    fn into_io(self) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, self)
+    }
+}
+
+impl std::fmt::Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Protocol(reason) => write!(f, "protocol error: {}", reason),
+            Self::InvalidUtf8 => f.write_str("text message is not utf-8"),
+            Self::TooLarge { max } => write!(f, "message over the {} bytes limit", max),
+        }
+    }
+}
+
+impl std::error::Error for WebSocketError {}
+
+fn protocol_error(reason: &str) -> std::io::Error {
+    WebSocketError::Protocol(reason.to_owned()).into_io()
+}
+
+/// `Sec-WebSocket-Accept` for a client's `Sec-WebSocket-Key`.
+pub fn accept_key(key: &str) -> String {
+    base64::encode(&sha1::digest(format!("{}{}", key, ACCEPT_GUID).as_bytes()))
+}
+
+/// Checks an upgrade request, returning the accept key or what to answer instead.
+fn check_upgrade(request: &Request) -> Result<String, (&'static str, String)> {
+    let bad = |reason: &str| ("400 Bad Request", reason.to_owned());
+    if request.method != "GET" || request.version != "HTTP/1.1" {
+        return Err(bad("a WebSocket handshake is a GET over HTTP/1.1"));
+    }
+    let has_token = |name: &str, token: &str| request.header(name).is_some_and(|value| value.split(',').any(|item| item.trim().eq_ignore_ascii_case(token)));
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return Err(("426 Upgrade Required", "this endpoint only speaks WebSocket".to_owned()));
+    }
+    if request.header("Sec-WebSocket-Version").as_deref() != Some("13") {
+        return Err(("426 Upgrade Required", "only WebSocket version 13 is supported".to_owned()));
+    }
+    let key: String = request.header("Sec-WebSocket-Key").ok_or_else(|| bad("missing Sec-WebSocket-Key"))?;
+    match base64::decode(&key) {
+        Ok(nonce) if nonce.len() == 16 => Ok(accept_key(&key)),
+        _ => Err(bad("invalid Sec-WebSocket-Key")),
+    }
+}
+
+/// Reads the upgrade request and answers it. Returns why if the client was turned away,
+/// or the stream ended before a request.
+fn handshake<S: Read + Write>(reader: &mut HttpReader<S>, max_head: usize) -> std::io::Result<Result<(), String>> {
+    let head: String = match reader.read_head(max_head) {
+        Ok(Some(head)) => head,
+        Ok(None) => return Ok(Err("closed before the handshake".to_owned())),
+        Err(err) => {
+            let http_error: HttpError = match err.get_ref().and_then(|err| err.downcast_ref::<HttpError>()) {
+                Some(http_error) => http_error.clone(),
+                None => return Err(err),
+            };
+            http::respond_error(reader.get_mut(), &http_error)?;
+            return Ok(Err(http_error.to_string()));
+        }
+    };
+    let checked: Result<String, (&str, String)> = http::parse_head(&head).map_err(|err| (err.status(), err.to_string())).and_then(|request| check_upgrade(&request));
+    match checked {
+        Ok(accept) => {
+            let response: String = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept);
+            reader.get_mut().write_all(response.as_bytes())?;
+            Ok(Ok(()))
+        }
+        Err((status, reason)) => {
+            let response: String = format!(
+                "HTTP/1.1 {}\r\nSec-WebSocket-Version: 13\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+                status,
+                reason.len() + 1,
+                reason,
+            );
+            reader.get_mut().write_all(response.as_bytes())?;
+            Ok(Err(reason))
+        }
+    }
+}
+
+/// XORs `payload` with the 4 byte `key`; masking and unmasking are the same.
+pub fn apply_mask(payload: &mut [u8], key: [u8; 4]) {
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= key[i % 4];
+    }
+}
+
+/// Reads one frame from a client. Returns `None` if the stream ends cleanly before a new
+/// frame starts; frames over `max_payload` are refused before their payload is read.
+pub fn read_frame<R: Read>(reader: &mut R, max_payload: usize) -> std::io::Result<Option<Frame>> {
+    let mut header = [0; 2];
+    match reader.read(&mut header[..1]) {
+        Ok(0) => return Ok(None),
+        Ok(_) => reader.read_exact(&mut header[1..])?,
+        Err(e) => return Err(e),
+    }
+
+    let fin: bool = header[0] & 0x80 != 0;
+    if header[0] & 0x70 != 0 {
+        return Err(protocol_error("reserved bits set"));
+    }
+    let opcode: Opcode = Opcode::from_u8(header[0] & 0x0f).ok_or_else(|| protocol_error("unknown opcode"))?;
+    if header[1] & 0x80 == 0 {
+        return Err(protocol_error("client frames must be masked"));
+    }
+    let len: u64 = match header[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if opcode.is_control() && (!fin || len > MAX_CONTROL as u64) {
+        return Err(protocol_error("control frames must be whole and at most 125 bytes"));
+    }
+    if len > max_payload as u64 {
+        return Err(WebSocketError::TooLarge { max: max_payload }.into_io());
+    }
+
+    let mut key = [0; 4];
+    reader.read_exact(&mut key)?;
+    let mut payload: Vec<u8> = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    apply_mask(&mut payload, key);
+    Ok(Some(Frame { fin, opcode, payload }))
+}
+
+/// Writes one whole, unmasked frame, as servers do.
+pub fn write_frame<W: Write>(writer: &mut W, opcode: Opcode, payload: &[u8]) -> std::io::Result<()> {
+    let mut frame: Vec<u8> = vec![0x80 | opcode.as_u8()];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// A whole, masked frame, as clients send them; for tests and clients, the server never masks.
+pub fn client_frame(fin: bool, opcode: Opcode, payload: &[u8]) -> Vec<u8> {
+    let key: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+    let mut frame: Vec<u8> = vec![if fin { 0x80 } else { 0 } | opcode.as_u8()];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&key);
+    let mut masked: Vec<u8> = payload.to_vec();
+    apply_mask(&mut masked, key);
+    frame.extend_from_slice(&masked);
+    frame
+}
+
+fn write_close<W: Write>(writer: &mut W, code: u16, reason: &str) -> std::io::Result<()> {
+    let mut payload: Vec<u8> = code.to_be_bytes().to_vec();
+    // A reason that doesn't fit is cut short, on a character boundary.
+    let mut end: usize = reason.len().min(MAX_CONTROL - 2);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    write_frame(writer, Opcode::Close, &payload)
+}
+
+/// Echoes messages until the client closes the connection, counting the messages and
+/// pings answered in `messages`.
+pub fn serve<S: Read + Write>(stream: S, limits: HttpLimits, max_message: usize, messages: &mut u64) -> std::io::Result<CloseReason> {
+    let mut reader: HttpReader<S> = HttpReader::new(stream);
+    if let Err(reason) = handshake(&mut reader, limits.max_head)? {
+        return Ok(CloseReason::Error(format!("handshake failed: {}", reason)));
+    }
+
+    match echo(&mut reader, max_message, messages) {
+        Err(err) => {
+            let ws_error: WebSocketError = match err.get_ref().and_then(|err| err.downcast_ref::<WebSocketError>()) {
+                Some(ws_error) => ws_error.clone(),
+                None => return Err(err),
+            };
+            write_close(reader.get_mut(), ws_error.code(), &ws_error.to_string())?;
+            Ok(CloseReason::Error(ws_error.to_string()))
+        }
+        result => result,
+    }
+}
+
+fn echo<S: Read + Write>(reader: &mut HttpReader<S>, max_message: usize, messages: &mut u64) -> std::io::Result<CloseReason> {
+    // The message being put back together from fragments, if any.
+    let mut message: Option<(Opcode, Vec<u8>)> = None;
+
+    while let Some(frame) = read_frame(reader, max_message)? {
+        match frame.opcode {
+            Opcode::Ping => {
+                write_frame(reader.get_mut(), Opcode::Pong, &frame.payload)?;
+                *messages += 1;
+            }
+            Opcode::Pong => {}
+            Opcode::Close => {
+                // Echo the status code; a close without one gets an empty close back.
+                let code: Option<u16> = match frame.payload.len() {
+                    0 => None,
+                    1 => return Err(protocol_error("close frame with a truncated status code")),
+                    _ => Some(u16::from_be_bytes([frame.payload[0], frame.payload[1]])),
+                };
+                if std::str::from_utf8(frame.payload.get(2..).unwrap_or_default()).is_err() {
+                    return Err(WebSocketError::InvalidUtf8.into_io());
+                }
+                match code {
+                    Some(code) => write_close(reader.get_mut(), code, "")?,
+                    None => write_frame(reader.get_mut(), Opcode::Close, &[])?,
+                }
+                return Ok(CloseReason::Bye);
+            }
+            Opcode::Text | Opcode::Binary => {
+                if message.is_some() {
+                    return Err(protocol_error("new message before the last one was finished"));
+                }
+                message = Some((frame.opcode, frame.payload));
+            }
+            Opcode::Continuation => match message.as_mut() {
+                Some((_, payload)) if payload.len() + frame.payload.len() > max_message => {
+                    return Err(WebSocketError::TooLarge { max: max_message }.into_io());
+                }
+                Some((_, payload)) => payload.extend_from_slice(&frame.payload),
+                None => return Err(protocol_error("continuation without a message to continue")),
+            },
+        }
+
+        if frame.fin && !frame.opcode.is_control() {
+            if let Some((opcode, payload)) = message.take() {
+                if opcode == Opcode::Text && std::str::from_utf8(&payload).is_err() {
+                    return Err(WebSocketError::InvalidUtf8.into_io());
+                }
+                write_frame(reader.get_mut(), opcode, &payload)?;
+                *messages += 1;
+            }
+        }
+    }
+
+    Ok(CloseReason::Eof)
+}
+
+#[test]
+fn accept_key_test() {
+    // The example from RFC 6455, section 1.3.
+    assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+}
+
+#[test]
+fn frame_test() {
+    let mut wire: Vec<u8> = client_frame(true, Opcode::Text, b"Hello");
+    // RFC 6455, section 5.7: a masked "Hello".
+    assert_eq!(wire, [0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58]);
+    wire.extend(client_frame(true, Opcode::Binary, &[7; 300]));
+    let mut reader: &[u8] = &wire;
+    assert_eq!(read_frame(&mut reader, 1024).unwrap(), Some(Frame { fin: true, opcode: Opcode::Text, payload: b"Hello".to_vec() }));
+    assert_eq!(read_frame(&mut reader, 1024).unwrap().unwrap().payload, [7; 300]);
+    assert_eq!(read_frame(&mut reader, 1024).unwrap(), None);
+
+    let mut written: Vec<u8> = Vec::new();
+    write_frame(&mut written, Opcode::Text, b"Hello").unwrap();
+    assert_eq!(written, [0x81, 0x05, 0x48, 0x65, 0x6c, 0x6c, 0x6f]);
+    written.clear();
+    write_frame(&mut written, Opcode::Binary, &[0; 256]).unwrap();
+    assert_eq!(&written[..4], [0x82, 126, 0x01, 0x00]);
+
+    let error = |wire: &[u8]| {
+        let err: std::io::Error = read_frame(&mut &wire[..], 16).unwrap_err();
+        err.get_ref().and_then(|err| err.downcast_ref::<WebSocketError>()).unwrap().code()
+    };
+    assert_eq!(error(&[0x81, 0x05, b'H', b'e', b'l', b'l', b'o']), 1002);
+    assert_eq!(error(&client_frame(false, Opcode::Ping, b"")), 1002);
+    assert_eq!(error(&client_frame(true, Opcode::Binary, &[0; 17])), 1009);
+}
+
+#[test]
+fn serve_test() {
+    let server: std::net::TcpListener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: std::net::SocketAddr = server.local_addr().unwrap();
+    let thread = std::thread::spawn(move || {
+        let (stream, _) = server.accept().unwrap();
+        let mut messages: u64 = 0;
+        let close: CloseReason = serve(stream, HttpLimits::default(), 1024, &mut messages).unwrap();
+        (close, messages)
+    });
+
+    let mut client: std::net::TcpStream = std::net::TcpStream::connect(addr).unwrap();
+    let request: &str = "GET /chat HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: keep-alive, Upgrade\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13\r\n\r\n";
+    // The first frames go out with the handshake, before its answer.
+    let mut wire: Vec<u8> = request.as_bytes().to_vec();
+    wire.extend(client_frame(false, Opcode::Text, "héllo ".as_bytes()));
+    wire.extend(client_frame(true, Opcode::Ping, b"are you there"));
+    wire.extend(client_frame(true, Opcode::Continuation, b"world"));
+    wire.extend(client_frame(true, Opcode::Close, &1000u16.to_be_bytes()));
+    client.write_all(&wire).unwrap();
+
+    let mut reply: Vec<u8> = Vec::new();
+    client.read_to_end(&mut reply).unwrap();
+    let expected_head: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+    assert!(reply.starts_with(expected_head.as_bytes()));
+
+    let mut frames: &[u8] = &reply[expected_head.len()..];
+    let mut next = || {
+        let mut header = [0; 2];
+        frames.read_exact(&mut header).unwrap();
+        let mut payload: Vec<u8> = vec![0; (header[1] & 0x7f) as usize];
+        frames.read_exact(&mut payload).unwrap();
+        (header[0], payload)
+    };
+    assert_eq!(next(), (0x8A, b"are you there".to_vec()));
+    assert_eq!(next(), (0x81, "héllo world".as_bytes().to_vec()));
+    assert_eq!(next(), (0x88, 1000u16.to_be_bytes().to_vec()));
+    assert_eq!(thread.join().unwrap(), (CloseReason::Bye, 2));
+}
+
+#[test]
+fn handshake_refused_test() {
+    let request: &[u8] = b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+    let mut stream: std::io::Cursor<Vec<u8>> = std::io::Cursor::new(request.to_vec());
+    let close: CloseReason = serve(&mut stream, HttpLimits::default(), 1024, &mut 0).unwrap();
+    assert_eq!(close, CloseReason::Error("handshake failed: this endpoint only speaks WebSocket".to_owned()));
+    assert!(stream.get_ref()[request.len()..].starts_with(b"HTTP/1.1 426 Upgrade Required\r\n"));
+}
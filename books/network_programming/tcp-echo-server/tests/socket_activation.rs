@@ -0,0 +1,108 @@
+//! Runs the binary the way a service manager would: sockets bound up front, left open at
+//! fds 3 and on, described by `LISTEN_FDS` and friends.
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// Starts the server with `sockets` at fds 3, 4, ... `LISTEN_PID` has to be the pid of the
+/// server itself, which only the child knows: a shell sets it and execs the server.
+fn spawn_activated(sockets: &[RawFd], names: &str, args: &[&str]) -> Child {
+    // Moved out of the way first, so that placing one at fd 3 can't close another.
+    let moved: Vec<OwnedFd> = sockets
+        .iter()
+        .map(|&fd| {
+            let moved: RawFd = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 100) };
+            assert!(moved >= 0, "{}", std::io::Error::last_os_error());
+            unsafe { OwnedFd::from_raw_fd(moved) }
+        })
+        .collect();
+    let fds: Vec<RawFd> = moved.iter().map(AsRawFd::as_raw_fd).collect();
+
+    let mut command: Command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ exec \"$0\" \"$@\"")
+        .arg(env!("CARGO_BIN_EXE_tcp-echo-server"))
+        .args(args)
+        .env("LISTEN_FDS", sockets.len().to_string())
+        .env("LISTEN_FDNAMES", names)
+        .stdout(Stdio::piped());
+    unsafe {
+        command.pre_exec(move || {
+            for (target, &fd) in (3..).zip(&fds) {
+                // dup2 leaves close-on-exec unset on the copy.
+                if libc::dup2(fd, target) < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    command.spawn().unwrap()
+}
+
+/// Reads the server's output up to the line starting with `prefix`.
+fn read_until(stdout: &mut BufReader<ChildStdout>, prefix: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    loop {
+        let mut line: String = String::new();
+        assert!(stdout.read_line(&mut line).unwrap() > 0, "no '{}' in {:?}", prefix, lines);
+        lines.push(line.trim_end().to_owned());
+        if line.starts_with(prefix) {
+            return lines;
+        }
+    }
+}
+
+#[test]
+fn socket_activation_test() {
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let udp: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let udp_addr: SocketAddr = udp.local_addr().unwrap();
+
+    // The --listen address is ignored; binding it would fail anyway, the port being taken.
+    let mut server: Child = spawn_activated(&[listener.as_raw_fd(), udp.as_raw_fd()], "line:unknown", &["--listen", &addr.to_string()]);
+    drop((listener, udp));
+    let mut stdout: BufReader<ChildStdout> = BufReader::new(server.stdout.take().unwrap());
+    let lines: Vec<String> = read_until(&mut stdout, "listening on udp");
+    assert_eq!(
+        lines,
+        [
+            "serving 2 socket(s) passed by the service manager instead of --listen and --udp".to_owned(),
+            format!("listening on {} protocol=line", addr),
+            format!("listening on udp {}", udp_addr),
+        ]
+    );
+
+    let mut client: TcpStream = TcpStream::connect(addr).unwrap();
+    client.write_all(b"PING\n").unwrap();
+    let mut reply: String = String::new();
+    BufReader::new(&client).read_line(&mut reply).unwrap();
+    assert_eq!(reply, "PONG\n");
+
+    let udp_client: UdpSocket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    udp_client.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+    udp_client.send_to(b"ping", udp_addr).unwrap();
+    let mut buf: [u8; 16] = [0; 16];
+    assert_eq!(udp_client.recv(&mut buf).unwrap(), 4);
+    drop(client);
+
+    assert_eq!(unsafe { libc::kill(server.id() as libc::pid_t, libc::SIGTERM) }, 0);
+    assert!(server.wait().unwrap().success());
+    // Nobody else held the listener, so it's gone with the server.
+    assert!(TcpStream::connect(addr).is_err());
+}
+
+#[test]
+fn socket_activation_error_test() {
+    // A connected socket, as `Accept=yes` would pass, can't be served.
+    let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client: TcpStream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let server: Child = spawn_activated(&[client.as_raw_fd()], "", &[]);
+    let output = server.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+}
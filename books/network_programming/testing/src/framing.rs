@@ -0,0 +1,51 @@
+//! Length-prefixed framing: every message is a `u32` big-endian length followed by that many bytes.
+
+use std::io::{Error, ErrorKind, Read, Result, Write};
+
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+/// Reads one frame of at most `max_frame` bytes, or `None` if the stream ends before a new frame starts.
+pub fn read_frame<R: Read>(reader: &mut R, max_frame: usize) -> Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    let mut filled = 0;
+    while filled < header.len() {
+        match reader.read(&mut header[filled..]) {
+            Ok(0) if filled == 0 => return Ok(None),
+            Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+            Ok(bytes_read) => filled += bytes_read,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    let len = u32::from_be_bytes(header) as usize;
+    if len > max_frame {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes is over the {} bytes limit", len, max_frame)));
+    }
+
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> Result<()> {
+    let len = u32::try_from(payload.len()).map_err(|_| Error::new(ErrorKind::InvalidInput, "frame too large"))?;
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(payload)
+}
+
+#[test]
+fn frame_round_trip_test() {
+    let mut wire = Vec::new();
+    write_frame(&mut wire, b"one").unwrap();
+    write_frame(&mut wire, b"").unwrap();
+    assert_eq!(wire, b"\0\0\0\x03one\0\0\0\0");
+
+    let mut reader = &wire[..];
+    assert_eq!(read_frame(&mut reader, 8).unwrap(), Some(b"one".to_vec()));
+    assert_eq!(read_frame(&mut reader, 8).unwrap(), Some(Vec::new()));
+    assert_eq!(read_frame(&mut reader, 8).unwrap(), None);
+
+    let mut too_large = &[0, 0, 0, 9][..];
+    assert_eq!(read_frame(&mut too_large, 8).unwrap_err().kind(), ErrorKind::InvalidData);
+}
@@ -1,7 +1,7 @@
-use std::io::{Write, Read};
-
 #[test]
 fn file_test() {
+    use std::io::{Write, Read};
+
     let (file_name, content): (&str, &str) = ("my_file.txt", "Hello world");
 
     {
@@ -9,7 +9,7 @@ fn file_test() {
         match &mut std::fs::File::create(file_name) {
             Err(e) => panic!("this shouldn't happen {}", e),
             Ok(write_stream) => {
-                write_stream.write(content.as_bytes()).expect("error trying to write our string to file");
+                write_stream.write_all(content.as_bytes()).expect("error trying to write our string to file");
             }
         }
     }
@@ -33,6 +33,6 @@ fn file_test() {
         }
     }
 
-    std::fs::remove_file(file_name);
+    std::fs::remove_file(file_name).expect("couldn't remove test file");
 }
 
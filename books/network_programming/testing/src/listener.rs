@@ -0,0 +1,140 @@
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// TCP and Unix clients, so both are served by the same `handle_client`.
+pub trait ClientStream: Read + Write + Send {
+    fn peer_name(&self) -> Result<String>;
+    fn shutdown(&self, how: Shutdown) -> Result<()>;
+    fn try_clone_stream(&self) -> Result<Box<dyn ClientStream>>;
+}
+
+impl ClientStream for TcpStream {
+    fn peer_name(&self) -> Result<String> {
+        self.peer_addr().map(|addr| addr.to_string())
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+impl ClientStream for UnixStream {
+    fn peer_name(&self) -> Result<String> {
+        let addr = self.peer_addr()?;
+        Ok(match addr.as_pathname() {
+            Some(path) => format!("unix:{}", path.display()),
+            None => "unix client".to_owned(),
+        })
+    }
+
+    fn shutdown(&self, how: Shutdown) -> Result<()> {
+        UnixStream::shutdown(self, how)
+    }
+
+    fn try_clone_stream(&self) -> Result<Box<dyn ClientStream>> {
+        Ok(Box::new(self.try_clone()?))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is the socket file to remove on drop; abstract sockets have none.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Binds `target`: a port, `unix:/path/to.sock[,mode=<octal>]` or, on Linux, `unix:@name`.
+    pub fn bind(target: &str) -> Result<Listener> {
+        let invalid = || Error::new(ErrorKind::InvalidInput, format!("invalid listen target '{}'", target));
+
+        let unix = match target.strip_prefix("unix:") {
+            None => {
+                let port = target.parse::<u16>().map_err(|_| invalid())?;
+                return Ok(Listener::Tcp(TcpListener::bind(("0.0.0.0", port))?));
+            }
+            Some(unix) => unix,
+        };
+
+        if let Some(name) = unix.strip_prefix('@') {
+            return Ok(Listener::Unix(bind_abstract(name)?, None));
+        }
+
+        let (path, mode) = match unix.split_once(',') {
+            None => (unix, None),
+            Some((path, option)) => {
+                let mode = option.strip_prefix("mode=").and_then(|mode| u32::from_str_radix(mode, 8).ok()).ok_or_else(invalid)?;
+                (path, Some(mode))
+            }
+        };
+        let path = PathBuf::from(path);
+
+        remove_stale_socket(&path)?;
+        let listener = Listener::Unix(UnixListener::bind(&path)?, Some(path.clone()));
+        if let Some(mode) = mode {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))?;
+        }
+        Ok(listener)
+    }
+
+    pub fn accept(&self) -> Result<Box<dyn ClientStream>> {
+        match self {
+            Listener::Tcp(listener) => Ok(Box::new(listener.accept()?.0)),
+            Listener::Unix(listener, _) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Listener::Tcp(listener) => listener.as_raw_fd(),
+            Listener::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Removes a socket file nobody accepts on anymore, left behind by a server that was killed.
+fn remove_stale_socket(path: &std::path::Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+        Ok(metadata) if !metadata.file_type().is_socket() => {
+            return Err(Error::new(ErrorKind::AlreadyExists, format!("{} is not a socket", path.display())));
+        }
+        Ok(_) => {}
+    }
+
+    match UnixStream::connect(path) {
+        Ok(_) => Err(Error::new(ErrorKind::AddrInUse, format!("{} is in use", path.display()))),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(name: &str) -> Result<UnixListener> {
+    use std::os::linux::net::SocketAddrExt;
+
+    UnixListener::bind_addr(&std::os::unix::net::SocketAddr::from_abstract_name(name)?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_abstract(_name: &str) -> Result<UnixListener> {
+    Err(Error::new(ErrorKind::Unsupported, "abstract unix sockets are only available on Linux"))
+}
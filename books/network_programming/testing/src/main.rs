@@ -1,44 +1,123 @@
 mod here_io;
 mod here_c;
+mod framing;
+mod listener;
+mod metrics;
+mod shutdown;
 
-use std::net::{TcpStream, TcpListener};
-use std::io::{Read, Write, Result};
+use std::io::Result;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant};
+
+use listener::{ClientStream, Listener};
+use metrics::Metrics;
+use shutdown::ConnectionTracker;
 
 fn main() {
-    if std::env::args().len() != 2 {
+    // `--framed` anywhere switches from the raw echo to length-prefixed messages,
+    // `--metrics <port>` serves Prometheus metrics on that port.
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let framed = args.iter().any(|arg| arg == "--framed");
+    args.retain(|arg| arg != "--framed");
+    let metrics_port = args.iter().position(|arg| arg == "--metrics").map(|index| {
+        let port = args.get(index + 1).and_then(|port| port.parse::<u16>().ok()).expect("expecting --metrics to be followed by a port");
+        args.drain(index..index + 2);
+        port
+    });
+    if args.len() != 1 && args.len() != 2 {
         std::process::exit(1);
     }
 
-    let port = std::env::args().nth(1).expect("expecting port as first and only argument");
-
-    server(port.parse::<u16>().expect("expecting port to be a number"));
+    // A port, `unix:/path/to.sock[,mode=<octal>]` or `unix:@name`.
+    let target = &args[0];
+    // Optional second argument: seconds connections get to finish on SIGINT/SIGTERM.
+    let grace_period = args.get(1).map_or(5, |secs| secs.parse::<u64>().expect("expecting grace period to be a number"));
 
+    shutdown::install_signal_handlers().expect("couldn't install signal handlers");
+    let listener = Listener::bind(target).unwrap_or_else(|err| {
+        eprintln!("couldn't bind to {}: {}", target, err);
+        std::process::exit(1);
+    });
+    let metrics = Arc::new(Metrics::default());
+    if let Some(port) = metrics_port {
+        let metrics_listener = std::net::TcpListener::bind(("0.0.0.0", port)).expect("couldn't bind metrics port");
+        let metrics = metrics.clone();
+        thread::spawn(move || metrics::serve(metrics_listener, &metrics).unwrap_or_else(|err| eprintln!("metrics: {}", err)));
+    }
+    let (drained, aborted) = server(listener, Duration::from_secs(grace_period), framed, &metrics);
+    println!("shutdown: drained {} connection(s), aborted {}", drained, aborted);
 }
 
-fn server(port: u16) {
-    let listener: TcpListener = TcpListener::bind(("0.0.0.0", port)).expect("couldn't bind to port");
+fn server(listener: Listener, grace_period: Duration, framed: bool, metrics: &Arc<Metrics>) -> (usize, usize) {
+    let tracker: ConnectionTracker = ConnectionTracker::default();
+
+    while !shutdown::requested() {
+        match shutdown::wait_for_client(&listener, Duration::from_millis(200)) {
+            Err(e) => {
+                eprintln!("{}", e);
+                break;
+            }
+            Ok(false) => continue,
+            Ok(true) => {}
+        }
 
-    for stream in listener.incoming() {
-        match stream {
-            Err(e) => eprintln!("{}", e),
+        match listener.accept() {
+            Err(e) => {
+                metrics.add_error(e.kind());
+                eprintln!("{}", e);
+            }
             Ok(stream) => {
+                let tracked = match tracker.track(&*stream) {
+                    Ok(tracked) => tracked,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        continue;
+                    }
+                };
+                let metrics = metrics.clone();
+                metrics.accepted.fetch_add(1, Ordering::Relaxed);
+                metrics.active.fetch_add(1, Ordering::Relaxed);
                 thread::spawn(move || {
-                    handle_client(stream).unwrap()
+                    let _tracked = tracked;
+                    let result = if framed { handle_framed_client(stream, &metrics) } else { handle_client(stream, &metrics) };
+                    metrics.active.fetch_sub(1, Ordering::Relaxed);
+                    result.unwrap_or_else(|err| {
+                        metrics.add_error(err.kind());
+                        eprintln!("{}", err);
+                    });
                 });
             },
         }
     }
+
+    drop(listener);
+    tracker.drain(grace_period)
 }
 
-fn handle_client(mut stream: TcpStream) -> Result<()> {
-    println!("connecting with {}", stream.peer_addr()?);
+fn handle_client(mut stream: Box<dyn ClientStream>, metrics: &Metrics) -> Result<()> {
+    println!("connecting with {}", stream.peer_name()?);
 
     loop {
         let mut buf = [0; 512];
         let bytes_read = stream.read(&mut buf)?;
         if bytes_read == 0 { return Ok(()); }
-        stream.write(&buf)?;
+        metrics.received(bytes_read);
+        let received = Instant::now();
+        stream.write_all(&buf[..bytes_read])?;
+        metrics.echoed(bytes_read, received.elapsed());
     }
 }
 
+fn handle_framed_client(mut stream: Box<dyn ClientStream>, metrics: &Metrics) -> Result<()> {
+    println!("connecting with {} (framed)", stream.peer_name()?);
+
+    while let Some(payload) = framing::read_frame(&mut stream, framing::MAX_FRAME)? {
+        metrics.received(payload.len() + 4);
+        let received = Instant::now();
+        framing::write_frame(&mut stream, &payload)?;
+        metrics.echoed(payload.len() + 4, received.elapsed());
+    }
+    Ok(())
+}
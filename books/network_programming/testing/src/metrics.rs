@@ -0,0 +1,110 @@
+//! Prometheus text format metrics at `GET /metrics`, on a port of its own.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Result, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::shutdown;
+
+/// Upper bounds, in seconds, of the round trip histogram buckets.
+const BUCKETS: [f64; 8] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 1.0];
+
+#[derive(Default)]
+pub struct Metrics {
+    pub active: AtomicU64,
+    pub accepted: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    errors: Mutex<BTreeMap<String, u64>>,
+    /// Per bucket, not cumulative; the last one is everything above `BUCKETS`.
+    round_trips: [AtomicU64; BUCKETS.len() + 1],
+    round_trip_micros: AtomicU64,
+}
+
+impl Metrics {
+    pub fn add_error(&self, kind: std::io::ErrorKind) {
+        *self.errors.lock().unwrap().entry(format!("{:?}", kind)).or_insert(0) += 1;
+    }
+
+    /// Counts `bytes` read from a client, whether or not they make it back.
+    pub fn received(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts one echoed message: `bytes` sent back, answered after `latency`.
+    pub fn echoed(&self, bytes: usize, latency: Duration) {
+        self.bytes_out.fetch_add(bytes as u64, Ordering::Relaxed);
+        let bucket = BUCKETS.iter().position(|&bound| latency.as_secs_f64() <= bound).unwrap_or(BUCKETS.len());
+        self.round_trips[bucket].fetch_add(1, Ordering::Relaxed);
+        self.round_trip_micros.fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, kind, value) in [
+            ("echo_connections_active", "gauge", &self.active),
+            ("echo_connections_accepted_total", "counter", &self.accepted),
+            ("echo_received_bytes_total", "counter", &self.bytes_in),
+            ("echo_sent_bytes_total", "counter", &self.bytes_out),
+        ] {
+            let _ = writeln!(out, "# TYPE {} {}\n{} {}", name, kind, name, value.load(Ordering::Relaxed));
+        }
+
+        out.push_str("# TYPE echo_errors_total counter\n");
+        for (kind, count) in self.errors.lock().unwrap().iter() {
+            let _ = writeln!(out, "echo_errors_total{{kind=\"{}\"}} {}", kind, count);
+        }
+
+        out.push_str("# TYPE echo_round_trip_seconds histogram\n");
+        let mut total = 0;
+        for (index, count) in self.round_trips.iter().enumerate() {
+            total += count.load(Ordering::Relaxed);
+            let bound = BUCKETS.get(index).map_or("+Inf".to_owned(), |bound| bound.to_string());
+            let _ = writeln!(out, "echo_round_trip_seconds_bucket{{le=\"{}\"}} {}", bound, total);
+        }
+        let sum = Duration::from_micros(self.round_trip_micros.load(Ordering::Relaxed));
+        let _ = writeln!(out, "echo_round_trip_seconds_sum {}\necho_round_trip_seconds_count {}", sum.as_secs_f64(), total);
+        out
+    }
+}
+
+/// Answers scrapes, one at a time, until a shutdown is requested.
+pub fn serve(listener: TcpListener, metrics: &Metrics) -> Result<()> {
+    while !shutdown::requested() {
+        if !shutdown::wait_for_client(&listener, Duration::from_millis(200))? {
+            continue;
+        }
+        // Like the client accept loop, a failed accept (EMFILE, ECONNABORTED, ...) only costs this scrape.
+        match listener.accept() {
+            Err(e) => eprintln!("metrics: {}", e),
+            Ok((stream, _)) => answer(stream, metrics).unwrap_or_else(|err| eprintln!("metrics: {}", err)),
+        }
+    }
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < 8192 {
+        let bytes_read = stream.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..bytes_read]);
+    }
+
+    let (status, body) = if head.starts_with(b"GET /metrics ") { ("200 OK", metrics.render()) } else { ("404 Not Found", "try GET /metrics\n".to_owned()) };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::listener::ClientStream;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signal: libc::c_int) {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// SIGINT and SIGTERM only raise a flag, which the accept loop checks.
+pub fn install_signal_handlers() -> std::io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = on_signal as *const () as libc::sighandler_t;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+        if unsafe { libc::sigaction(signal, &action, std::ptr::null_mut()) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Waits up to `timeout` for `listener` to have a pending connection.
+pub fn wait_for_client(listener: &impl std::os::unix::io::AsRawFd, timeout: Duration) -> std::io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd: listener.as_raw_fd(), events: libc::POLLIN, revents: 0 };
+    match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) } {
+        n if n < 0 => {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted { Ok(false) } else { Err(err) }
+        }
+        n => Ok(n > 0),
+    }
+}
+
+#[derive(Default)]
+struct Live {
+    next_id: u64,
+    streams: HashMap<u64, Box<dyn ClientStream>>,
+}
+
+/// Every live connection, so they can be drained on shutdown.
+#[derive(Clone, Default)]
+pub struct ConnectionTracker {
+    inner: Arc<(Mutex<Live>, Condvar)>,
+}
+
+pub struct TrackedConnection {
+    id: u64,
+    tracker: ConnectionTracker,
+}
+
+impl Drop for TrackedConnection {
+    fn drop(&mut self) {
+        let (live, finished) = &*self.tracker.inner;
+        live.lock().unwrap().streams.remove(&self.id);
+        finished.notify_all();
+    }
+}
+
+impl ConnectionTracker {
+    pub fn track(&self, stream: &dyn ClientStream) -> std::io::Result<TrackedConnection> {
+        let clone: Box<dyn ClientStream> = stream.try_clone_stream()?;
+        let mut live = self.inner.0.lock().unwrap();
+        let id: u64 = live.next_id;
+        live.next_id += 1;
+        live.streams.insert(id, clone);
+
+        Ok(TrackedConnection { id, tracker: self.clone() })
+    }
+
+    /// Makes blocked reads return EOF so the handlers end, waits up to `grace` for them and
+    /// force-closes the rest. Returns (drained, aborted).
+    pub fn drain(&self, grace: Duration) -> (usize, usize) {
+        let (live, finished) = &*self.inner;
+        let mut live = live.lock().unwrap();
+        let initial: usize = live.streams.len();
+
+        for stream in live.streams.values() {
+            let _ = stream.shutdown(std::net::Shutdown::Read);
+        }
+
+        let deadline: Instant = Instant::now() + grace;
+        while !live.streams.is_empty() && Instant::now() < deadline {
+            live = finished.wait_timeout(live, deadline - Instant::now()).unwrap().0;
+        }
+
+        let aborted: usize = live.streams.len();
+        for stream in live.streams.values() {
+            let _ = stream.shutdown(std::net::Shutdown::Both);
+        }
+
+        (initial - aborted, aborted)
+    }
+}